@@ -10,11 +10,24 @@ type VideoMetadata = {
     created_at: string;
 };
 
-type ServerVideos = {
-    serverId: number;
+const DEFAULT_SOURCE_BUFFER_TYPE = 'video/mp4; codecs="avc1.42E01E,mp4a.40.2"';
+
+const PAGE_SIZE = 20; // Local videos per page
+
+type VideoPage = {
+    total: number;
+    offset: number;
+    limit: number;
     videos: VideoMetadata[];
 };
 
+// Video advertised by one or more servers
+type CatalogEntry = {
+    metadata: VideoMetadata;
+    servers: number[];
+    last_seen_ms: number;
+};
+
 enum FSMStatus {
     ServerNotFound = "ServerNotFound",
     NotSubscribedToServer = "NotSubscribedToServer",
@@ -22,16 +35,30 @@ enum FSMStatus {
     Terminated = "Terminated",
 }
 
+// The client sends a `shutdown` event before closing its streams, stop reconnecting
+const closeOnShutdown = (source: EventSource): void => {
+    source.addEventListener("shutdown", () => source.close());
+};
+
 const VideoStreamer: React.FC = () => {
     const videoRef = useRef<HTMLVideoElement | null>(null);
     const mediaSourceRef = useRef<MediaSource | null>(null);
     const sourceBufferRef = useRef<SourceBuffer | null>(null);
+    const videoStreamRef = useRef<EventSource | null>(null);
+    const playbackStateRef = useRef<EventSource | null>(null);
+    const currentVideoRef = useRef<number | null>(null);
+    const startTimeRef = useRef<number>(0); // Start time of the current request
 
     const [videos, setVideos] = useState<VideoMetadata[]>([]);
-    const [videosFromServer, setVideosFromServer] = useState<ServerVideos[]>([]);
+    const [totalVideos, setTotalVideos] = useState<number>(0);
+    const [search, setSearch] = useState<string>("");
+    const [sort, setSort] = useState<string>("title");
+    const [page, setPage] = useState<number>(0);
+    const [catalog, setCatalog] = useState<CatalogEntry[]>([]);
     const [fsmStatus, setFsmStatus] = useState<string>("Setup");
     const [selectedVideo, setSelectedVideo] = useState<VideoMetadata | null>(null);
     const [errorMessage, setErrorMessage] = useState<string | null>(null);
+    const [playbackState, setPlaybackState] = useState<string | null>(null);
 
     const chunkQueue: Uint8Array[] = [];
     let isAppending = false;
@@ -48,7 +75,94 @@ const VideoStreamer: React.FC = () => {
         throw new Error("Unsupported data format");
     };
 
-    const requestVideo = async (video_id: number): Promise<void> => {
+    const openVideoStream = (sessionId: string): void => {
+        // Close the stream of the previous video
+        videoStreamRef.current?.close();
+
+        const evtSource = new EventSource(`/video-stream/${sessionId}`);
+        videoStreamRef.current = evtSource;
+        closeOnShutdown(evtSource);
+
+        // Buffering/playing events of the session
+        playbackStateRef.current?.close();
+        const stateSource = new EventSource(`/playback-state/${sessionId}`);
+        playbackStateRef.current = stateSource;
+        closeOnShutdown(stateSource);
+        stateSource.onmessage = (event: MessageEvent) => {
+            try {
+                const status: { state: string } = JSON.parse(event.data);
+                setPlaybackState(status.state);
+                if (status.state === "Completed" || status.state === "Failed" || status.state === "NotSeekable") {
+                    stateSource.close();
+                }
+            } catch (error) {
+                console.error("Error parsing playback state:", error);
+            }
+        };
+        stateSource.onerror = () => stateSource.close();
+
+        evtSource.onmessage = async (event: MessageEvent) => {
+            try {
+                const videoChunk = await decodeChunk(event.data);
+                chunkQueue.push(videoChunk); // Queue the chunk
+                processChunkQueue(); // Try to process the queue
+            } catch (error) {
+                /* ... */
+            }
+        };
+
+        const processChunkQueue = async () => {
+            if (isAppending || chunkQueue.length === 0 || !sourceBufferRef.current) return;
+
+            isAppending = true;
+            try {
+                const chunk = chunkQueue.shift(); // Get the next chunk
+                if (chunk) {
+                    if (sourceBufferRef.current.updating) {
+                        // Should not happen, but a safeguard
+                        await new Promise<void>((resolve) => {
+                            sourceBufferRef.current?.addEventListener("updateend", () => resolve(), { once: true });
+                        });
+                    }
+
+                    sourceBufferRef.current.appendBuffer(chunk);
+
+                    await new Promise<void>((resolve) => {
+                        sourceBufferRef.current?.addEventListener("updateend", () => resolve(), { once: true });
+                    });
+                }
+            } catch (error) {
+                console.error("Error appending chunk:", error);
+                // setErrorMessage("Failed to append video chunk");
+                chunkQueue.length = 0; // Clear the queue to prevent further errors
+                // evtSource.close(); // Close the event source
+            } finally {
+                isAppending = false;
+                processChunkQueue(); // Process the next chunk if available
+            }
+        };
+
+        evtSource.onerror = (error: Event) => {
+            console.error("EventSource error:", error);
+            setErrorMessage("Failed to receive video stream");
+            evtSource.close();
+        };
+    };
+
+    // Use the codecs parsed from the video file when the browser supports them
+    const sourceBufferType = (video_id: number): string => {
+        const video = [...videos, ...catalog.map((entry) => entry.metadata)].find(
+            (video) => video.id === video_id
+        );
+        if (video && video.mime_type.includes("codecs=") && MediaSource.isTypeSupported(video.mime_type)) {
+            return video.mime_type;
+        }
+        return DEFAULT_SOURCE_BUFFER_TYPE;
+    };
+
+    const requestVideo = async (video_id: number, t: number = 0): Promise<void> => {
+        currentVideoRef.current = video_id;
+        startTimeRef.current = t;
         try {
             // Reset video and buffer
             if (videoRef.current && mediaSourceRef.current) {
@@ -58,22 +172,30 @@ const VideoStreamer: React.FC = () => {
                 const newVideoURL = URL.createObjectURL(mediaSourceRef.current);
                 videoRef.current.src = newVideoURL;
 
+                const mimeType = sourceBufferType(video_id);
                 mediaSourceRef.current.addEventListener("sourceopen", () => {
-                    sourceBufferRef.current = mediaSourceRef.current!.addSourceBuffer(
-                        'video/mp4; codecs="avc1.42E01E,mp4a.40.2"'
-                    );
+                    sourceBufferRef.current = mediaSourceRef.current!.addSourceBuffer(mimeType);
+                    if (videoRef.current && t > 0) {
+                        videoRef.current.currentTime = t;
+                    }
                 });
             }
 
-            // Request new video
-            const response = await fetch(`/req-video/${video_id}`, {
+            // Request new video, starting from the fragment containing `t`
+            const query = t > 0 ? `?t=${t}` : "";
+            const response = await fetch(`/req-video/${video_id}${query}`, {
                 method: "GET",
             });
 
-            if (!response.ok) {
+            if (response.status === 422) {
+                setErrorMessage("This video cannot start from the requested time");
+            } else if (!response.ok) {
                 console.error("Failed to fetch video:", response.status);
                 setErrorMessage("Failed to fetch video");
             } else {
+                // Subscribe to the playback session of the requested video
+                const sessionId = await response.text();
+                openVideoStream(sessionId);
                 setErrorMessage(null);
             }
         } catch (error) {
@@ -82,20 +204,40 @@ const VideoStreamer: React.FC = () => {
         }
     };
 
+    // Request the video again from the seek time when it is not buffered yet
+    const handleSeeking = (): void => {
+        const video = videoRef.current;
+        const video_id = currentVideoRef.current;
+        if (!video || video_id === null || video.currentTime === startTimeRef.current) return;
+
+        const buffered = video.buffered;
+        for (let i = 0; i < buffered.length; i++) {
+            if (buffered.start(i) <= video.currentTime && video.currentTime <= buffered.end(i)) {
+                return;
+            }
+        }
+        requestVideo(video_id, video.currentTime);
+    };
+
     const requestVideoList = async (): Promise<void> => {
         try {
-            const response = await fetch("/req-video-list-from-db", {
+            const params = new URLSearchParams({
+                sort,
+                offset: String(page * PAGE_SIZE),
+                limit: String(PAGE_SIZE),
+            });
+            if (search.trim() !== "") {
+                params.set("title", search.trim());
+            }
+
+            const response = await fetch(`/videos?${params}`, {
                 method: "GET",
             });
             if (response.ok) {
-                const text = await response.text();
-                const parsedVideos = text
-                    .trim()
-                    .split("\n")
-                    .filter((line) => line.startsWith("data:"))
-                    .map((line) => JSON.parse(line.slice(5)));
-
-                setVideos(parsedVideos);
+                const videoPage: VideoPage = await response.json();
+
+                setVideos(videoPage.videos);
+                setTotalVideos(videoPage.total);
                 setErrorMessage(null);
             } else {
                 console.error("Failed to fetch videos:", response.status);
@@ -148,37 +290,35 @@ const VideoStreamer: React.FC = () => {
     }, [fsmStatus]);
 
     useEffect(() => {
-        if (!videoRef.current) return;
-
         requestVideoList();
+    }, [search, sort, page]);
+
+    useEffect(() => {
+        if (!videoRef.current) return;
 
         // New EventSource for video list from server
         const videoListFromServer = new EventSource("/video-list-from-server");
-        videoListFromServer.onmessage = function (event) {
+        closeOnShutdown(videoListFromServer);
+        // Every new server list updates the catalog merged by the client
+        videoListFromServer.onmessage = async function () {
             try {
-                const data: [number, VideoMetadata[]] = JSON.parse(event.data);
-                const serverId = data[0];
-                const videos = data[1];
-
-                setVideosFromServer((prev) => {
-                    const existingIndex = prev.findIndex((entry) => entry.serverId === serverId);
-                    if (existingIndex !== -1) {
-                        const updated = [...prev];
-                        updated[existingIndex] = { serverId, videos };
-                        return updated;
-                    } else {
-                        return [...prev, { serverId, videos }];
-                    }
-                });
+                const response = await fetch("/network-catalog", { method: "GET" });
+                if (response.ok) {
+                    setCatalog(await response.json());
+                } else {
+                    console.error("Failed to fetch network catalog:", response.status);
+                    setErrorMessage("Failed to fetch network catalog");
+                }
             } catch (error) {
-                console.error("Error parsing video list from server:", error);
-                setVideosFromServer([]);
-                setErrorMessage("Failed to fetch video list from server");
+                console.error("Error fetching network catalog:", error);
+                setCatalog([]);
+                setErrorMessage("Failed to fetch network catalog");
             }
         };
 
         // New EventSource for fsm
         const fsmStatusSource = new EventSource("/fsm-status");
+        closeOnShutdown(fsmStatusSource);
         fsmStatusSource.onmessage = function (event) {
             try {
                 setFsmStatus(event.data);
@@ -189,6 +329,18 @@ const VideoStreamer: React.FC = () => {
             }
         };
 
+        // New EventSource for the requests to servers that got no answer
+        const requestErrorsSource = new EventSource("/request-errors");
+        closeOnShutdown(requestErrorsSource);
+        requestErrorsSource.addEventListener("request-error", (event) => {
+            try {
+                const error: { message: string } = JSON.parse((event as MessageEvent).data);
+                setErrorMessage(`Request failed: ${error.message}`);
+            } catch (error) {
+                console.error("Error parsing request error:", error);
+            }
+        });
+
         mediaSourceRef.current = new MediaSource();
         const videoURL = URL.createObjectURL(mediaSourceRef.current);
 
@@ -199,62 +351,11 @@ const VideoStreamer: React.FC = () => {
             if (!mediaSourceRef.current) return;
 
             if (mediaSourceRef.current?.readyState === "open") {
-                sourceBufferRef.current = mediaSourceRef.current.addSourceBuffer(
-                    'video/mp4; codecs="avc1.42E01E,mp4a.40.2"'
-                );
+                sourceBufferRef.current = mediaSourceRef.current.addSourceBuffer(DEFAULT_SOURCE_BUFFER_TYPE);
             } else {
                 console.error("MediaSource not ready");
             }
 
-            const evtSource = new EventSource("/video-stream");
-
-            evtSource.onmessage = async (event: MessageEvent) => {
-                try {
-                    const videoChunk = await decodeChunk(event.data);
-                    chunkQueue.push(videoChunk); // Queue the chunk
-                    processChunkQueue(); // Try to process the queue
-                } catch (error) {
-                    /* ... */
-                }
-            };
-
-            const processChunkQueue = async () => {
-                if (isAppending || chunkQueue.length === 0 || !sourceBufferRef.current) return;
-
-                isAppending = true;
-                try {
-                    const chunk = chunkQueue.shift(); // Get the next chunk
-                    if (chunk) {
-                        if (sourceBufferRef.current.updating) {
-                            // Should not happen, but a safeguard
-                            await new Promise<void>((resolve) => {
-                                sourceBufferRef.current?.addEventListener("updateend", () => resolve(), { once: true });
-                            });
-                        }
-
-                        sourceBufferRef.current.appendBuffer(chunk);
-
-                        await new Promise<void>((resolve) => {
-                            sourceBufferRef.current?.addEventListener("updateend", () => resolve(), { once: true });
-                        });
-                    }
-                } catch (error) {
-                    console.error("Error appending chunk:", error);
-                    // setErrorMessage("Failed to append video chunk");
-                    chunkQueue.length = 0; // Clear the queue to prevent further errors
-                    // evtSource.close(); // Close the event source
-                } finally {
-                    isAppending = false;
-                    processChunkQueue(); // Process the next chunk if available
-                }
-            };
-
-            evtSource.onerror = (error: Event) => {
-                console.error("EventSource error:", error);
-                setErrorMessage("Failed to receive video stream");
-                evtSource.close();
-            };
-
             // Handle buffer updates
             sourceBufferRef.current?.addEventListener("updateend", () => {
                 if (!sourceBufferRef.current || !videoRef.current) return;
@@ -272,6 +373,8 @@ const VideoStreamer: React.FC = () => {
 
         // Cleanup
         return () => {
+            videoStreamRef.current?.close();
+            playbackStateRef.current?.close();
             if (mediaSourceRef.current?.readyState === "open") {
                 mediaSourceRef.current.endOfStream();
             }
@@ -331,9 +434,32 @@ const VideoStreamer: React.FC = () => {
                 <div className="grid md:grid-cols-3 gap-8">
                     {/* Video Player */}
                     <div className="md:col-span-2 rounded-xl overflow-hidden shadow-2xl">
-                        <video ref={videoRef} className="w-full bg-black" controls preload="auto">
-                            <p className="vjs-no-js">To view this video, please enable JavaScript.</p>
-                        </video>
+                        <div className="relative">
+                            <video
+                                ref={videoRef}
+                                className="w-full bg-black"
+                                controls
+                                preload="auto"
+                                onSeeking={handleSeeking}
+                            >
+                                <p className="vjs-no-js">To view this video, please enable JavaScript.</p>
+                            </video>
+                            {playbackState === "Buffering" && (
+                                <div className="absolute top-2 left-2 bg-gray-800 bg-opacity-75 rounded px-2 py-1 text-sm">
+                                    Buffering...
+                                </div>
+                            )}
+                            {playbackState === "Failed" && (
+                                <div className="absolute top-2 left-2 bg-red-800 bg-opacity-75 rounded px-2 py-1 text-sm">
+                                    Download failed, no peer can send this video
+                                </div>
+                            )}
+                            {playbackState === "NotSeekable" && (
+                                <div className="absolute top-2 left-2 bg-red-800 bg-opacity-75 rounded px-2 py-1 text-sm">
+                                    This video cannot start from the requested time
+                                </div>
+                            )}
+                        </div>
 
                         {selectedVideo && (
                             <div className="p-4 bg-gray-700">
@@ -347,6 +473,30 @@ const VideoStreamer: React.FC = () => {
                     <div className="space-y-6">
                         <div>
                             <h2 className="text-2xl font-bold mb-4 text-gray-200">Local Videos</h2>
+                            <div className="flex space-x-2 mb-4">
+                                <input
+                                    type="search"
+                                    value={search}
+                                    onChange={(e) => {
+                                        setSearch(e.target.value);
+                                        setPage(0);
+                                    }}
+                                    placeholder="Search by title"
+                                    className="flex-1 bg-gray-800 rounded-lg px-3 py-2 text-sm text-gray-200"
+                                />
+                                <select
+                                    value={sort}
+                                    onChange={(e) => {
+                                        setSort(e.target.value);
+                                        setPage(0);
+                                    }}
+                                    className="bg-gray-800 rounded-lg px-3 py-2 text-sm text-gray-200"
+                                >
+                                    <option value="title">Title</option>
+                                    <option value="duration">Duration</option>
+                                    <option value="created_at">Date</option>
+                                </select>
+                            </div>
                             <div className="space-y-4">
                                 {videos.map((video) => (
                                     <VideoCard
@@ -360,32 +510,47 @@ const VideoStreamer: React.FC = () => {
                                 ))}
                                 {videos.length === 0 && <p className="text-gray-500 text-center">No local videos</p>}
                             </div>
+                            {totalVideos > PAGE_SIZE && (
+                                <div className="flex justify-between items-center mt-4 text-sm text-gray-400">
+                                    <button
+                                        onClick={() => setPage(page - 1)}
+                                        disabled={page === 0}
+                                        className="text-blue-400 hover:text-blue-300 disabled:text-gray-600"
+                                    >
+                                        Previous
+                                    </button>
+                                    <span>
+                                        Page {page + 1} of {Math.ceil(totalVideos / PAGE_SIZE)}
+                                    </span>
+                                    <button
+                                        onClick={() => setPage(page + 1)}
+                                        disabled={(page + 1) * PAGE_SIZE >= totalVideos}
+                                        className="text-blue-400 hover:text-blue-300 disabled:text-gray-600"
+                                    >
+                                        Next
+                                    </button>
+                                </div>
+                            )}
                         </div>
 
                         <div>
                             <h2 className="text-2xl font-bold mb-4 text-gray-200">Server Videos</h2>
-                            <div className="space-y-6">
-                                {videosFromServer.map(({ serverId, videos }) => (
-                                    <div key={serverId} className="border border-gray-600 p-4 rounded-lg">
-                                        <h3 className="text-xl font-semibold text-gray-300">Server {serverId}</h3>
-                                        <div className="space-y-4 mt-2">
-                                            {videos.map((video) => (
-                                                <VideoCard
-                                                    key={video.id}
-                                                    video={video}
-                                                    onSelect={() => {
-                                                        setSelectedVideo(video);
-                                                        requestVideo(video.id);
-                                                    }}
-                                                />
-                                            ))}
-                                            {videos.length === 0 && (
-                                                <p className="text-gray-500 text-center">No videos from this server</p>
-                                            )}
-                                        </div>
+                            <div className="space-y-4">
+                                {catalog.map(({ metadata, servers }) => (
+                                    <div key={metadata.id}>
+                                        <VideoCard
+                                            video={metadata}
+                                            onSelect={() => {
+                                                setSelectedVideo(metadata);
+                                                requestVideo(metadata.id);
+                                            }}
+                                        />
+                                        <p className="text-xs text-gray-500 mt-1">
+                                            Servers: {servers.join(", ")}
+                                        </p>
                                     </div>
                                 ))}
-                                {videosFromServer.length === 0 && (
+                                {catalog.length === 0 && (
                                     <p className="text-gray-500 text-center">No server videos</p>
                                 )}
                             </div>
@@ -1,38 +1,56 @@
+mod catalog;
+mod config;
+mod integrity;
 mod logger_settings;
 mod message_handlers;
+mod playback_buffer;
+mod playback_sessions;
 mod routes;
 mod routes_handlers;
+mod swarm;
 mod utils;
-mod video_chunker;
+pub(crate) mod video_chunker;
+mod video_range;
 
-use bytes::Bytes;
+use catalog::NetworkCatalog;
+use config::ClientConfig;
 use crossbeam::channel::{Receiver, Sender};
+use integrity::ChunkVerifier;
 use logger::{LogLevel, Logger};
 use packet_forge::{ClientT, ClientType, FileHash, PacketForge, SessionIdT, VideoMetaData};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use playback_sessions::PlaybackSessions;
 use rocket::fs::{relative, FileServer};
 use rocket::{Build, Config, Rocket};
 use routes::{
-    flood_req, fsm_status, get_id, req_video_list_from_server, request_video,
-    request_video_list_from_db, video_list_from_server, video_stream,
+    flood_req, flood_status, fsm_status, get_id, media_info, network_catalog, playback_state,
+    reassembly_metrics, req_video_list_from_server, request_errors, request_video,
+    request_video_list_from_db, search_videos, video, video_list_from_server, video_stream,
 };
 use routing_handler::RoutingHandler;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
 use std::sync::{Arc, LazyLock};
+use std::thread::JoinHandle;
+use swarm::SwarmDownload;
 use tokio::sync::broadcast;
+use utils::{
+    congestion::SendWindows,
+    flood_scheduler::FloodScheduler,
+    pending_requests::{PendingRequests, RequestError, REQUEST_ERRORS_CAPACITY},
+    reassembly::FragmentReassembler,
+    retransmission::RetransmissionTimers,
+    shutdown::ShutdownToken,
+};
 use wg_internal::controller::{DroneCommand, DroneEvent};
 use wg_internal::network::NodeId;
-use wg_internal::packet::{Fragment, Packet};
+use wg_internal::packet::Packet;
 
 use crate::db::structures::VideoDb;
 
 type StateT<'a> = Arc<RwLock<ClientState>>;
 type VideoListSenderT = (NodeId, Vec<VideoMetaData>);
 
-const BASE_DB_PATH: &str = "db/client_video";
-const FLOODING_TIMER: u64 = 180; // Timer in seconds for sending flood_req
-
 static RT: LazyLock<tokio::runtime::Runtime> =
     LazyLock::new(|| tokio::runtime::Runtime::new().unwrap());
 
@@ -58,7 +76,16 @@ impl ClientT for ClientVideo {
         receiver: Receiver<Packet>,
         senders: HashMap<NodeId, Sender<Packet>>,
     ) -> Self {
-        Self::new(id, command_send, command_recv, receiver, senders)
+        // The config file is only known once the client runs: `run_internal` loads it with
+        // the environment overrides, and stops the client if any value is invalid
+        Self::new(
+            id,
+            command_send,
+            command_recv,
+            receiver,
+            senders,
+            ClientConfig::default(),
+        )
     }
 
     fn run(self: Box<Self>, init_client_path: &str) {
@@ -100,24 +127,35 @@ pub(crate) struct ClientState {
     packet_recv: Receiver<Packet>,
     senders: HashMap<NodeId, Sender<Packet>>,
     packet_forge: PacketForge,
-    packets_map: HashMap<u64, Vec<Fragment>>,
+    reassembler: FragmentReassembler, // Fragments of the messages being received
     fsm: FsmStatus,
     routing_handler: RoutingHandler, // Topology graph
     packets_history: HashMap<(u64, SessionIdT), Packet>, // (fragment_index, session_id) -> Packet
+    retransmissions: RetransmissionTimers, // Timers of the packets waiting for an ack
+    pending_requests: PendingRequests, // Requests to servers waiting for an answer
+    send_windows: SendWindows,       // Congestion windows of the destinations
     logger: Logger,
     flood_id: u64,
     client_type: ClientType,
     servers: HashMap<NodeId, Vec<FileHash>>,
+    videos_metadata: HashMap<FileHash, VideoMetaData>, // Metadata of videos advertised by servers
+    catalog: NetworkCatalog, // Videos of every server, removed with their server
+    peer_list_servers: HashMap<FileHash, VecDeque<NodeId>>, // Servers still to ask for the peers of a video
+    config: ClientConfig,
 }
 
 #[derive(Clone)]
 pub struct ClientVideo {
     state: Arc<RwLock<ClientState>>,
-    video_sender: Arc<RwLock<Option<broadcast::Sender<Bytes>>>>, // Frontend sender for video chunks
     file_list_sender: Arc<RwLock<Option<broadcast::Sender<VideoListSenderT>>>>, // Frontend sender for video list
+    request_errors: broadcast::Sender<RequestError>, // Requests to servers that failed, for the frontend
     db: Arc<VideoDb>,
-    chunk_buffer: Arc<RwLock<BTreeMap<u32, Bytes>>>, // Store out-of-order chunks
-    next_expected_index: Arc<RwLock<u32>>,           // Track next expected chunk
+    playback_sessions: Arc<RwLock<PlaybackSessions>>, // Video streams requested by the frontend
+    swarm_downloads: Arc<RwLock<HashMap<FileHash, SwarmDownload>>>, // Videos being downloaded from peers
+    chunk_verifier: Arc<RwLock<ChunkVerifier>>, // Digests of the videos being downloaded
+    flood_scheduler: FloodScheduler,            // Sends the periodic and the requested flood_req
+    shutdown: ShutdownToken,                    // Cancelled on `Crash` or when Rocket stops
+    background_threads: Arc<Mutex<Vec<JoinHandle<()>>>>, // Joined on shutdown
 }
 
 impl ClientVideo {
@@ -131,8 +169,11 @@ impl ClientVideo {
         command_recv: Receiver<DroneCommand>,
         receiver: Receiver<Packet>,
         senders: HashMap<NodeId, Sender<Packet>>,
+        config: ClientConfig,
     ) -> Self {
-        let client_dir = format!("{BASE_DB_PATH}/client_{id}");
+        let playback_sessions = PlaybackSessions::new(&config);
+        let flood_scheduler = FloodScheduler::new(&config);
+        let (request_errors, _) = broadcast::channel(REQUEST_ERRORS_CAPACITY);
 
         let state = ClientState {
             id,
@@ -141,23 +182,35 @@ impl ClientVideo {
             packet_recv: receiver,
             senders,
             packet_forge: PacketForge::new(),
-            packets_map: HashMap::new(),
+            reassembler: FragmentReassembler::default(),
             fsm: FsmStatus::ServerNotFound,
             routing_handler: RoutingHandler::new(),
             packets_history: HashMap::new(),
+            retransmissions: RetransmissionTimers::default(),
+            pending_requests: PendingRequests::default(),
+            send_windows: SendWindows::default(),
             logger: Logger::new(LogLevel::None as u8, false, format!("client-video-{id}")),
             flood_id: 0,
             client_type: ClientType::Video,
             servers: HashMap::new(),
+            videos_metadata: HashMap::new(),
+            catalog: NetworkCatalog::default(),
+            peer_list_servers: HashMap::new(),
+            config,
         };
 
         ClientVideo {
             state: Arc::new(RwLock::new(state)),
-            video_sender: Arc::new(RwLock::new(None)),
             file_list_sender: Arc::new(RwLock::new(None)),
-            db: Arc::new(VideoDb::new(&client_dir)),
-            chunk_buffer: Arc::new(RwLock::new(BTreeMap::new())),
-            next_expected_index: Arc::new(RwLock::new(0)),
+            request_errors,
+            // The db path is known once the config is loaded in `run_internal`
+            db: Arc::new(VideoDb::temporary()),
+            playback_sessions: Arc::new(RwLock::new(playback_sessions)),
+            swarm_downloads: Arc::new(RwLock::new(HashMap::new())),
+            chunk_verifier: Arc::new(RwLock::new(ChunkVerifier::default())),
+            flood_scheduler,
+            shutdown: ShutdownToken::default(),
+            background_threads: Arc::new(Mutex::new(Vec::new())),
         }
     }
     /// Get the ID of the client
@@ -168,11 +221,19 @@ impl ClientVideo {
         self.state.read().id
     }
 
+    /// Applies the config loaded when the client starts and opens the db it sets
+    fn apply_config(&mut self, config: ClientConfig) {
+        self.db = Arc::new(VideoDb::new(&config.client_db_path(self.get_id())));
+        *self.playback_sessions.write() = PlaybackSessions::new(&config);
+        self.flood_scheduler = FloodScheduler::new(&config);
+        self.state.write().config = config;
+    }
+
     #[must_use]
     fn configure(client: ClientVideo) -> Rocket<Build> {
         // Config rocket to use a different port for each client
         let config = Config {
-            port: 8000 + u16::from(client.get_id()),
+            port: client.state.read().config.http_port(client.get_id()),
             ..Config::default()
         };
 
@@ -186,49 +247,176 @@ impl ClientVideo {
                     video_stream,
                     request_video,
                     request_video_list_from_db,
+                    search_videos,
                     video_list_from_server,
+                    network_catalog,
+                    request_errors,
                     req_video_list_from_server,
-                    flood_req
+                    flood_req,
+                    flood_status,
+                    video,
+                    reassembly_metrics,
+                    playback_state,
+                    media_info
                 ],
             )
             .mount("/", FileServer::from(relative!("static")))
     }
 
+    /// Joins every background thread and returns how many were joined
+    fn join_background_threads(&self) -> usize {
+        let mut joined = 0;
+        loop {
+            // Threads may be started while joining the previous ones
+            let handles = std::mem::take(&mut *self.background_threads.lock());
+            if handles.is_empty() {
+                return joined;
+            }
+
+            for handle in handles {
+                if handle.join().is_err() {
+                    self.state.read().logger.log_error(&format!(
+                        "[{}, {}] a background thread panicked",
+                        file!(),
+                        line!()
+                    ));
+                }
+                joined += 1;
+            }
+        }
+    }
+
+    /// Waits for the background threads to stop, then persists the db
+    async fn finish_shutdown(&self) {
+        let client = self.clone();
+        let joined = tokio::task::spawn_blocking(move || client.join_background_threads())
+            .await
+            .unwrap_or_default();
+        self.state.read().logger.log_info(&format!(
+            "[{}, {}] {joined} background threads stopped",
+            file!(),
+            line!()
+        ));
+
+        if let Err(err) = self.db.flush() {
+            self.state.read().logger.log_error(&err);
+        }
+    }
+
     /// Launch the Rocket app
     /// This function will block the current thread until the Rocket app is shut down
     /// # Errors
     /// If the Rocket app fails to launch
-    async fn run_internal(self, init_client_path: &str) {
-        // Initialize the client db
-        let res = self.db.init(init_client_path, Some("video_metadata.json"));
-        if let Err(err) = res {
-            self.state.read().logger.log_error(&err);
-            return;
+    async fn run_internal(mut self, init_client_path: &str) {
+        match ClientConfig::load(init_client_path) {
+            Ok(config) => self.apply_config(config),
+            Err(err) => {
+                self.state.read().logger.log_error(&format!(
+                    "[{}, {}] invalid config: {err}",
+                    file!(),
+                    line!()
+                ));
+                return;
+            }
         }
 
-        let processing_handle = self.clone().start_message_processing();
-        let state = self.state.clone();
+        // Synchronize the client db with the local videos
+        let prune = self.state.read().config.prune_removed_videos;
+        let res = self
+            .db
+            .init(init_client_path, Some("video_metadata.json"), prune);
+        match res {
+            Ok(report) => {
+                let logger = &self.state.read().logger;
+                for err in &report.errors {
+                    logger.log_error(err);
+                }
+                for conflict in &report.conflicts {
+                    logger.log_warn(conflict);
+                }
+                logger.log_info(&format!(
+                    "[{}, {}] db synchronized: {report}",
+                    file!(),
+                    line!()
+                ));
+            }
+            Err(err) => {
+                self.state.read().logger.log_error(&err);
+                return;
+            }
+        }
 
-        // Launch rocket in a separate task
-        let rocket = Self::configure(self).launch();
+        self.clone().start_message_processing();
 
-        // Monitor termination flag in a separate task
-        let termination_handle = tokio::spawn(async move {
-            loop {
-                if state.read().fsm == FsmStatus::Terminated {
-                    // Wait for processing thread to complete
-                    let _ = processing_handle.join();
-                    break;
-                }
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let rocket = match Self::configure(self.clone()).ignite().await {
+            Ok(rocket) => rocket,
+            Err(err) => {
+                self.state.read().logger.log_error(&format!(
+                    "[{}, {}] failed to start rocket: {err}",
+                    file!(),
+                    line!()
+                ));
+                self.shutdown.cancel();
+                self.finish_shutdown().await;
+                return;
             }
-        });
+        };
+        let rocket_shutdown = rocket.shutdown();
+        let mut rocket_handle = tokio::spawn(rocket.launch());
+
+        // Stop on `Crash`, or when Rocket stops on its own (e.g. on Ctrl-C)
+        let rocket_stopped = tokio::select! {
+            _ = &mut rocket_handle => true,
+            () = self.shutdown.cancelled() => false,
+        };
+        self.shutdown.cancel();
+        self.state.write().fsm = FsmStatus::Terminated;
 
-        // Run both tasks concurrently
-        tokio::select! {
-            _ = rocket => {},
-            _ = termination_handle => {},
+        if !rocket_stopped {
+            // Let the requests in progress finish, SSE streams end with a terminal event
+            rocket_shutdown.notify();
+            let _ = rocket_handle.await;
         }
+
+        self.finish_shutdown().await;
         println!("[CLIENT] Terminated");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel::unbounded;
+    use std::time::Duration;
+
+    #[test]
+    fn crash_stops_every_background_thread() {
+        let (event_send, _event_recv) = unbounded();
+        let (command_send, command_recv) = unbounded();
+        let (_packet_send, packet_recv) = unbounded();
+        let client = ClientVideo::new(
+            1,
+            event_send,
+            command_recv,
+            packet_recv,
+            HashMap::new(),
+            ClientConfig::default(),
+        );
+        client.clone().start_message_processing();
+
+        command_send.send(DroneCommand::Crash).unwrap();
+
+        // Join from another thread, so a thread that never stops fails the test
+        let (joined_send, joined_recv) = unbounded();
+        let joining = client.clone();
+        std::thread::spawn(move || {
+            let _ = joined_send.send(joining.join_background_threads());
+        });
+        let joined = joined_recv.recv_timeout(Duration::from_secs(5));
+
+        assert_eq!(joined, Ok(2)); // Message processing and flood scheduler
+        assert!(client.shutdown.is_cancelled());
+        assert!(matches!(client.state.read().fsm, FsmStatus::Terminated));
+        assert!(client.background_threads.lock().is_empty());
+    }
+}
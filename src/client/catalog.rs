@@ -0,0 +1,147 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use packet_forge::{FileHash, VideoMetaData};
+use serde::Serialize;
+use wg_internal::network::NodeId;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| {
+            u64::try_from(time.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+/// Video advertised by one or more servers
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CatalogEntry {
+    pub metadata: VideoMetaData, // From the last server listing the video
+    pub servers: BTreeSet<NodeId>,
+    pub last_seen_ms: u64, // Unix time in milliseconds of the last file list containing the video
+}
+
+/// Videos advertised by every discovered server, merged by id
+#[derive(Debug, Default)]
+pub(crate) struct NetworkCatalog {
+    entries: HashMap<FileHash, CatalogEntry>,
+}
+
+impl NetworkCatalog {
+    /// Replaces the videos listed by `server_id` with `videos`
+    pub fn update_server(&mut self, server_id: NodeId, videos: &[VideoMetaData]) {
+        let now = now_ms();
+        self.remove_server(server_id);
+
+        for video in videos {
+            let entry = self
+                .entries
+                .entry(video.id)
+                .or_insert_with(|| CatalogEntry {
+                    metadata: video.clone(),
+                    servers: BTreeSet::new(),
+                    last_seen_ms: now,
+                });
+            entry.metadata = video.clone();
+            entry.servers.insert(server_id);
+            entry.last_seen_ms = now;
+        }
+    }
+
+    /// Forgets the videos of a server, dropping the ones no other server lists
+    pub fn remove_server(&mut self, server_id: NodeId) {
+        self.entries.retain(|_, entry| {
+            entry.servers.remove(&server_id);
+            !entry.servers.is_empty()
+        });
+    }
+
+    /// Every video of the catalog, sorted by title
+    pub fn entries(&self) -> Vec<CatalogEntry> {
+        let mut entries: Vec<CatalogEntry> = self.entries.values().cloned().collect();
+        entries.sort_by(|a, b| {
+            a.metadata
+                .title
+                .cmp(&b.metadata.title)
+                .then(a.metadata.id.cmp(&b.metadata.id))
+        });
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(id: FileHash, title: &str) -> VideoMetaData {
+        VideoMetaData {
+            id,
+            title: title.to_string(),
+            description: String::new(),
+            duration: 10,
+            mime_type: "video/mp4".to_string(),
+            created_at: String::new(),
+        }
+    }
+
+    /// (id, title, servers) of every entry
+    fn summary(catalog: &NetworkCatalog) -> Vec<(FileHash, String, Vec<NodeId>)> {
+        catalog
+            .entries()
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.metadata.id,
+                    entry.metadata.title,
+                    entry.servers.into_iter().collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn videos_of_every_server_are_merged_by_id() {
+        let mut catalog = NetworkCatalog::default();
+        catalog.update_server(1, &[video(1, "b"), video(2, "a")]);
+        catalog.update_server(2, &[video(2, "a, renamed"), video(3, "a")]);
+
+        assert_eq!(
+            summary(&catalog),
+            vec![
+                (3, "a".to_string(), vec![2]),
+                (2, "a, renamed".to_string(), vec![1, 2]),
+                (1, "b".to_string(), vec![1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_file_list_replaces_the_videos_of_its_server() {
+        let mut catalog = NetworkCatalog::default();
+        catalog.update_server(1, &[video(1, "a"), video(2, "b")]);
+        catalog.update_server(2, &[video(2, "b")]);
+        catalog.update_server(1, &[video(3, "c")]);
+
+        assert_eq!(
+            summary(&catalog),
+            vec![(2, "b".to_string(), vec![2]), (3, "c".to_string(), vec![1])]
+        );
+
+        catalog.update_server(2, &[]);
+        assert_eq!(summary(&catalog), vec![(3, "c".to_string(), vec![1])]);
+    }
+
+    #[test]
+    fn removed_server_keeps_the_videos_of_the_others() {
+        let mut catalog = NetworkCatalog::default();
+        catalog.update_server(1, &[video(1, "a"), video(2, "b")]);
+        catalog.update_server(2, &[video(2, "b")]);
+
+        catalog.remove_server(1);
+        assert_eq!(summary(&catalog), vec![(2, "b".to_string(), vec![2])]);
+        catalog.remove_server(3);
+        assert_eq!(catalog.entries().len(), 1);
+        catalog.remove_server(2);
+        assert!(catalog.entries().is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use wg_internal::network::NodeId;
+
+/// Files looked up inside `init_client_path`, in order
+const TOML_CONFIG_FILE: &str = "client_config.toml";
+const JSON_CONFIG_FILE: &str = "client_config.json";
+const ENV_PREFIX: &str = "CLIENT_VIDEO_"; // e.g. `CLIENT_VIDEO_HTTP_BASE_PORT=9000`
+
+/// Runtime settings of the client.
+/// Every field is optional in the config file, missing ones keep their default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ClientConfig {
+    pub db_path: String,     // Each client stores its db in `<db_path>/client_<id>`
+    pub http_base_port: u16, // Each client serves the frontend on `http_base_port + id`
+    pub flooding_interval_secs: u64, // Time between two periodic flood_req
+    pub flood_throttle_secs: u64, // Min time between two flood_req caused by events
+    pub flood_debounce_millis: u64, // Events within this time are merged in a single flood_req
+    pub prune_removed_videos: bool, // Remove from the db the local videos no longer listed
+    pub video_list_channel_capacity: usize, // Video lists buffered for the frontend
+    pub video_channel_capacity: usize, // Chunks buffered for each playback session
+    pub preroll_millis: u64, // Video buffered before starting playback
+    pub preroll_chunks: usize, // Chunks buffered before starting playback if the bitrate is unknown
+    pub peer_stall_timeout_secs: u64, // Max time without receiving an assigned chunk
+    pub max_peer_nacks: u32, // Nacks after which a peer is considered unreachable
+    pub shutdown_drain_secs: u64, // Max time spent waiting for the acks of the sent fragments on shutdown
+    pub request_timeout_secs: u64, // Max time waiting for a server to answer a request
+    pub request_retries: u32,     // Times a request is resent before reporting it to the frontend
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            db_path: "db/client_video".to_string(),
+            http_base_port: 8000,
+            flooding_interval_secs: 180,
+            flood_throttle_secs: 5,
+            flood_debounce_millis: 500,
+            prune_removed_videos: true,
+            video_list_channel_capacity: 10,
+            video_channel_capacity: 1024,
+            preroll_millis: 2000,
+            preroll_chunks: 4,
+            peer_stall_timeout_secs: 10,
+            max_peer_nacks: 10,
+            shutdown_drain_secs: 5,
+            request_timeout_secs: 5,
+            request_retries: 2,
+        }
+    }
+}
+
+/// Replaces `value` with the environment variable `<ENV_PREFIX><name>`, if set
+fn env_override<T: FromStr>(name: &str, value: &mut T) -> Result<(), String>
+where
+    T::Err: std::fmt::Display,
+{
+    let key = format!("{ENV_PREFIX}{name}");
+    let Ok(raw) = std::env::var(&key) else {
+        return Ok(());
+    };
+    *value = raw
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid value '{raw}' for {key}: {e}"))?;
+    Ok(())
+}
+
+impl ClientConfig {
+    /// Loads `client_config.toml` or `client_config.json` from `init_client_path`,
+    /// falling back to the defaults, then applies the environment overrides
+    pub fn load(init_client_path: &str) -> Result<Self, String> {
+        let toml_path = Path::new(init_client_path).join(TOML_CONFIG_FILE);
+        let json_path = Path::new(init_client_path).join(JSON_CONFIG_FILE);
+
+        let mut config = if toml_path.is_file() {
+            let content = read_config_file(&toml_path)?;
+            toml::from_str(&content)
+                .map_err(|e| format!("Error parsing {TOML_CONFIG_FILE}: {e}"))?
+        } else if json_path.is_file() {
+            let content = read_config_file(&json_path)?;
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Error parsing {JSON_CONFIG_FILE}: {e}"))?
+        } else {
+            Self::default()
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env_override("DB_PATH", &mut self.db_path)?;
+        env_override("HTTP_BASE_PORT", &mut self.http_base_port)?;
+        env_override("FLOODING_INTERVAL_SECS", &mut self.flooding_interval_secs)?;
+        env_override("FLOOD_THROTTLE_SECS", &mut self.flood_throttle_secs)?;
+        env_override("FLOOD_DEBOUNCE_MILLIS", &mut self.flood_debounce_millis)?;
+        env_override("PRUNE_REMOVED_VIDEOS", &mut self.prune_removed_videos)?;
+        env_override(
+            "VIDEO_LIST_CHANNEL_CAPACITY",
+            &mut self.video_list_channel_capacity,
+        )?;
+        env_override("VIDEO_CHANNEL_CAPACITY", &mut self.video_channel_capacity)?;
+        env_override("PREROLL_MILLIS", &mut self.preroll_millis)?;
+        env_override("PREROLL_CHUNKS", &mut self.preroll_chunks)?;
+        env_override("PEER_STALL_TIMEOUT_SECS", &mut self.peer_stall_timeout_secs)?;
+        env_override("MAX_PEER_NACKS", &mut self.max_peer_nacks)?;
+        env_override("SHUTDOWN_DRAIN_SECS", &mut self.shutdown_drain_secs)?;
+        env_override("REQUEST_TIMEOUT_SECS", &mut self.request_timeout_secs)?;
+        env_override("REQUEST_RETRIES", &mut self.request_retries)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.db_path.trim().is_empty() {
+            return Err("db_path must not be empty".to_string());
+        }
+        if self
+            .http_base_port
+            .checked_add(u16::from(NodeId::MAX))
+            .is_none()
+        {
+            return Err(format!(
+                "http_base_port {} leaves no room for the port of every client",
+                self.http_base_port
+            ));
+        }
+
+        let positive = [
+            ("flooding_interval_secs", self.flooding_interval_secs),
+            ("peer_stall_timeout_secs", self.peer_stall_timeout_secs),
+            ("request_timeout_secs", self.request_timeout_secs),
+            ("max_peer_nacks", u64::from(self.max_peer_nacks)),
+            (
+                "video_list_channel_capacity",
+                self.video_list_channel_capacity as u64,
+            ),
+            ("video_channel_capacity", self.video_channel_capacity as u64),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                return Err(format!("{name} must be greater than 0"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Folder of the db of client `id`
+    pub fn client_db_path(&self, id: NodeId) -> String {
+        format!("{}/client_{id}", self.db_path)
+    }
+
+    /// Port of the frontend of client `id`
+    pub fn http_port(&self, id: NodeId) -> u16 {
+        self.http_base_port.saturating_add(u16::from(id))
+    }
+
+    pub fn flooding_interval(&self) -> Duration {
+        Duration::from_secs(self.flooding_interval_secs)
+    }
+
+    pub fn flood_throttle(&self) -> Duration {
+        Duration::from_secs(self.flood_throttle_secs)
+    }
+
+    pub fn flood_debounce(&self) -> Duration {
+        Duration::from_millis(self.flood_debounce_millis)
+    }
+
+    pub fn preroll(&self) -> Duration {
+        Duration::from_millis(self.preroll_millis)
+    }
+
+    pub fn peer_stall_timeout(&self) -> Duration {
+        Duration::from_secs(self.peer_stall_timeout_secs)
+    }
+
+    pub fn shutdown_drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_drain_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| format!("Error reading file {}: {e}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(ClientConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn empty_db_path_is_rejected() {
+        let config = ClientConfig {
+            db_path: " ".to_string(),
+            ..ClientConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn port_of_every_client_must_fit() {
+        let max_port = u16::MAX - u16::from(NodeId::MAX);
+        let config = ClientConfig {
+            http_base_port: max_port,
+            ..ClientConfig::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ClientConfig {
+            http_base_port: max_port + 1,
+            ..ClientConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn zero_timeouts_and_capacities_are_rejected() {
+        let config = ClientConfig {
+            request_timeout_secs: 0,
+            ..ClientConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ClientConfig {
+            video_channel_capacity: 0,
+            ..ClientConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn missing_fields_keep_their_default() {
+        let config: ClientConfig = toml::from_str("http_base_port = 9000").unwrap();
+        assert_eq!(config.http_base_port, 9000);
+        assert_eq!(config.db_path, ClientConfig::default().db_path);
+        assert!(toml::from_str::<ClientConfig>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn invalid_env_values_are_errors() {
+        let mut value = 5u64;
+        env_override("TEST_UNSET", &mut value).unwrap();
+        assert_eq!(value, 5);
+
+        std::env::set_var(format!("{ENV_PREFIX}TEST_VALUE"), " 7 ");
+        env_override("TEST_VALUE", &mut value).unwrap();
+        assert_eq!(value, 7);
+
+        std::env::set_var(format!("{ENV_PREFIX}TEST_INVALID"), "seven");
+        assert!(env_override("TEST_INVALID", &mut value).is_err());
+        assert_eq!(value, 7);
+    }
+}
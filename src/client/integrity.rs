@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use packet_forge::{ChunkResponse, FileHash};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::db::{mp4::SeekIndex, structures::VideoDigests};
+
+/// Index of the `ChunkResponse` carrying the `ChunkManifest` of a video
+pub(crate) const MANIFEST_CHUNK_INDEX: u32 = u32::MAX;
+pub(crate) const MANIFEST_TIMEOUT: Duration = Duration::from_secs(5); // Max time chunks wait for their manifest
+
+type DigestT = [u8; 32];
+
+fn digest(data: &[u8]) -> DigestT {
+    Sha256::digest(data).into()
+}
+
+/// SHA-256 digests of a video and of each of its chunks, sent before the chunks.
+/// It also carries the size of the video, used to estimate its bitrate,
+/// and its seek index, used to start playing from any fragment.
+/// The manifest comes from the same peer as the chunks: it detects chunks corrupted
+/// or mixed up on the way, not a peer sending a different video on purpose.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ChunkManifest {
+    file_digest: DigestT,
+    chunk_digests: Vec<DigestT>,
+    size: u64, // Bytes of the whole video
+    seek_index: Option<SeekIndex>,
+}
+
+impl ChunkManifest {
+    /// Creates the manifest from the digests computed when the video was stored
+    pub fn new(digests: VideoDigests, size: u64, seek_index: Option<SeekIndex>) -> Self {
+        Self {
+            file_digest: digests.file_digest,
+            chunk_digests: digests.chunk_digests,
+            size,
+            seek_index,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn seek_index(&self) -> Option<&SeekIndex> {
+        self.seek_index.as_ref()
+    }
+
+    pub fn to_bytes(&self) -> Result<Bytes, String> {
+        bincode::serialize(self)
+            .map(Bytes::from)
+            .map_err(|e| format!("Serialization error: {e}"))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        bincode::deserialize(data).map_err(|e| format!("Deserialization error: {e}"))
+    }
+
+    fn verify_chunk(&self, content: &ChunkResponse) -> bool {
+        let Ok(index) = usize::try_from(content.chunk_index) else {
+            return false;
+        };
+
+        self.chunk_digests.len() == content.total_n_chunks as usize
+            && self
+                .chunk_digests
+                .get(index)
+                .is_some_and(|expected| *expected == digest(&content.chunk_data))
+    }
+
+    fn verify_file(&self, payload: &[u8]) -> bool {
+        self.file_digest == digest(payload)
+    }
+}
+
+pub(crate) enum ChunkVerification {
+    Valid(ChunkResponse),
+    Invalid(ChunkResponse),
+    Unverified(ChunkResponse), // No manifest received in time, it can be played but not stored
+    Pending,                   // Waiting for the manifest
+}
+
+/// Chunks of a video received before its manifest
+struct PendingChunks {
+    since: Instant, // First chunk parked
+    chunks: Vec<ChunkResponse>,
+}
+
+/// Verifies the received chunks against the manifest of their video
+#[derive(Default)]
+pub(crate) struct ChunkVerifier {
+    manifests: HashMap<FileHash, ChunkManifest>,
+    unverified: HashMap<FileHash, PendingChunks>, // Chunks received before the manifest
+    unchecked: HashSet<FileHash>,                 // Videos whose manifest did not arrive in time
+}
+
+impl ChunkVerifier {
+    /// Stores the manifest of `video_id`, returning the verification of the chunks waiting for it.
+    /// Returns an error if a different manifest was already received.
+    pub fn add_manifest(
+        &mut self,
+        video_id: FileHash,
+        manifest: ChunkManifest,
+    ) -> Result<Vec<ChunkVerification>, String> {
+        if let Some(current) = self.manifests.get(&video_id) {
+            if *current != manifest {
+                return Err(format!("conflicting manifest for video {video_id}"));
+            }
+            return Ok(Vec::new());
+        }
+        self.manifests.insert(video_id, manifest);
+        self.unchecked.remove(&video_id);
+
+        let pending = self
+            .unverified
+            .remove(&video_id)
+            .map(|pending| pending.chunks)
+            .unwrap_or_default();
+        Ok(pending
+            .into_iter()
+            .map(|chunk| self.verify(chunk))
+            .collect())
+    }
+
+    pub fn verify(&mut self, content: ChunkResponse) -> ChunkVerification {
+        let Some(manifest) = self.manifests.get(&content.file_hash) else {
+            if self.unchecked.contains(&content.file_hash) {
+                return ChunkVerification::Unverified(content);
+            }
+            self.unverified
+                .entry(content.file_hash)
+                .or_insert_with(|| PendingChunks {
+                    since: Instant::now(),
+                    chunks: Vec::new(),
+                })
+                .chunks
+                .push(content);
+            return ChunkVerification::Pending;
+        };
+
+        if manifest.verify_chunk(&content) {
+            ChunkVerification::Valid(content)
+        } else {
+            ChunkVerification::Invalid(content)
+        }
+    }
+
+    /// Releases the chunks that waited longer than `timeout` for their manifest.
+    /// The following chunks of their videos are released as soon as they arrive.
+    pub fn take_expired(&mut self, timeout: Duration) -> Vec<ChunkVerification> {
+        let expired: Vec<FileHash> = self
+            .unverified
+            .iter()
+            .filter(|(_, pending)| pending.since.elapsed() >= timeout)
+            .map(|(video_id, _)| *video_id)
+            .collect();
+
+        let mut released = Vec::new();
+        for video_id in expired {
+            self.unchecked.insert(video_id);
+            if let Some(pending) = self.unverified.remove(&video_id) {
+                released.extend(
+                    pending
+                        .chunks
+                        .into_iter()
+                        .map(ChunkVerification::Unverified),
+                );
+            }
+        }
+        released
+    }
+
+    /// Whether the manifest of `video_id` was received
+    pub fn has_manifest(&self, video_id: FileHash) -> bool {
+        self.manifests.contains_key(&video_id)
+    }
+
+    /// Seek index received with the manifest of `video_id`
+    pub fn seek_index(&self, video_id: FileHash) -> Option<&SeekIndex> {
+        self.manifests.get(&video_id)?.seek_index()
+    }
+
+    /// Size of `video_id` received with its manifest
+    pub fn video_size(&self, video_id: FileHash) -> Option<u64> {
+        self.manifests.get(&video_id).map(ChunkManifest::size)
+    }
+
+    /// Whether `payload` matches the digest of the whole video
+    pub fn verify_file(&self, video_id: FileHash, payload: &[u8]) -> bool {
+        self.manifests
+            .get(&video_id)
+            .is_some_and(|manifest| manifest.verify_file(payload))
+    }
+
+    /// Forgets the manifest and the pending chunks of `video_id`
+    pub fn remove(&mut self, video_id: FileHash) {
+        self.manifests.remove(&video_id);
+        self.unverified.remove(&video_id);
+        self.unchecked.remove(&video_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(index: u32, data: &'static [u8]) -> ChunkResponse {
+        ChunkResponse::new(1, index, 2, Bytes::from_static(data))
+    }
+
+    #[test]
+    fn chunks_without_manifest_are_released_after_the_timeout() {
+        let mut verifier = ChunkVerifier::default();
+        assert!(matches!(
+            verifier.verify(chunk(0, b"a")),
+            ChunkVerification::Pending
+        ));
+        assert!(verifier.take_expired(MANIFEST_TIMEOUT).is_empty());
+
+        let released = verifier.take_expired(Duration::ZERO);
+        assert!(matches!(
+            released.as_slice(),
+            [ChunkVerification::Unverified(_)]
+        ));
+        assert!(matches!(
+            verifier.verify(chunk(1, b"b")),
+            ChunkVerification::Unverified(_)
+        ));
+        assert!(!verifier.verify_file(1, b"ab"));
+    }
+
+    #[test]
+    fn late_manifest_verifies_the_following_chunks() {
+        let mut verifier = ChunkVerifier::default();
+        verifier.verify(chunk(0, b"a"));
+        verifier.take_expired(Duration::ZERO);
+
+        let digests = VideoDigests {
+            file_digest: digest(b"ab"),
+            chunk_digests: vec![digest(b"a"), digest(b"b")],
+        };
+        let manifest = ChunkManifest::new(digests, 2, None);
+        assert!(verifier.add_manifest(1, manifest).is_ok());
+        assert!(matches!(
+            verifier.verify(chunk(1, b"b")),
+            ChunkVerification::Valid(_)
+        ));
+        assert!(matches!(
+            verifier.verify(chunk(1, b"c")),
+            ChunkVerification::Invalid(_)
+        ));
+        assert!(verifier.verify_file(1, b"ab"));
+    }
+}
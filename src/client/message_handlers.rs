@@ -2,34 +2,109 @@ mod command_dispatcher;
 mod node_messages;
 mod packet_dispatcher;
 
-use crossbeam::channel::TryRecvError;
-use std::{thread, time::Duration};
+use crossbeam::channel::{RecvTimeoutError, TryRecvError};
+use std::{thread, time::Instant};
 
-use super::{utils::start_flooding::init_flood_request, ClientVideo, FsmStatus, FLOODING_TIMER};
+use super::{
+    playback_buffer::PLAYBACK_CHECK_INTERVAL,
+    swarm::SWARM_CHECK_INTERVAL,
+    utils::{
+        pending_requests::REQUEST_CHECK_INTERVAL, reassembly::REASSEMBLY_CHECK_INTERVAL,
+        retransmission::RETRANSMISSION_CHECK_INTERVAL,
+    },
+    ClientVideo,
+};
 
 impl ClientVideo {
-    /// Sends a `flood_req` every 60 seconds in a separate thread
-    fn start_flooding(&self) {
-        let state = self.state.clone();
-        thread::spawn(move || loop {
-            init_flood_request(&state);
-            thread::sleep(Duration::from_secs(FLOODING_TIMER));
-        });
+    /// Starts the flood scheduler thread, until the client shuts down
+    fn start_flood_scheduler(&self) {
+        let handle = self
+            .flood_scheduler
+            .start(self.state.clone(), self.shutdown.clone());
+        self.background_threads.lock().push(handle);
     }
 
-    #[must_use]
-    pub(crate) fn start_message_processing(self) -> thread::JoinHandle<()> {
+    /// Keeps handling acks and retransmissions until every sent fragment is acked,
+    /// or `shutdown_drain_secs` elapse
+    fn drain_sends(&self) {
+        let deadline = Instant::now() + self.state.read().config.shutdown_drain_timeout();
+        let packet_recv = self.state.read().packet_recv.clone();
+
+        while Instant::now() < deadline {
+            let idle = {
+                let state = self.state.read();
+                state.retransmissions.is_empty() && state.send_windows.is_idle()
+            };
+            if idle {
+                return;
+            }
+
+            self.check_retransmissions();
+            match packet_recv.recv_timeout(RETRANSMISSION_CHECK_INTERVAL) {
+                Ok(packet) => self.packet_dispatcher(&packet),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+
+        self.state.read().logger.log_warn(&format!(
+            "[{}, {}] shutting down with fragments still waiting for an ack",
+            file!(),
+            line!()
+        ));
+    }
+
+    /// Starts the thread handling packets and commands, until the client shuts down
+    pub(crate) fn start_message_processing(self) {
         let state = self.state.clone();
+        let background_threads = self.background_threads.clone();
 
-        self.start_flooding();
+        self.start_flood_scheduler();
+
+        let handle = thread::spawn(move || {
+            let mut last_swarm_check = Instant::now();
+            let mut last_retransmission_check = Instant::now();
+            let mut last_reassembly_check = Instant::now();
+            let mut last_playback_check = Instant::now();
+            let mut last_request_check = Instant::now();
 
-        thread::spawn(move || {
             loop {
-                // If the client is terminated, break the loop
-                if state.read().fsm == FsmStatus::Terminated {
+                // If the client is shutting down, stop handling new work
+                if self.shutdown.is_cancelled() {
                     break;
                 }
 
+                // Resend packets that did not receive an ack in time
+                if last_retransmission_check.elapsed() >= RETRANSMISSION_CHECK_INTERVAL {
+                    self.check_retransmissions();
+                    last_retransmission_check = Instant::now();
+                }
+
+                // Free incomplete messages that stopped receiving fragments
+                if last_reassembly_check.elapsed() >= REASSEMBLY_CHECK_INTERVAL {
+                    self.evict_stale_fragments();
+                    last_reassembly_check = Instant::now();
+                }
+
+                // Detect playback stalls while no chunk arrives and free the idle sessions
+                if last_playback_check.elapsed() >= PLAYBACK_CHECK_INTERVAL {
+                    self.check_playback_sessions();
+                    last_playback_check = Instant::now();
+                }
+
+                // Retry the requests to servers that got no answer in time
+                if last_request_check.elapsed() >= REQUEST_CHECK_INTERVAL {
+                    self.check_pending_requests();
+                    last_request_check = Instant::now();
+                }
+
+                // Reassign chunks of stalled peers and release the chunks without manifest
+                if last_swarm_check.elapsed() >= SWARM_CHECK_INTERVAL {
+                    self.check_swarm_downloads();
+                    self.check_chunk_manifests();
+                    last_swarm_check = Instant::now();
+                }
+
                 let controller_recv = state.read().controller_recv.clone();
                 match controller_recv.try_recv() {
                     Ok(command) => self.command_dispatcher(&command),
@@ -56,6 +131,9 @@ impl ClientVideo {
                     }
                 }
             }
-        })
+
+            self.drain_sends();
+        });
+        background_threads.lock().push(handle);
     }
 }
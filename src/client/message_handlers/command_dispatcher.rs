@@ -1,6 +1,6 @@
 use wg_internal::controller::DroneCommand;
 
-use crate::client::{ClientVideo, FsmStatus};
+use crate::client::{utils::flood_scheduler::FloodReason, ClientVideo, FsmStatus};
 
 impl ClientVideo {
     pub(crate) fn command_dispatcher(&self, command: &DroneCommand) {
@@ -9,13 +9,14 @@ impl ClientVideo {
         match command {
             DroneCommand::Crash => {
                 state.write().fsm = FsmStatus::Terminated;
+                self.shutdown.cancel();
             }
             DroneCommand::AddSender(node_id, sender) => {
-                self.start_flooding();
                 state.write().senders.insert(*node_id, sender.clone());
+                self.flood_scheduler.flood_soon(FloodReason::TopologyChange);
             }
             DroneCommand::RemoveSender(node_id) => {
-                self.start_flooding();
+                self.flood_scheduler.flood_soon(FloodReason::TopologyChange);
 
                 let res = state.write().senders.remove(node_id);
                 if res.is_none() {
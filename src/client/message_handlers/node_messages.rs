@@ -1,9 +1,17 @@
+use std::collections::VecDeque;
+
 use packet_forge::{
     FileHash, FileMetadata, MessageType, RequestFileList, RequestPeerList, SubscribeClient,
 };
 use wg_internal::network::NodeId;
 
-use crate::client::{utils::sends::send_msg, ClientVideo};
+use crate::client::{
+    utils::{
+        pending_requests::{RequestError, RequestKind},
+        sends::send_msg,
+    },
+    ClientVideo,
+};
 
 impl ClientVideo {
     pub(crate) fn send_subscribe_client(&self, dest_id: NodeId) {
@@ -24,16 +32,13 @@ impl ClientVideo {
         ));
 
         // Send message
-        let res = send_msg(&self.state, dest_id, msg);
-        if let Err(err) = res {
-            self.state.read().logger.log_error(&err);
+        match send_msg(&self.state, dest_id, msg) {
+            Ok(()) => self.track_request(RequestKind::SubscribeClient, dest_id),
+            Err(err) => self.fail_unsent_request(RequestKind::SubscribeClient, dest_id, &err),
         }
     }
 
     pub(crate) fn send_req_file_list(&self) {
-        // Create a RequestFileList message
-        let msg = MessageType::RequestFileList(RequestFileList::new(self.get_id()));
-
         // Check if there are servers available
         if self.state.read().servers.is_empty() {
             self.state.read().logger.log_error(&format!(
@@ -45,50 +50,115 @@ impl ClientVideo {
         }
 
         // Send request to all servers
-        let servers = self.state.read().servers.clone();
-        for dest_id in servers.keys() {
-            // Send message
-            let res = send_msg(&self.state, *dest_id, msg.clone());
-            // If send failed, send to frontend an empty list
-            if let Err(err) = res {
-                if let Some(sender) = &self.file_list_sender.read().clone() {
-                    let _ = sender.send((*dest_id, Vec::new()));
-                } else {
-                    self.state.read().logger.log_error(&format!(
-                        "[{}, {}] frontend file list sender not found",
-                        file!(),
-                        line!()
-                    ));
-                }
-
-                self.state.read().logger.log_error(&err);
-            }
+        let servers: Vec<NodeId> = self.state.read().servers.keys().copied().collect();
+        for dest_id in servers {
+            self.send_req_file_list_to(dest_id);
         }
     }
 
+    pub(crate) fn send_req_file_list_to(&self, dest_id: NodeId) {
+        // Create a RequestFileList message
+        let msg = MessageType::RequestFileList(RequestFileList::new(self.get_id()));
+
+        // Send message, if it fails the frontend gets an empty list
+        match send_msg(&self.state, dest_id, msg) {
+            Ok(()) => self.track_request(RequestKind::FileList, dest_id),
+            Err(err) => self.fail_unsent_request(RequestKind::FileList, dest_id, &err),
+        }
+    }
+
+    /// Sorts `servers` by the number of hops of their best path, the unreachable ones last.
+    /// `RoutingHandler` exposes no path cost, so the hop count stands in for it.
+    fn rank_servers_by_path(&self, servers: Vec<NodeId>) -> Vec<NodeId> {
+        let id = self.get_id();
+        let mut ranked: Vec<(usize, NodeId)> = servers
+            .into_iter()
+            .map(|server| {
+                // Locked once per server, so packet handlers are not stalled by the whole ranking
+                let path = self.state.write().routing_handler.best_path(id, server);
+                let hops = path.map_or(usize::MAX, |srh| srh.hops.len());
+                (hops, server)
+            })
+            .collect();
+        ranked.sort_unstable();
+        ranked.into_iter().map(|(_, server)| server).collect()
+    }
+
+    /// Asks the peers of `video_id` to the closest server advertising it.
+    /// The others are tried in order if it does not answer or has no peers.
     pub(crate) fn send_req_peer_list(&self, video_id: FileHash) {
-        // Create RequestPeerList
-        let msg = MessageType::RequestPeerList(RequestPeerList::new(self.get_id(), video_id));
+        // Find the servers where the video_id is available
+        let servers: Vec<NodeId> = self
+            .state
+            .read()
+            .servers
+            .iter()
+            .filter(|(_, videos)| videos.contains(&video_id))
+            .map(|(server_id, _)| *server_id)
+            .collect();
+
+        // Report to the frontend if video_id is not found in any server
+        if servers.is_empty() {
+            self.report_request_error(RequestError {
+                kind: RequestKind::PeerList(video_id),
+                dest: None,
+                message: format!("video {video_id} not found in servers"),
+            });
+            self.playback_sessions.write().fail(video_id);
+            return;
+        }
 
-        // Check if the video_id is available in any server
-        let servers = self.state.read().servers.clone();
-        for server in &servers {
-            if server.1.contains(&video_id) {
-                // Send message
-                let res = send_msg(&self.state, *server.0, msg);
-                if let Err(err) = res {
-                    self.state.read().logger.log_error(&err);
-                }
-                return;
+        let servers = self.rank_servers_by_path(servers);
+        self.state
+            .write()
+            .peer_list_servers
+            .insert(video_id, servers.into());
+        self.send_req_peer_list_to_next(video_id);
+    }
+
+    /// Asks the peers of `video_id` to the next server not tried yet,
+    /// reporting the failure to the frontend once every server was tried
+    pub(crate) fn send_req_peer_list_to_next(&self, video_id: FileHash) {
+        let next_server = {
+            let mut state = self.state.write();
+            let next_server = state
+                .peer_list_servers
+                .get_mut(&video_id)
+                .and_then(VecDeque::pop_front);
+            if next_server.is_none() {
+                state.peer_list_servers.remove(&video_id);
             }
+            next_server
+        };
+
+        // The sessions waiting for the video stop buffering once every server was tried
+        let Some(server_id) = next_server else {
+            self.report_request_error(RequestError {
+                kind: RequestKind::PeerList(video_id),
+                dest: None,
+                message: format!("no server provided peers for video {video_id}"),
+            });
+            self.playback_sessions.write().fail(video_id);
+            return;
+        };
+
+        // A failed send moves on to the following server
+        if let Err(err) = self.send_req_peer_list_to(server_id, video_id) {
+            self.fail_unsent_request(RequestKind::PeerList(video_id), server_id, &err);
         }
+    }
 
-        // Log error if video_id is not found in any server
-        self.state.read().logger.log_error(&format!(
-            "[{}, {}] video_id {} not found in servers",
-            file!(),
-            line!(),
-            video_id
-        ));
+    pub(crate) fn send_req_peer_list_to(
+        &self,
+        dest_id: NodeId,
+        video_id: FileHash,
+    ) -> Result<(), String> {
+        // Create RequestPeerList
+        let msg = MessageType::RequestPeerList(RequestPeerList::new(self.get_id(), video_id));
+
+        // Send message
+        send_msg(&self.state, dest_id, msg)?;
+        self.track_request(RequestKind::PeerList(video_id), dest_id);
+        Ok(())
     }
 }
@@ -1,7 +1,7 @@
 use packet_forge::SessionIdT;
 use wg_internal::packet::{Ack, Packet};
 
-use crate::client::ClientVideo;
+use crate::client::{utils::congestion::flush_send_window, ClientVideo};
 
 impl ClientVideo {
     pub(crate) fn handle_ack(&self, packet: &Packet, ack: &Ack, session_id: SessionIdT) {
@@ -11,12 +11,18 @@ impl ClientVideo {
             .routing_handler
             .nodes_ack(packet.routing_header.clone());
 
-        // Remove packet from history
-        let res = self
-            .state
-            .write()
-            .packets_history
-            .remove(&(ack.fragment_index, session_id));
+        // Remove packet from history and stop its timer
+        let key = (ack.fragment_index, session_id);
+        self.state.write().retransmissions.on_acked(key);
+        let res = self.state.write().packets_history.remove(&key);
+
+        // Grow the window of the destination and send the queued packets
+        let dest_id = self.state.write().send_windows.on_ack(key);
+        if let Some(dest_id) = dest_id {
+            if let Err(err) = flush_send_window(&self.state, dest_id) {
+                self.state.read().logger.log_error(&err);
+            }
+        }
 
         if res.is_none() {
             self.state.read().logger.log_error(&format!(
@@ -6,7 +6,10 @@ mod res_peer_list_handler;
 use packet_forge::{MessageType, SessionIdT};
 use wg_internal::packet::{Fragment, Packet};
 
-use crate::client::{utils::sends::send_ack, ClientVideo};
+use crate::client::{
+    utils::{reassembly::FragmentOutcome, sends::send_ack},
+    ClientVideo,
+};
 
 impl ClientVideo {
     fn handle_messages(&self, message: MessageType) {
@@ -29,15 +32,31 @@ impl ClientVideo {
     pub(crate) fn handle_fragment(&self, packet: &Packet, frag: Fragment, session_id: SessionIdT) {
         let state = &self.state;
 
-        // Add fragment to packets_map
-        state
-            .write()
-            .packets_map
-            .entry(session_id)
-            .or_default()
-            .push(frag);
+        // Session ids are only unique per sender
+        let Some(source) = packet.routing_header.hops.first().copied() else {
+            state.read().logger.log_warn(&format!(
+                "[{}, {}] fragment of session {session_id} without source",
+                file!(),
+                line!()
+            ));
+            return;
+        };
 
-        // Send an ack to the sender
+        // Add fragment to the reassembler, duplicates are dropped
+        let outcome = state.write().reassembler.insert((source, session_id), frag);
+
+        // Rejected fragments are not acked, so the sender gives up on the message after its
+        // retries and reports it instead of considering it delivered
+        if let FragmentOutcome::Rejected(err) = &outcome {
+            state.read().logger.log_warn(&format!(
+                "[{}, {}] fragment of session {session_id} from {source} rejected: {err}",
+                file!(),
+                line!()
+            ));
+            return;
+        }
+
+        // Send an ack to the sender, also for duplicates since the previous ack may be lost
         let res = send_ack(state, packet);
         if let Err(err) = res {
             state.read().logger.log_error(&format!(
@@ -48,40 +67,34 @@ impl ClientVideo {
             ));
         }
 
-        let should_assemble = {
-            let state_guard = state.read();
-            if let Some(fragments) = state_guard.packets_map.get(&session_id) {
-                let total_expected = fragments[0].total_n_fragments;
-                fragments.len() as u64 == total_expected
-            } else {
-                false
+        // If all fragments are received, assemble the message
+        let mut fragments = match outcome {
+            FragmentOutcome::Complete(fragments) => fragments,
+            FragmentOutcome::Pending | FragmentOutcome::Rejected(_) => return,
+            FragmentOutcome::Duplicate => {
+                state.read().logger.log_debug(&format!(
+                    "[{}, {}] duplicate fragment {} of session {session_id}",
+                    file!(),
+                    line!(),
+                    packet.get_fragment_index()
+                ));
+                return;
             }
         };
 
-        // If all fragments are received, assemble the message
-        if should_assemble {
-            // Clone fragments
-            let fragments_clone = state.read().packets_map.get(&session_id).cloned();
-
-            if let Some(mut fragments) = fragments_clone {
-                // Assemble message using cloned fragments
-                let assembled = match state.read().packet_forge.assemble_dynamic(&mut fragments) {
-                    Ok(message) => message,
-                    Err(e) => {
-                        state.read().logger.log_error(&format!(
-                            "[{}, {}] failed to assemble message: {:?}",
-                            file!(),
-                            line!(),
-                            e
-                        ));
-                        return;
-                    }
-                };
-
-                // Remove assembled fragments and handle the message
-                state.write().packets_map.remove(&session_id);
-                self.handle_messages(assembled);
+        let assembled = match state.read().packet_forge.assemble_dynamic(&mut fragments) {
+            Ok(message) => message,
+            Err(e) => {
+                state.read().logger.log_error(&format!(
+                    "[{}, {}] failed to assemble message: {:?}",
+                    file!(),
+                    line!(),
+                    e
+                ));
+                return;
             }
-        }
+        };
+
+        self.handle_messages(assembled);
     }
 }
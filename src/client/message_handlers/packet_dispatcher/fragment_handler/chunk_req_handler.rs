@@ -1,49 +1,78 @@
-use packet_forge::{ChunkRequest, ChunkResponse, MessageType};
+use packet_forge::{ChunkRequest, ChunkResponse, FileHash, Index, MessageType};
 
 use crate::{
-    client::{utils::sends::send_msg, video_chunker::get_video_chunks},
+    client::{
+        integrity::{ChunkManifest, MANIFEST_CHUNK_INDEX},
+        utils::sends::send_msg,
+    },
+    db::structures::VideoContentInfo,
     ClientVideo,
 };
 
+/// Returns the requested chunk indexes that exist in a video of `total_n_chunks`
+fn requested_chunks(index: &Index, total_n_chunks: u32) -> Vec<u32> {
+    match index {
+        Index::All => (0..total_n_chunks).collect(),
+        Index::Indexes(indexes) => indexes
+            .iter()
+            .copied()
+            .filter(|index| *index < total_n_chunks)
+            .collect(),
+        Index::Range(range) => (range.start..range.end.min(total_n_chunks)).collect(),
+    }
+}
+
 impl ClientVideo {
     pub(crate) fn handle_chunk_req(&self, content: &ChunkRequest) {
-        // Get video from db
-        let res = self.db.get_video_content(content.file_hash);
-        if let Err(err) = res {
-            self.state.read().logger.log_error(&format!(
-                "[{}, {}] failed to get video content: {err}",
-                file!(),
-                line!()
-            ));
-            return;
-        }
-        let res = res.unwrap();
-
-        // Split the video into chunks
-        let video_chunks = get_video_chunks(res);
-
-        // Get the total number of chunks
-        let total_n_chunks = u32::try_from(video_chunks.len());
-        if let Err(e) = total_n_chunks {
-            self.state.read().logger.log_error(&format!(
-                "[{}, {}] failed to convert len to u32: {e:?}",
-                file!(),
-                line!()
-            ));
-            return;
-        }
-        let total_n_chunks = total_n_chunks.unwrap();
-
-        // Send each chunk
-        for (i, chunk) in video_chunks.enumerate() {
-            let Ok(chunk_index) = u32::try_from(i) else {
+        // Get the number of chunks and the size of the video from db
+        let content_info = match self.db.get_video_content_info(content.file_hash) {
+            Ok(info) => info,
+            Err(err) => {
                 self.state.read().logger.log_error(&format!(
-                    "[{}, {}] failed to convert index {} to u32",
+                    "[{}, {}] failed to get video content: {err}",
                     file!(),
-                    line!(),
-                    i
+                    line!()
                 ));
                 return;
+            }
+        };
+        let total_n_chunks = content_info.n_chunks;
+
+        let chunks = requested_chunks(&content.chunk_index, total_n_chunks);
+
+        // Send the digests of the chunks first, so the requester can verify them.
+        // Every download requests the first chunk once: the chunks requested again,
+        // or from the other peers of a swarm, are verified with the same manifest.
+        if chunks.contains(&0) {
+            match self.chunk_manifest(content.file_hash, &content_info) {
+                Ok(manifest_res) => {
+                    if let Err(err) = send_msg(&self.state, content.client_id, manifest_res) {
+                        self.state.read().logger.log_error(&err);
+                    }
+                }
+                Err(err) => {
+                    self.state.read().logger.log_error(&format!(
+                        "[{}, {}] failed to create chunk manifest: {err}",
+                        file!(),
+                        line!()
+                    ));
+                    return;
+                }
+            }
+        }
+
+        // Send each requested chunk, reading it from db only when needed
+        for chunk_index in chunks {
+            let chunk = match self.db.get_video_chunk(content.file_hash, chunk_index) {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    self.state.read().logger.log_error(&format!(
+                        "[{}, {}] failed to get video chunk: {err}",
+                        file!(),
+                        line!()
+                    ));
+                    return;
+                }
             };
 
             // Create ChunkResponse
@@ -51,7 +80,7 @@ impl ClientVideo {
                 content.file_hash,
                 chunk_index,
                 total_n_chunks,
-                chunk.clone(),
+                chunk,
             ));
 
             // Send message
@@ -61,4 +90,21 @@ impl ClientVideo {
             }
         }
     }
+
+    /// Creates the `ChunkResponse` carrying the manifest of `video_id`
+    fn chunk_manifest(
+        &self,
+        video_id: FileHash,
+        content_info: &VideoContentInfo,
+    ) -> Result<MessageType, String> {
+        let seek_index = self.db.get_video_seek_index(video_id).ok();
+        let digests = self.db.get_video_digests(video_id)?;
+        let manifest = ChunkManifest::new(digests, content_info.size, seek_index).to_bytes()?;
+        Ok(MessageType::ChunkResponse(ChunkResponse::new(
+            video_id,
+            MANIFEST_CHUNK_INDEX,
+            content_info.n_chunks,
+            manifest,
+        )))
+    }
 }
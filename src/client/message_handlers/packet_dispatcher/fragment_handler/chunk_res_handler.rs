@@ -1,46 +1,209 @@
-use std::cmp::Ordering;
+use std::collections::HashSet;
 
-use bytes::Bytes;
-use packet_forge::ChunkResponse;
+use packet_forge::{ChunkResponse, FileHash};
+use wg_internal::network::NodeId;
 
-use crate::ClientVideo;
+use crate::{
+    client::integrity::{ChunkManifest, ChunkVerification, MANIFEST_CHUNK_INDEX, MANIFEST_TIMEOUT},
+    ClientVideo,
+};
 
 impl ClientVideo {
-    fn send_chunk(&self, data: Bytes) {
-        if let Some(sender) = &self.video_sender.read().clone() {
-            let _ = sender.send(data);
-        } else {
+    /// Stores the downloaded video inside the db and announces it to the servers
+    fn save_video(&self, video_id: FileHash, payload: Vec<u8>) {
+        // Never store content that does not match its digest
+        let (has_manifest, verified) = {
+            let verifier = self.chunk_verifier.read();
+            (
+                verifier.has_manifest(video_id),
+                verifier.verify_file(video_id, &payload),
+            )
+        };
+        self.chunk_verifier.write().remove(video_id);
+        if !has_manifest {
+            self.state.read().logger.log_warn(&format!(
+                "[{}, {}] no manifest received for video {video_id}, video not saved",
+                file!(),
+                line!()
+            ));
+            return;
+        }
+        if !verified {
+            self.state.read().logger.log_error(&format!(
+                "[{}, {}] video {video_id} failed verification, video not saved",
+                file!(),
+                line!()
+            ));
+            return;
+        }
+
+        let Some(metadata) = self.state.read().videos_metadata.get(&video_id).cloned() else {
+            self.state.read().logger.log_error(&format!(
+                "[{}, {}] metadata of video {video_id} not found, video not saved",
+                file!(),
+                line!()
+            ));
+            return;
+        };
+
+        if let Err(err) = self.db.insert_video(&metadata, payload.as_slice()) {
             self.state.read().logger.log_error(&format!(
-                "[{}, {}] frontend video sender not found",
+                "[{}, {}] failed to save video {video_id}: {err}",
                 file!(),
                 line!()
             ));
+            return;
+        }
+
+        self.state.read().logger.log_info(&format!(
+            "[{}, {}] video {video_id} saved in db",
+            file!(),
+            line!()
+        ));
+
+        // Notify servers about the new available video
+        let servers: Vec<NodeId> = self.state.read().servers.keys().copied().collect();
+        for server_id in servers {
+            self.send_subscribe_client(server_id);
         }
     }
 
-    pub(crate) fn handle_chunk_res(&self, content: ChunkResponse) {
-        let mut buffer = self.chunk_buffer.write(); // Buffer of out-of-order chunks
-        let mut next_index = self.next_expected_index.write();
-
-        match content.chunk_index.cmp(&next_index) {
-            Ordering::Equal => {
-                // Send the chunk directly
-                self.send_chunk(content.chunk_data);
-
-                // Update expected index and check for buffered chunks
-                *next_index += 1;
-                while let Some(data) = buffer.remove(&next_index) {
-                    self.send_chunk(data);
-                    *next_index += 1;
+    /// Forwards a verified chunk to every session waiting for its video
+    fn accept_chunk(&self, content: ChunkResponse) {
+        let mut payload = None;
+        let completed = {
+            let mut sessions = self.playback_sessions.write();
+            for session in sessions.downloading_mut(content.file_hash) {
+                let completed = session.push_chunk(
+                    content.chunk_index,
+                    content.total_n_chunks,
+                    content.chunk_data.clone(),
+                );
+                // Sessions started from a seek time miss part of the video
+                if completed && payload.is_none() && session.has_full_payload() {
+                    payload = Some(session.payload());
+                }
+            }
+            sessions.downloading(content.file_hash).is_none()
+        };
+
+        self.swarm_chunk_received(content.file_hash, content.chunk_index, completed);
+
+        if let Some(payload) = payload {
+            self.save_video(content.file_hash, payload);
+        } else if completed {
+            self.chunk_verifier.write().remove(content.file_hash);
+        }
+    }
+
+    fn handle_chunk_verification(&self, verification: ChunkVerification) {
+        match verification {
+            ChunkVerification::Valid(content) | ChunkVerification::Unverified(content) => {
+                self.accept_chunk(content);
+            }
+            ChunkVerification::Invalid(content) => {
+                self.state.read().logger.log_warn(&format!(
+                    "[{}, {}] chunk {} of video {} failed verification, requesting it again",
+                    file!(),
+                    line!(),
+                    content.chunk_index,
+                    content.file_hash
+                ));
+                self.swarm_chunk_invalid(content.file_hash, content.chunk_index);
+            }
+            ChunkVerification::Pending => {}
+        }
+    }
+
+    fn handle_chunk_manifest(&self, content: &ChunkResponse) {
+        let manifest = match ChunkManifest::from_bytes(&content.chunk_data) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                self.state.read().logger.log_error(&format!(
+                    "[{}, {}] invalid manifest for video {}: {err}",
+                    file!(),
+                    line!(),
+                    content.file_hash
+                ));
+                return;
+            }
+        };
+
+        // Start the sessions that requested a start time from the right fragment
+        let seekable = manifest.seek_index().is_some();
+        {
+            let mut sessions = self.playback_sessions.write();
+            sessions.apply_video_size(content.file_hash, manifest.size());
+            if let Some(seek_index) = manifest.seek_index() {
+                sessions.apply_seek_index(content.file_hash, seek_index);
+            }
+        }
+
+        let res = self
+            .chunk_verifier
+            .write()
+            .add_manifest(content.file_hash, manifest);
+        match res {
+            Ok(verifications) => {
+                for verification in verifications {
+                    self.handle_chunk_verification(verification);
                 }
             }
-            Ordering::Greater => {
-                // Store out-of-order chunks
-                buffer.insert(content.chunk_index, content.chunk_data);
+            Err(err) => {
+                self.state
+                    .read()
+                    .logger
+                    .log_warn(&format!("[{}, {}] {err}", file!(), line!()));
             }
-            Ordering::Less => {
-                // Duplicate chunk (or old), ignore it
+        }
+
+        if !seekable {
+            self.reject_seek(content.file_hash);
+        }
+    }
+
+    /// Plays the chunks whose manifest did not arrive in time, without verifying them
+    pub(crate) fn check_chunk_manifests(&self) {
+        let released = self.chunk_verifier.write().take_expired(MANIFEST_TIMEOUT);
+
+        let mut videos = HashSet::new();
+        for verification in released {
+            if let ChunkVerification::Unverified(content) = &verification {
+                if videos.insert(content.file_hash) {
+                    self.state.read().logger.log_warn(&format!(
+                        "[{}, {}] no manifest received for video {}, playing its chunks unverified",
+                        file!(),
+                        line!(),
+                        content.file_hash
+                    ));
+                }
             }
+            self.handle_chunk_verification(verification);
         }
+
+        // Without manifest the seek index of the videos is unknown
+        for video_id in videos {
+            self.reject_seek(video_id);
+        }
+    }
+
+    pub(crate) fn handle_chunk_res(&self, content: ChunkResponse) {
+        // Ignore chunks of videos no longer downloading
+        let is_downloading = self
+            .playback_sessions
+            .read()
+            .downloading(content.file_hash)
+            .is_some();
+        if !is_downloading {
+            return;
+        }
+
+        if content.chunk_index == MANIFEST_CHUNK_INDEX {
+            self.handle_chunk_manifest(&content);
+            return;
+        }
+
+        let verification = self.chunk_verifier.write().verify(content);
+        self.handle_chunk_verification(verification);
     }
 }
@@ -1,6 +1,9 @@
 use packet_forge::{FileMetadata, ResponseFileList, VideoMetaData};
 
-use crate::{client::FsmStatus, ClientVideo};
+use crate::{
+    client::{utils::pending_requests::RequestKind, FsmStatus},
+    ClientVideo,
+};
 
 impl ClientVideo {
     pub(crate) fn handle_response_file_list(&self, content: &ResponseFileList) {
@@ -9,6 +12,13 @@ impl ClientVideo {
             self.state.write().fsm = FsmStatus::SubscribedToServer;
         }
 
+        // A file list answers both the subscription and the list request
+        {
+            let pending_requests = &mut self.state.write().pending_requests;
+            pending_requests.complete(RequestKind::SubscribeClient, content.server_id);
+            pending_requests.complete(RequestKind::FileList, content.server_id);
+        }
+
         // Convert FileMetadata to VideoMetaData
         let video_list: Vec<VideoMetaData> = content
             .file_list
@@ -22,12 +32,18 @@ impl ClientVideo {
             })
             .collect();
 
-        // Add video ids to the server id map
+        // Add video ids to the server id map and keep their metadata
         let video_ids: Vec<u16> = video_list.iter().map(|video| video.id).collect();
-        self.state
-            .write()
-            .servers
-            .insert(content.server_id, video_ids);
+        {
+            let mut state_guard = self.state.write();
+            state_guard.servers.insert(content.server_id, video_ids);
+            state_guard
+                .catalog
+                .update_server(content.server_id, &video_list);
+            for video in &video_list {
+                state_guard.videos_metadata.insert(video.id, video.clone());
+            }
+        }
 
         // Send video metadata to event stream
         if let Some(sender) = &self.file_list_sender.read().clone() {
@@ -1,30 +1,43 @@
-use packet_forge::{ChunkRequest, FileHash, Index, MessageType, ResponsePeerList};
+use packet_forge::ResponsePeerList;
 use wg_internal::network::NodeId;
 
-use crate::{client::utils::sends::send_msg, ClientVideo};
+use crate::{client::utils::pending_requests::RequestKind, ClientVideo};
 
 impl ClientVideo {
-    fn request_video_from_network(&self, video_id: FileHash, dest_id: NodeId) {
-        // Create ChunkRequest
-        let msg = MessageType::ChunkRequest(ChunkRequest::new(self.get_id(), video_id, Index::All));
+    pub(crate) fn handle_peer_list_res(&self, content: &ResponsePeerList) {
+        let answered = self
+            .state
+            .write()
+            .pending_requests
+            .complete_kind(RequestKind::PeerList(content.file_hash));
 
-        // Send message
-        let res = send_msg(&self.state, dest_id, msg);
-        if let Err(err) = res {
-            self.state.read().logger.log_error(&err);
-        }
-    }
+        let id = self.get_id();
+        let peers: Vec<NodeId> = content
+            .peers
+            .iter()
+            .map(|peer| peer.client_id)
+            .filter(|peer_id| *peer_id != id)
+            .collect();
 
-    pub(crate) fn handle_peer_list_res(&self, content: &ResponsePeerList) {
-        if content.peers.is_empty() {
+        if peers.is_empty() {
             self.state.read().logger.log_warn(&format!(
                 "[{}, {}] peer list is empty",
                 file!(),
                 line!()
             ));
+
+            // Ask the next server, unless the list answers a request already failed over
+            if !answered.is_empty() {
+                self.send_req_peer_list_to_next(content.file_hash);
+            }
             return;
         }
 
-        self.request_video_from_network(content.file_hash, content.peers[0].client_id);
+        self.state
+            .write()
+            .peer_list_servers
+            .remove(&content.file_hash);
+
+        self.start_swarm_download(content.file_hash, &peers);
     }
 }
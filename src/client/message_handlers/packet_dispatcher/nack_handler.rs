@@ -1,20 +1,13 @@
-use std::time::{Duration, Instant};
-
 use packet_forge::SessionIdT;
-use parking_lot::Mutex;
 use wg_internal::packet::{Nack, NackType, Packet};
 
 use crate::client::{
-    utils::{sends::send_packet, start_flooding::init_flood_request},
+    utils::{flood_scheduler::FloodReason, sends::send_packet},
     ClientVideo, StateT,
 };
 
-lazy_static::lazy_static! {
-    static ref LAST_EXECUTION: Mutex<Instant> = Mutex::new(Instant::now().checked_sub(Duration::from_secs(5)).unwrap());
-}
-
 impl ClientVideo {
-    fn retransmit_packet(state: &StateT, mut packet: Packet) {
+    pub(crate) fn retransmit_packet(state: &StateT, mut packet: Packet) {
         let dest = packet.routing_header.hops[packet.routing_header.hops.len() - 1];
 
         // Retrieve new best path from server to client otherwise return
@@ -71,13 +64,22 @@ impl ClientVideo {
             return;
         };
 
+        // Track peers that keep failing during swarm downloads
+        if let Some(dest) = packet.routing_header.hops.last() {
+            self.swarm_peer_nacked(*dest);
+        }
+
         match nack.nack_type {
             NackType::Dropped => {
-                // Update the routing handler
+                // Update the routing handler and the sending window
                 self.state
                     .write()
                     .routing_handler
                     .node_nack(packet.routing_header.hops[0]);
+                self.state
+                    .write()
+                    .send_windows
+                    .on_dropped((nack.fragment_index, session_id));
 
                 Self::retransmit_packet(state, packet);
             }
@@ -89,18 +91,8 @@ impl ClientVideo {
                     id
                 ));
 
-                // Send flood request after a certain time window
-                let mut last_exec = LAST_EXECUTION.lock();
-                let now = Instant::now();
-                if now.duration_since(*last_exec) > Duration::from_secs(5) {
-                    *last_exec = now;
-                    init_flood_request(state);
-                } else {
-                    state
-                        .read()
-                        .logger
-                        .log_error("Skipping flood request to avoid overloading.");
-                }
+                // Rediscover the topology, merged with the other recent flood signals
+                self.flood_scheduler.flood_soon(FloodReason::ErrorInRouting);
             }
             NackType::DestinationIsDrone => {
                 state.read().logger.log_error(&format!(
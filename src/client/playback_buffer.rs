@@ -0,0 +1,292 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+pub(crate) const PLAYBACK_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+const STATUS_CHANNEL_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) enum PlaybackState {
+    Buffering,   // Waiting for enough data to play without stalling
+    Playing,     // Chunks are forwarded to the frontend as they arrive
+    Completed,   // Every chunk has been forwarded
+    Failed,      // The video cannot be downloaded from any peer
+    NotSeekable, // The video has no seek index to start from the requested time
+}
+
+/// Playback state sent to the frontend
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PlaybackStatus {
+    pub state: PlaybackState,
+    pub download_rate: f64,   // Bytes per second
+    pub bitrate: Option<f64>, // Bytes per second of the video
+}
+
+/// Jitter buffer deciding when the received chunks can be released to the frontend
+pub(crate) struct PlaybackBuffer {
+    state: PlaybackState,
+    duration: Option<f64>, // Video duration in seconds
+    preroll: Duration,     // Video buffered before starting playback
+    preroll_chunks: usize, // Chunks buffered before starting playback if the bitrate is unknown
+    download_started: Instant,
+    received_bytes: usize,
+    video_bytes: Option<usize>, // Size of the whole video, gives the bitrate
+    skipped_bytes: usize,       // Bytes not received because of the start time
+    play_started: Option<Instant>, // Start of the current playing period
+    played_offset: f64,         // Bytes played before the current playing period
+    status_sender: broadcast::Sender<PlaybackStatus>,
+}
+
+impl PlaybackBuffer {
+    pub fn new(duration: Option<f64>, preroll: Duration, preroll_chunks: usize) -> Self {
+        let (status_sender, _) = broadcast::channel(STATUS_CHANNEL_CAPACITY);
+        Self {
+            state: PlaybackState::Buffering,
+            duration: duration.filter(|d| *d > 0.0),
+            preroll,
+            preroll_chunks,
+            download_started: Instant::now(),
+            received_bytes: 0,
+            video_bytes: None,
+            skipped_bytes: 0,
+            play_started: None,
+            played_offset: 0.0,
+            status_sender,
+        }
+    }
+
+    /// Creates a buffer for a video already available
+    pub fn completed() -> Self {
+        let mut buffer = Self::new(None, Duration::ZERO, 0);
+        buffer.state = PlaybackState::Completed;
+        buffer
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.state
+    }
+
+    /// Records `bytes` of video received, with the size of the video
+    /// and the bytes skipped to reach the start time
+    pub fn on_received(&mut self, bytes: usize, video_bytes: usize, skipped_bytes: usize) {
+        self.received_bytes += bytes;
+        self.video_bytes = Some(video_bytes);
+        self.skipped_bytes = skipped_bytes;
+    }
+
+    fn download_rate(&self) -> f64 {
+        let elapsed = self.download_started.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        self.received_bytes as f64 / elapsed
+    }
+
+    fn bitrate(&self) -> Option<f64> {
+        Some(self.video_bytes? as f64 / self.duration?)
+    }
+
+    /// Bytes the frontend is estimated to have played
+    fn played_bytes(&self, bitrate: f64) -> f64 {
+        let playing = self
+            .play_started
+            .map_or(0.0, |start| start.elapsed().as_secs_f64());
+        self.played_offset + playing * bitrate
+    }
+
+    /// Whether enough data is buffered to play until the end of the download without stalling
+    fn ready_to_play(&self, available_bytes: usize, held_chunks: usize) -> bool {
+        let Some(bitrate) = self.bitrate() else {
+            return held_chunks >= self.preroll_chunks;
+        };
+
+        let ahead_secs = (available_bytes as f64 - self.played_offset) / bitrate;
+        let remaining_bytes =
+            self.video_bytes
+                .unwrap_or(0)
+                .saturating_sub(self.skipped_bytes + available_bytes) as f64;
+
+        // While downloading, playback consumes `bitrate` and the buffer fills at `rate`
+        let rate = self.download_rate();
+        let required_secs = if rate >= bitrate {
+            0.0
+        } else if rate == 0.0 {
+            f64::INFINITY
+        } else {
+            remaining_bytes / bitrate * (bitrate / rate - 1.0)
+        };
+
+        ahead_secs >= required_secs.max(self.preroll.as_secs_f64())
+    }
+
+    fn set_state(&mut self, state: PlaybackState) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        let _ = self.status_sender.send(self.status());
+    }
+
+    /// Updates the playback state, returns `true` if the held chunks can be released
+    pub fn update(
+        &mut self,
+        released_bytes: usize,
+        held_bytes: usize,
+        held_chunks: usize,
+        download_completed: bool,
+    ) -> bool {
+        match self.state {
+            PlaybackState::Completed => true,
+            PlaybackState::Failed | PlaybackState::NotSeekable => false,
+            PlaybackState::Buffering => {
+                if download_completed
+                    || self.ready_to_play(released_bytes + held_bytes, held_chunks)
+                {
+                    self.play_started = Some(Instant::now());
+                    self.set_state(PlaybackState::Playing);
+                    return true;
+                }
+                false
+            }
+            PlaybackState::Playing => {
+                // The frontend played everything it received, wait for more data
+                let stalled = self
+                    .bitrate()
+                    .is_some_and(|bitrate| (released_bytes as f64) <= self.played_bytes(bitrate));
+                if stalled && !download_completed {
+                    self.played_offset = released_bytes as f64;
+                    self.play_started = None;
+                    self.set_state(PlaybackState::Buffering);
+                    return false;
+                }
+                true
+            }
+        }
+    }
+
+    /// Marks every chunk as forwarded
+    pub fn complete(&mut self) {
+        self.set_state(PlaybackState::Completed);
+    }
+
+    /// Marks the download as failed, no other chunk will be forwarded
+    pub fn fail(&mut self) {
+        self.set_state(PlaybackState::Failed);
+    }
+
+    /// Marks the requested start time as not reachable, no chunk will be forwarded
+    pub fn reject_seek(&mut self) {
+        self.set_state(PlaybackState::NotSeekable);
+    }
+
+    pub fn status(&self) -> PlaybackStatus {
+        PlaybackStatus {
+            state: self.state,
+            download_rate: self.download_rate(),
+            bitrate: self.bitrate(),
+        }
+    }
+
+    pub fn subscribe(&self) -> (PlaybackStatus, broadcast::Receiver<PlaybackStatus>) {
+        (self.status(), self.status_sender.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREROLL: Duration = Duration::from_secs(2);
+
+    /// Buffer of a 10s video of 1000 bytes, played at 100 bytes per second
+    fn video_buffer() -> PlaybackBuffer {
+        let mut buffer = PlaybackBuffer::new(Some(10.0), PREROLL, 3);
+        buffer.on_received(0, 1000, 0);
+        buffer
+    }
+
+    #[test]
+    fn bitrate_comes_from_the_video_size() {
+        let mut buffer = video_buffer();
+        assert_eq!(buffer.bitrate(), Some(100.0));
+
+        // The skipped bytes do not change the bitrate of the video
+        buffer.on_received(0, 1000, 500);
+        assert_eq!(buffer.bitrate(), Some(100.0));
+        assert_eq!(PlaybackBuffer::new(None, PREROLL, 3).bitrate(), None);
+    }
+
+    #[test]
+    fn unknown_bitrate_waits_for_the_preroll_chunks() {
+        let buffer = PlaybackBuffer::new(None, PREROLL, 3);
+        assert!(!buffer.ready_to_play(10_000, 2));
+        assert!(buffer.ready_to_play(0, 3));
+    }
+
+    #[test]
+    fn fast_download_waits_for_the_preroll() {
+        let mut buffer = video_buffer();
+        buffer.on_received(300, 1000, 0);
+        assert!(!buffer.ready_to_play(150, 1));
+        assert!(buffer.ready_to_play(200, 1));
+    }
+
+    #[test]
+    fn stopped_download_is_never_ready() {
+        let buffer = video_buffer();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!buffer.ready_to_play(900, 10));
+    }
+
+    #[test]
+    fn buffering_plays_once_ready_or_downloaded() {
+        let mut buffer = video_buffer();
+        buffer.on_received(300, 1000, 0);
+        assert!(!buffer.update(0, 100, 1, false));
+        assert_eq!(buffer.state(), PlaybackState::Buffering);
+        assert!(buffer.update(0, 300, 1, false));
+        assert_eq!(buffer.state(), PlaybackState::Playing);
+
+        let mut buffer = video_buffer();
+        assert!(buffer.update(0, 10, 1, true));
+        assert_eq!(buffer.state(), PlaybackState::Playing);
+    }
+
+    #[test]
+    fn stalled_playback_buffers_again() {
+        let mut buffer = video_buffer();
+        buffer.on_received(300, 1000, 0);
+        assert!(buffer.update(0, 300, 1, false));
+
+        // Nothing released yet, the frontend played everything it received
+        assert!(!buffer.update(0, 0, 0, false));
+        assert_eq!(buffer.state(), PlaybackState::Buffering);
+        assert!(buffer.update(0, 300, 1, false));
+        assert!(buffer.update(300, 0, 0, false));
+        assert_eq!(buffer.state(), PlaybackState::Playing);
+    }
+
+    #[test]
+    fn final_states_are_reported_once() {
+        let mut buffer = video_buffer();
+        let (status, mut receiver) = buffer.subscribe();
+        assert_eq!(status.state, PlaybackState::Buffering);
+
+        buffer.fail();
+        buffer.fail();
+        assert!(!buffer.update(0, 1000, 10, true));
+        assert_eq!(receiver.try_recv().unwrap().state, PlaybackState::Failed);
+        assert!(receiver.try_recv().is_err());
+
+        let mut buffer = PlaybackBuffer::new(Some(10.0), PREROLL, 3);
+        buffer.reject_seek();
+        assert_eq!(buffer.state(), PlaybackState::NotSeekable);
+        assert!(!buffer.update(0, 1000, 10, true));
+
+        let mut buffer = PlaybackBuffer::completed();
+        assert!(buffer.update(0, 0, 0, false));
+        buffer.complete();
+        assert_eq!(buffer.state(), PlaybackState::Completed);
+    }
+}
@@ -0,0 +1,553 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use packet_forge::FileHash;
+use tokio::sync::broadcast;
+
+use super::{
+    config::ClientConfig,
+    playback_buffer::{PlaybackBuffer, PlaybackState, PlaybackStatus},
+    video_chunker::CHUNK_SIZE,
+    ClientVideo,
+};
+use crate::db::mp4::SeekIndex;
+
+pub(crate) type PlaybackId = u64;
+
+const MAX_COMPLETED_SESSIONS: usize = 8; // Completed sessions kept for late subscribers
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60); // Without chunks or subscribers
+
+/// State of a single video playback requested by the frontend
+pub(crate) struct PlaybackSession {
+    pub video_id: FileHash,
+    chunks: Vec<Bytes>,                       // In-order chunks received
+    released: usize,                          // Chunks already forwarded to the frontend
+    chunk_buffer: BTreeMap<u32, Bytes>,       // Store out-of-order chunks
+    next_expected_index: u32,                 // Track next expected chunk
+    total_n_chunks: Option<u32>,              // Known once the first chunk arrives
+    video_size: Option<usize>,                // Bytes of the whole video, known from the manifest
+    sender: Option<broadcast::Sender<Bytes>>, // Frontend sender, dropped once completed
+    buffer: PlaybackBuffer,                   // Decides when chunks are forwarded
+    stored: bool,                             // Chunks are read from the db when streamed
+    seek_time: Option<f64>,                   // Requested start time in seconds
+    skipped: Option<Range<u32>>,              // Chunks between the init segment and the start time
+    last_chunk: Instant,                      // Last chunk received, or creation
+    last_watched: Instant,                    // Last time the frontend was subscribed, or creation
+}
+
+impl PlaybackSession {
+    /// Creates a session waiting for chunks from the network.
+    /// `duration` is the video length in seconds, used to estimate its bitrate.
+    /// `seek_time` is applied once the seek index of the video is known.
+    fn new(
+        video_id: FileHash,
+        duration: Option<f64>,
+        seek_time: Option<f64>,
+        config: &SessionConfig,
+    ) -> Self {
+        let (sender, _) = broadcast::channel::<Bytes>(config.channel_capacity);
+        Self {
+            video_id,
+            chunks: Vec::new(),
+            released: 0,
+            chunk_buffer: BTreeMap::new(),
+            next_expected_index: 0,
+            total_n_chunks: None,
+            video_size: None,
+            sender: Some(sender),
+            buffer: PlaybackBuffer::new(duration, config.preroll, config.preroll_chunks),
+            stored: false,
+            seek_time: seek_time.filter(|t| *t > 0.0),
+            skipped: None,
+            last_chunk: Instant::now(),
+            last_watched: Instant::now(),
+        }
+    }
+
+    /// Creates a session continuing the download of `other`
+    fn resume_from(other: &PlaybackSession, duration: Option<f64>, config: &SessionConfig) -> Self {
+        let mut session = Self::new(other.video_id, duration, None, config);
+        session.chunks.clone_from(&other.chunks);
+        session.chunk_buffer.clone_from(&other.chunk_buffer);
+        session.next_expected_index = other.next_expected_index;
+        session.total_n_chunks = other.total_n_chunks;
+        session.video_size = other.video_size;
+        session
+    }
+
+    /// Creates an already completed session for a video stored in the db.
+    /// Its chunks are not kept in memory.
+    fn stored(video_id: FileHash, total_n_chunks: u32, skipped: Option<Range<u32>>) -> Self {
+        Self {
+            video_id,
+            chunks: Vec::new(),
+            released: 0,
+            chunk_buffer: BTreeMap::new(),
+            next_expected_index: total_n_chunks,
+            total_n_chunks: Some(total_n_chunks),
+            video_size: None,
+            sender: None,
+            buffer: PlaybackBuffer::completed(),
+            stored: true,
+            seek_time: None,
+            skipped,
+            last_chunk: Instant::now(),
+            last_watched: Instant::now(),
+        }
+    }
+
+    /// Whether the chunks must be read from the db instead of the session
+    pub fn is_stored(&self) -> bool {
+        self.stored
+    }
+
+    /// Chunks not played because of the requested start time
+    pub fn skipped(&self) -> Option<Range<u32>> {
+        self.skipped.clone()
+    }
+
+    /// Whether the session receives every chunk of the video
+    pub fn has_full_payload(&self) -> bool {
+        self.skipped.is_none()
+    }
+
+    /// Skips the chunks before the requested start time, if no media segment was received yet
+    fn apply_seek(&mut self, seek_index: &SeekIndex) {
+        let Some(seek_time) = self.seek_time.take() else {
+            return;
+        };
+        let Some(skipped) = seek_index.skipped_chunks(seek_time) else {
+            return;
+        };
+        if self.next_expected_index > skipped.start {
+            return;
+        }
+
+        self.chunk_buffer
+            .retain(|index, _| !skipped.contains(index));
+        if self.next_expected_index == skipped.start {
+            self.next_expected_index = skipped.end;
+        }
+        self.skipped = Some(skipped);
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.total_n_chunks
+            .is_some_and(|total| self.next_expected_index >= total)
+    }
+
+    /// Whether the download stopped receiving chunks, or the frontend stopped
+    /// watching the session, for longer than `timeout`
+    fn is_idle(&mut self, timeout: Duration) -> bool {
+        if self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| sender.receiver_count() > 0)
+        {
+            self.last_watched = Instant::now();
+        }
+
+        let stalled = !self.is_completed() && self.last_chunk.elapsed() > timeout;
+        stalled || self.last_watched.elapsed() > timeout
+    }
+
+    fn forward(&mut self, data: Bytes) {
+        // Without the manifest, or for the skipped chunks, every chunk is estimated full
+        let n_chunks = self.total_n_chunks.unwrap_or(0) as usize;
+        let video_size = self.video_size.unwrap_or(n_chunks * CHUNK_SIZE);
+        let n_skipped = self.skipped.as_ref().map_or(0, |skipped| skipped.len());
+        self.buffer
+            .on_received(data.len(), video_size, n_skipped * CHUNK_SIZE);
+
+        self.chunks.push(data);
+        self.next_expected_index += 1;
+
+        // Jump from the init segment to the requested start time
+        if let Some(skipped) = &self.skipped {
+            if self.next_expected_index == skipped.start {
+                self.next_expected_index = skipped.end;
+            }
+        }
+    }
+
+    /// Sends the held chunks to the frontend if the playback buffer allows it
+    fn update_playback(&mut self) {
+        if self.buffer.state() == PlaybackState::Completed {
+            return;
+        }
+
+        let held = &self.chunks[self.released..];
+        let held_bytes = held.iter().map(Bytes::len).sum();
+        let released_bytes = self.payload_len() - held_bytes;
+        let completed = self.is_completed();
+
+        if !self
+            .buffer
+            .update(released_bytes, held_bytes, held.len(), completed)
+        {
+            return;
+        }
+
+        if let Some(sender) = &self.sender {
+            for chunk in &self.chunks[self.released..] {
+                let _ = sender.send(chunk.clone());
+            }
+        }
+        self.released = self.chunks.len();
+
+        if completed {
+            // Close the frontend stream
+            self.sender = None;
+            self.buffer.complete();
+        }
+    }
+
+    /// Stops the session, closing the frontend stream
+    fn fail(&mut self) {
+        self.sender = None;
+        self.buffer.fail();
+    }
+
+    /// Stops the session if it still waits to start from a time, closing the frontend stream.
+    /// Returns `true` if the session was stopped.
+    fn reject_seek(&mut self) -> bool {
+        if self.seek_time.take().is_none() {
+            return false;
+        }
+        self.sender = None;
+        self.buffer.reject_seek();
+        true
+    }
+
+    /// Adds a chunk to the session, forwarding every chunk that is now in order
+    /// once the playback buffer allows it.
+    /// Returns `true` if this chunk completed the video.
+    pub fn push_chunk(&mut self, chunk_index: u32, total_n_chunks: u32, data: Bytes) -> bool {
+        let is_skipped = self
+            .skipped
+            .as_ref()
+            .is_some_and(|skipped| skipped.contains(&chunk_index));
+        if self.is_completed() || chunk_index < self.next_expected_index || is_skipped {
+            // Duplicate chunk (or old, or before the start time), ignore it
+            return false;
+        }
+        self.total_n_chunks = Some(total_n_chunks);
+        self.last_chunk = Instant::now();
+
+        if chunk_index > self.next_expected_index {
+            self.chunk_buffer.insert(chunk_index, data);
+            return false;
+        }
+
+        self.forward(data);
+        while let Some(data) = self.chunk_buffer.remove(&self.next_expected_index) {
+            self.forward(data);
+        }
+
+        self.update_playback();
+        self.is_completed()
+    }
+
+    /// Returns the indexes of the chunks not received yet,
+    /// `None` if the total number of chunks is still unknown
+    pub fn missing_chunks(&self) -> Option<Vec<u32>> {
+        let total = self.total_n_chunks?;
+        Some(
+            (self.next_expected_index..total)
+                .filter(|index| !self.chunk_buffer.contains_key(index))
+                .filter(|index| {
+                    !self
+                        .skipped
+                        .as_ref()
+                        .is_some_and(|skipped| skipped.contains(index))
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the chunks forwarded so far and a receiver for the following ones
+    pub fn subscribe(&self) -> (Vec<Bytes>, Option<broadcast::Receiver<Bytes>>) {
+        (
+            self.chunks[..self.released].to_vec(),
+            self.sender.as_ref().map(broadcast::Sender::subscribe),
+        )
+    }
+
+    /// Returns the current playback status and a receiver for its changes
+    pub fn subscribe_status(&self) -> (PlaybackStatus, broadcast::Receiver<PlaybackStatus>) {
+        self.buffer.subscribe()
+    }
+
+    /// Returns the in-order payload received so far
+    pub fn payload(&self) -> Vec<u8> {
+        self.chunks.concat()
+    }
+
+    /// Number of in-order bytes received so far
+    pub fn payload_len(&self) -> usize {
+        self.chunks.iter().map(Bytes::len).sum()
+    }
+
+    /// Copies the bytes in `start..=end` that are already available.
+    /// Returns `None` if `start` has not been received yet.
+    pub fn read_bytes(&self, start: usize, end: Option<usize>) -> Option<Vec<u8>> {
+        let len = self.payload_len();
+        if start >= len {
+            return None;
+        }
+        let end = end.map_or(len - 1, |end| end.min(len - 1));
+
+        let mut data = Vec::with_capacity(end - start + 1);
+        let mut offset = 0;
+        for chunk in &self.chunks {
+            let chunk_end = offset + chunk.len();
+            if chunk_end > start && offset <= end {
+                let from = start.saturating_sub(offset);
+                let to = (end + 1 - offset).min(chunk.len());
+                data.extend_from_slice(&chunk[from..to]);
+            }
+            if chunk_end > end {
+                break;
+            }
+            offset = chunk_end;
+        }
+        Some(data)
+    }
+}
+
+/// Settings shared by the sessions downloading from the network
+struct SessionConfig {
+    channel_capacity: usize, // Chunks buffered for the frontend
+    preroll: Duration,       // Video buffered before starting playback
+    preroll_chunks: usize,   // Chunks buffered before starting playback if the bitrate is unknown
+}
+
+/// All the playback sessions of the client
+pub(crate) struct PlaybackSessions {
+    next_id: PlaybackId,
+    sessions: HashMap<PlaybackId, PlaybackSession>,
+    config: SessionConfig,
+}
+
+impl PlaybackSessions {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            next_id: 0,
+            sessions: HashMap::new(),
+            config: SessionConfig {
+                channel_capacity: config.video_channel_capacity,
+                preroll: config.preroll(),
+                preroll_chunks: config.preroll_chunks,
+            },
+        }
+    }
+
+    fn insert(&mut self, session: PlaybackSession) -> PlaybackId {
+        self.evict_completed();
+
+        self.next_id += 1;
+        self.sessions.insert(self.next_id, session);
+        self.next_id
+    }
+
+    /// Removes the oldest completed sessions exceeding `MAX_COMPLETED_SESSIONS`
+    fn evict_completed(&mut self) {
+        let mut completed: Vec<PlaybackId> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_completed())
+            .map(|(id, _)| *id)
+            .collect();
+
+        if completed.len() < MAX_COMPLETED_SESSIONS {
+            return;
+        }
+
+        completed.sort_unstable();
+        let n_to_remove = completed.len() + 1 - MAX_COMPLETED_SESSIONS;
+        for id in completed.into_iter().take(n_to_remove) {
+            self.sessions.remove(&id);
+        }
+    }
+
+    /// Removes the sessions that are abandoned by the frontend or stopped downloading,
+    /// returning the videos no session downloads anymore
+    pub fn evict_idle(&mut self) -> Vec<FileHash> {
+        let idle: Vec<PlaybackId> = self
+            .sessions
+            .iter_mut()
+            .filter_map(|(id, session)| session.is_idle(SESSION_IDLE_TIMEOUT).then_some(*id))
+            .collect();
+
+        let mut abandoned = Vec::new();
+        for id in idle {
+            let Some(session) = self.sessions.remove(&id) else {
+                continue;
+            };
+            if !session.is_completed() && self.downloading(session.video_id).is_none() {
+                abandoned.push(session.video_id);
+            }
+        }
+        abandoned.sort_unstable();
+        abandoned.dedup();
+        abandoned
+    }
+
+    /// Fails and removes the sessions downloading `video_id`
+    pub fn fail(&mut self, video_id: FileHash) {
+        self.sessions.retain(|_, session| {
+            if session.video_id != video_id || session.is_completed() {
+                return true;
+            }
+            session.fail();
+            false
+        });
+    }
+
+    /// Stops and removes the sessions of `video_id` waiting to start from a time,
+    /// returning `true` if they were the last ones downloading the video
+    pub fn reject_seek(&mut self, video_id: FileHash) -> bool {
+        let n_sessions = self.sessions.len();
+        self.sessions.retain(|_, session| {
+            session.video_id != video_id || session.is_completed() || !session.reject_seek()
+        });
+        self.sessions.len() < n_sessions && self.downloading(video_id).is_none()
+    }
+
+    /// Creates a session waiting for chunks from the network, starting at `seek_time` if set.
+    /// If `video_id` is already being downloaded from the beginning, the new session
+    /// starts from the chunks received so far.
+    pub fn create(
+        &mut self,
+        video_id: FileHash,
+        duration: Option<f64>,
+        seek_time: Option<f64>,
+    ) -> PlaybackId {
+        let session = match self.downloading_full(video_id) {
+            Some(other) if seek_time.is_none() => {
+                PlaybackSession::resume_from(other, duration, &self.config)
+            }
+            _ => PlaybackSession::new(video_id, duration, seek_time, &self.config),
+        };
+        self.insert(session)
+    }
+
+    /// Creates an already completed session for a video stored in the db,
+    /// not playing the `skipped` chunks
+    pub fn create_stored(
+        &mut self,
+        video_id: FileHash,
+        total_n_chunks: u32,
+        skipped: Option<Range<u32>>,
+    ) -> PlaybackId {
+        self.insert(PlaybackSession::stored(video_id, total_n_chunks, skipped))
+    }
+
+    /// Sets the size of `video_id` received with its manifest
+    pub fn apply_video_size(&mut self, video_id: FileHash, size: u64) {
+        for session in self.downloading_mut(video_id) {
+            session.video_size = usize::try_from(size).ok();
+        }
+    }
+
+    /// Applies the seek index of `video_id` to the sessions that requested a start time
+    pub fn apply_seek_index(&mut self, video_id: FileHash, seek_index: &SeekIndex) {
+        for session in self.downloading_mut(video_id) {
+            session.apply_seek(seek_index);
+        }
+    }
+
+    /// Updates the playback of every session, detecting stalls while no chunk arrives
+    pub fn update_playback(&mut self) {
+        for session in self.sessions.values_mut() {
+            session.update_playback();
+        }
+    }
+
+    pub fn get(&self, id: PlaybackId) -> Option<&PlaybackSession> {
+        self.sessions.get(&id)
+    }
+
+    /// Returns the sessions still downloading `video_id`
+    pub fn downloading_mut(
+        &mut self,
+        video_id: FileHash,
+    ) -> impl Iterator<Item = &mut PlaybackSession> {
+        self.sessions
+            .values_mut()
+            .filter(move |session| session.video_id == video_id && !session.is_completed())
+    }
+
+    /// Returns the sessions still downloading `video_id`
+    pub fn downloading_all(&self, video_id: FileHash) -> impl Iterator<Item = &PlaybackSession> {
+        self.sessions
+            .values()
+            .filter(move |session| session.video_id == video_id && !session.is_completed())
+    }
+
+    /// Returns a session still downloading `video_id`, if any
+    pub fn downloading(&self, video_id: FileHash) -> Option<&PlaybackSession> {
+        self.downloading_all(video_id).next()
+    }
+
+    /// Returns a session still downloading every chunk of `video_id`, if any
+    pub fn downloading_full(&self, video_id: FileHash) -> Option<&PlaybackSession> {
+        self.downloading_all(video_id)
+            .find(|session| session.has_full_payload())
+    }
+
+    /// Returns the indexes of the chunks of `video_id` still needed by any session,
+    /// `None` if the whole video is needed
+    pub fn missing_chunks(&self, video_id: FileHash) -> Option<Vec<u32>> {
+        let mut missing = BTreeSet::new();
+        for session in self.downloading_all(video_id) {
+            missing.extend(session.missing_chunks()?);
+        }
+
+        if missing.is_empty() {
+            return None;
+        }
+        Some(missing.into_iter().collect())
+    }
+}
+
+impl ClientVideo {
+    /// Updates the playback of every session and stops downloading the videos
+    /// whose sessions were all freed because idle
+    pub(crate) fn check_playback_sessions(&self) {
+        let abandoned = {
+            let mut sessions = self.playback_sessions.write();
+            sessions.update_playback();
+            sessions.evict_idle()
+        };
+
+        for video_id in abandoned {
+            self.state.read().logger.log_warn(&format!(
+                "[{}, {}] playback of video {video_id} abandoned, download stopped",
+                file!(),
+                line!()
+            ));
+            self.stop_download(video_id);
+        }
+    }
+
+    /// Stops the sessions that requested a start time of a video without seek index
+    pub(crate) fn reject_seek(&self, video_id: FileHash) {
+        let abandoned = self.playback_sessions.write().reject_seek(video_id);
+        if abandoned {
+            self.state.read().logger.log_warn(&format!(
+                "[{}, {}] video {video_id} cannot start from the requested time, download stopped",
+                file!(),
+                line!()
+            ));
+            self.stop_download(video_id);
+        }
+    }
+
+    fn stop_download(&self, video_id: FileHash) {
+        self.swarm_downloads.write().remove(&video_id);
+        self.chunk_verifier.write().remove(video_id);
+    }
+}
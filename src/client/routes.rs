@@ -1,26 +1,61 @@
 use std::time::Duration;
 
 use base64::{engine::general_purpose, Engine};
-use bytes::Bytes;
 use packet_forge::FileHash;
 use rocket::{
-    response::stream::{Event, EventStream},
+    http::Status,
+    response::{
+        status::Custom,
+        stream::{Event, EventStream},
+    },
     State,
 };
 use tokio::{sync::broadcast, time::interval};
 
-use crate::client::VideoListSenderT;
+use crate::{
+    client::VideoListSenderT,
+    db::search::{VideoQuery, VideoSort},
+};
+
+use super::{
+    playback_buffer::PlaybackState,
+    playback_sessions::{PlaybackId, PlaybackSession},
+    utils::{flood_scheduler::FloodReason, shutdown::ShutdownToken},
+    video_range::{RangeHeader, VideoRange},
+    ClientVideo,
+};
 
-use super::{utils::start_flooding::init_flood_request, ClientVideo};
+/// Last event of every stream closed because the client shuts down
+fn shutdown_event() -> Event {
+    Event::data("shutdown").event("shutdown")
+}
+
+/// Waits for the next message of `receiver`, `None` once it is closed or the client shuts down
+async fn recv_until_shutdown<T: Clone>(
+    receiver: &mut broadcast::Receiver<T>,
+    shutdown: &ShutdownToken,
+) -> Option<T> {
+    tokio::select! {
+        msg = receiver.recv() => msg.ok(),
+        () = shutdown.cancelled() => None,
+    }
+}
 
 #[get("/get-id")]
 pub(crate) fn get_id(client: &State<ClientVideo>) -> String {
     client.get_id().to_string()
 }
 
-#[get("/req-video/<video_id>")]
-pub(crate) fn request_video(client: &State<ClientVideo>, video_id: FileHash) {
-    client.request_video(video_id);
+#[get("/req-video/<video_id>?<t>")]
+pub(crate) fn request_video(
+    client: &State<ClientVideo>,
+    video_id: FileHash,
+    t: Option<f64>,
+) -> Result<String, Custom<String>> {
+    client
+        .request_video(video_id, t)
+        .map(|session_id| session_id.to_string())
+        .map_err(|err| Custom(Status::UnprocessableEntity, err))
 }
 
 #[get("/req-video-list-from-db")]
@@ -36,6 +71,64 @@ pub(crate) fn request_video_list_from_db(client: &State<ClientVideo>) -> EventSt
     }
 }
 
+/// Videos per page when the query sets no limit
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// Query string of `/videos`, every field is optional
+#[derive(FromForm)]
+pub(crate) struct SearchParams<'r> {
+    title: Option<String>,
+    mime_type: Option<String>,
+    min_duration: Option<u64>, // Seconds
+    max_duration: Option<u64>, // Seconds
+    sort: Option<&'r str>,     // `title`, `duration`, `created_at` or `id`
+    order: Option<&'r str>,    // `asc` or `desc`
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// Searches the local videos, returning a JSON `VideoPage`
+#[get("/videos?<params..>")]
+pub(crate) fn search_videos(
+    client: &State<ClientVideo>,
+    params: SearchParams<'_>,
+) -> Result<String, Custom<String>> {
+    let bad_request = |err: String| Custom(Status::BadRequest, err);
+    let sort = params
+        .sort
+        .map(str::parse::<VideoSort>)
+        .transpose()
+        .map_err(bad_request)?
+        .unwrap_or_default();
+    let descending = match params.order {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(order) => return Err(bad_request(format!("Invalid order '{order}'"))),
+    };
+
+    let query = VideoQuery {
+        title: params.title.filter(|title| !title.trim().is_empty()),
+        mime_type: params
+            .mime_type
+            .filter(|mime_type| !mime_type.trim().is_empty()),
+        min_duration: params.min_duration,
+        max_duration: params.max_duration,
+        sort,
+        descending,
+        offset: params.offset.unwrap_or(0),
+        limit: params.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+    };
+    let page = client.db.search_videos(&query).map_err(|err| {
+        client.state.read().logger.log_error(&format!(
+            "[{}, {}] failed to search videos: {err}",
+            file!(),
+            line!()
+        ));
+        Custom(Status::InternalServerError, err)
+    })?;
+    serde_json::to_string(&page).map_err(|e| Custom(Status::InternalServerError, e.to_string()))
+}
+
 #[get("/req-video-list-from-server")]
 pub(crate) fn req_video_list_from_server(client: &State<ClientVideo>) {
     client.send_req_file_list();
@@ -44,49 +137,188 @@ pub(crate) fn req_video_list_from_server(client: &State<ClientVideo>) {
 #[get("/fsm-status")]
 pub(crate) fn fsm_status(client: &State<ClientVideo>) -> EventStream![] {
     let client_state = client.state.clone();
+    let shutdown = client.shutdown.clone();
 
     EventStream! {
         let mut interval = interval(Duration::from_secs(1));
         loop {
             let fsm_status = client_state.read().fsm.to_string();
             yield Event::data(fsm_status);
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                () = shutdown.cancelled() => break,
+            }
         }
+        yield shutdown_event();
     }
 }
 
-#[get("/video-stream")]
-pub(crate) fn video_stream(client: &State<ClientVideo>) -> EventStream![] {
-    // Create broadcast channel
-    let (sender, _) = broadcast::channel::<Bytes>(1024);
-    *client.video_sender.write() = Some(sender.clone());
-    let mut receiver = sender.subscribe();
+#[get("/video-stream/<session_id>")]
+pub(crate) fn video_stream(client: &State<ClientVideo>, session_id: PlaybackId) -> EventStream![] {
+    let db = client.db.clone();
+    let shutdown = client.shutdown.clone();
+
+    // Stored video or chunks already received and receiver for the following ones
+    let subscription = client
+        .playback_sessions
+        .read()
+        .get(session_id)
+        .map(|session| {
+            if session.is_stored() {
+                Err((session.video_id, session.skipped()))
+            } else {
+                Ok(session.subscribe())
+            }
+        });
+
+    EventStream! {
+        match subscription {
+            // Read one chunk at a time from the db, skipping the chunks before the start time
+            Some(Err((video_id, skipped))) => {
+                let skipped = skipped.unwrap_or(u32::MAX..u32::MAX);
+                let chunks = db
+                    .get_video_chunks(video_id, 0)
+                    .take(skipped.start as usize)
+                    .chain(db.get_video_chunks(video_id, skipped.end));
+                for chunk in chunks {
+                    let Ok(chunk) = chunk else {
+                        break;
+                    };
+                    if shutdown.is_cancelled() {
+                        break;
+                    }
+                    let encoded = general_purpose::STANDARD.encode(&chunk);
+                    yield Event::data(encoded);
+                }
+            }
+            Some(Ok((chunks, receiver))) => {
+                for chunk in chunks {
+                    let encoded = general_purpose::STANDARD.encode(&chunk);
+                    yield Event::data(encoded);
+                }
+
+                if let Some(mut receiver) = receiver {
+                    while let Some(chunk) = recv_until_shutdown(&mut receiver, &shutdown).await {
+                        let encoded = general_purpose::STANDARD.encode(&chunk);
+                        yield Event::data(encoded);
+                    }
+                }
+            }
+            None => {}
+        }
+        if shutdown.is_cancelled() {
+            yield shutdown_event();
+        }
+    }
+}
+
+#[get("/playback-state/<session_id>")]
+pub(crate) fn playback_state(
+    client: &State<ClientVideo>,
+    session_id: PlaybackId,
+) -> EventStream![] {
+    // Current status and receiver for its changes
+    let subscription = client
+        .playback_sessions
+        .read()
+        .get(session_id)
+        .map(PlaybackSession::subscribe_status);
+    let shutdown = client.shutdown.clone();
 
     EventStream! {
-        while let Ok(chunk) = receiver.recv().await {
-            let encoded =  general_purpose::STANDARD.encode(&chunk);
-            yield Event::data(encoded);
+        if let Some((status, mut receiver)) = subscription {
+            let completed = status.state == PlaybackState::Completed;
+            yield Event::data(serde_json::to_string(&status).unwrap_or_default());
+
+            if !completed {
+                while let Some(status) = recv_until_shutdown(&mut receiver, &shutdown).await {
+                    let completed = status.state == PlaybackState::Completed;
+                    yield Event::data(serde_json::to_string(&status).unwrap_or_default());
+                    if completed {
+                        break;
+                    }
+                }
+            }
+        }
+        if shutdown.is_cancelled() {
+            yield shutdown_event();
         }
     }
 }
 
+#[get("/video/<video_id>")]
+pub(crate) async fn video(
+    client: &State<ClientVideo>,
+    video_id: FileHash,
+    range: RangeHeader,
+) -> VideoRange {
+    client.get_video_range(video_id, range).await
+}
+
 #[get("/video-list-from-server")]
 pub(crate) fn video_list_from_server(client: &State<ClientVideo>) -> EventStream![] {
     // Create broadcast channel
-    let (sender, _) = broadcast::channel::<VideoListSenderT>(10);
+    let capacity = client.state.read().config.video_list_channel_capacity;
+    let (sender, _) = broadcast::channel::<VideoListSenderT>(capacity);
     *client.file_list_sender.write() = Some(sender.clone());
     let mut receiver = sender.subscribe();
+    let shutdown = client.shutdown.clone();
 
     EventStream! {
-        while let Ok(video_metadata) = receiver.recv().await {
+        while let Some(video_metadata) = recv_until_shutdown(&mut receiver, &shutdown).await {
             let json_metadata =
                 serde_json::to_string(&video_metadata).unwrap_or_else(|_| "[]".to_string());
             yield Event::data(json_metadata);
         }
+        if shutdown.is_cancelled() {
+            yield shutdown_event();
+        }
+    }
+}
+
+/// Requests to servers that got no answer, as `request-error` events
+#[get("/request-errors")]
+pub(crate) fn request_errors(client: &State<ClientVideo>) -> EventStream![] {
+    let mut receiver = client.request_errors.subscribe();
+    let shutdown = client.shutdown.clone();
+
+    EventStream! {
+        while let Some(error) = recv_until_shutdown(&mut receiver, &shutdown).await {
+            let json_error = serde_json::to_string(&error).unwrap_or_else(|_| "{}".to_string());
+            yield Event::data(json_error).event("request-error");
+        }
+        if shutdown.is_cancelled() {
+            yield shutdown_event();
+        }
     }
 }
 
+/// Videos advertised by every server, merged by id
+#[get("/network-catalog")]
+pub(crate) fn network_catalog(client: &State<ClientVideo>) -> String {
+    let entries = client.state.read().catalog.entries();
+    serde_json::to_string(&entries).unwrap_or_else(|_| "[]".to_string())
+}
+
+#[get("/reassembly-metrics")]
+pub(crate) fn reassembly_metrics(client: &State<ClientVideo>) -> String {
+    let metrics = client.state.read().reassembler.metrics();
+    serde_json::to_string(&metrics).unwrap_or_else(|_| "{}".to_string())
+}
+
+#[get("/media-info/<video_id>")]
+pub(crate) fn media_info(client: &State<ClientVideo>, video_id: FileHash) -> Option<String> {
+    let media_info = client.db.get_video_media_info(video_id).ok()?;
+    serde_json::to_string(&media_info).ok()
+}
+
 #[get("/flood-req")]
 pub(crate) fn flood_req(client: &State<ClientVideo>) {
-    init_flood_request(&client.state);
+    client.flood_scheduler.flood_soon(FloodReason::Manual);
+}
+
+#[get("/flood-status")]
+pub(crate) fn flood_status(client: &State<ClientVideo>) -> String {
+    let status = client.flood_scheduler.status();
+    serde_json::to_string(&status).unwrap_or_else(|_| "{}".to_string())
 }
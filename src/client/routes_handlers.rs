@@ -1,38 +1,45 @@
 use packet_forge::FileHash;
 
-use super::{video_chunker::get_video_chunks, ClientVideo};
+use super::{playback_sessions::PlaybackId, ClientVideo};
+use crate::db::mp4::is_mp4_mime_type;
 
 impl ClientVideo {
-    fn get_video_from_db(&self, video_id: FileHash) -> Option<()> {
+    fn get_video_from_db(
+        &self,
+        video_id: FileHash,
+        seek_time: Option<f64>,
+    ) -> Result<Option<PlaybackId>, String> {
         // Search for the video in the database
-        let video_content = self.db.get_video_content(video_id);
+        let content_info = self.db.get_video_content_info(video_id);
         let state_guard = self.state.read();
 
-        match video_content {
-            Ok(video_content) => {
-                if video_content.is_empty() {
+        match content_info {
+            Ok(content_info) => {
+                if content_info.size == 0 {
                     state_guard.logger.log_warn(&format!(
                         "[{}, {}] video content is empty",
                         file!(),
                         line!()
                     ));
-                    return None;
+                    return Ok(None);
                 }
 
-                // Send video chunks to frontend
-                if let Some(sender) = self.video_sender.read().clone() {
-                    let video_chunks = get_video_chunks(video_content);
-                    for chunk in video_chunks {
-                        let _ = sender.send(chunk);
-                    }
-                    return Some(());
-                }
+                // Skip the fragments before the start time, only fragmented videos can be seeked
+                let skipped = match seek_time.filter(|t| *t > 0.0) {
+                    Some(seek_time) => self
+                        .db
+                        .get_video_seek_index(video_id)
+                        .map_err(|_| format!("Video {video_id} cannot start from {seek_time}s"))?
+                        .skipped_chunks(seek_time),
+                    None => None,
+                };
 
-                state_guard.logger.log_error(&format!(
-                    "[{}, {}] frontend sender not found",
-                    file!(),
-                    line!()
-                ));
+                // Create a completed playback session, its chunks are streamed from the db
+                return Ok(Some(self.playback_sessions.write().create_stored(
+                    video_id,
+                    content_info.n_chunks,
+                    skipped,
+                )));
             }
             Err(err) => {
                 state_guard.logger.log_warn(&format!(
@@ -43,20 +50,66 @@ impl ClientVideo {
             }
         }
 
-        None
+        Ok(None)
     }
 
-    pub(crate) fn request_video(&self, video_id: FileHash) {
+    /// Whether a network video cannot start from a time, if already known
+    fn is_not_seekable(&self, video_id: FileHash) -> bool {
+        let not_mp4 = self
+            .state
+            .read()
+            .videos_metadata
+            .get(&video_id)
+            .is_some_and(|metadata| !is_mp4_mime_type(&metadata.mime_type));
+        let verifier = self.chunk_verifier.read();
+        not_mp4 || (verifier.has_manifest(video_id) && verifier.seek_index(video_id).is_none())
+    }
+
+    /// Creates a playback session for `video_id` starting at `seek_time` seconds, and returns its id.
+    /// Fails if the video has no seek index to start from `seek_time`.
+    pub(crate) fn request_video(
+        &self,
+        video_id: FileHash,
+        seek_time: Option<f64>,
+    ) -> Result<PlaybackId, String> {
         // Search for the video in the database
-        if self.get_video_from_db(video_id).is_some() {
-            return;
+        if let Some(session_id) = self.get_video_from_db(video_id, seek_time)? {
+            return Ok(session_id);
         }
 
-        // Clear the chunk buffer and reset the next expected index
-        self.chunk_buffer.write().clear();
-        *self.next_expected_index.write() = 0;
-
         // If the video is not found in the database, request it from the network
-        self.send_req_peer_list(video_id);
+        if let Some(seek_time) = seek_time.filter(|t| *t > 0.0) {
+            if self.is_not_seekable(video_id) {
+                return Err(format!("Video {video_id} cannot start from {seek_time}s"));
+            }
+        }
+        let duration = self
+            .state
+            .read()
+            .videos_metadata
+            .get(&video_id)
+            .map(|metadata| metadata.duration as f64);
+        let session_id = {
+            let mut sessions = self.playback_sessions.write();
+            let session_id = sessions.create(video_id, duration, seek_time);
+
+            // The manifest may be known from a previous request
+            let verifier = self.chunk_verifier.read();
+            if let Some(size) = verifier.video_size(video_id) {
+                sessions.apply_video_size(video_id, size);
+            }
+            if let Some(seek_index) = verifier.seek_index(video_id) {
+                sessions.apply_seek_index(video_id, seek_index);
+            }
+            session_id
+        };
+
+        // Join the download in flight instead of asking the peers again
+        let peers_requested = self.state.read().peer_list_servers.contains_key(&video_id);
+        if !peers_requested && !self.join_swarm_download(video_id) {
+            self.send_req_peer_list(video_id);
+        }
+
+        Ok(session_id)
     }
 }
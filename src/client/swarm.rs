@@ -0,0 +1,495 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use packet_forge::{ChunkRequest, FileHash, Index, MessageType};
+use wg_internal::network::NodeId;
+
+use super::{utils::sends::send_msg, ClientVideo};
+
+pub(crate) const SWARM_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+type ChunkAssignmentsT = Vec<(NodeId, Vec<u32>)>;
+
+/// Chunks assigned to a single peer
+struct PeerAssignment {
+    chunks: BTreeSet<u32>,
+    last_progress: Instant,
+    nacks: u32,
+}
+
+impl PeerAssignment {
+    fn new() -> Self {
+        Self {
+            chunks: BTreeSet::new(),
+            last_progress: Instant::now(),
+            nacks: 0,
+        }
+    }
+}
+
+/// Download of a single video split between all the peers holding it
+pub(crate) struct SwarmDownload {
+    peers: HashMap<NodeId, PeerAssignment>,
+    failed_peers: HashSet<NodeId>,
+    split: bool, // Whether the chunks have been split between the peers
+}
+
+impl SwarmDownload {
+    fn new(peers: &[NodeId]) -> Self {
+        let peers = peers
+            .iter()
+            .map(|peer| (*peer, PeerAssignment::new()))
+            .collect();
+
+        Self {
+            peers,
+            failed_peers: HashSet::new(),
+            split: false,
+        }
+    }
+
+    /// Adds the `peers` not part of the download yet
+    fn add_peers(&mut self, peers: &[NodeId]) {
+        for peer in peers {
+            self.peers.entry(*peer).or_insert_with(PeerAssignment::new);
+        }
+    }
+
+    /// Returns the peers that did not fail, sorted by id
+    fn active_peers(&self) -> Vec<NodeId> {
+        let mut peers: Vec<NodeId> = self
+            .peers
+            .keys()
+            .filter(|peer| !self.failed_peers.contains(peer))
+            .copied()
+            .collect();
+        peers.sort_unstable();
+        peers
+    }
+
+    /// Assigns only the first chunk, used to learn the total number of chunks.
+    /// The peers send the manifest of the video only along with the first chunk.
+    fn probe(&mut self) -> ChunkAssignmentsT {
+        self.active_peers()
+            .first()
+            .map(|peer| self.assign_to(*peer, vec![0]))
+            .into_iter()
+            .collect()
+    }
+
+    fn assign_to(&mut self, peer: NodeId, chunks: Vec<u32>) -> (NodeId, Vec<u32>) {
+        if let Some(assignment) = self.peers.get_mut(&peer) {
+            if assignment.chunks.is_empty() {
+                assignment.last_progress = Instant::now();
+            }
+            assignment.chunks.extend(chunks.iter().copied());
+        }
+        (peer, chunks)
+    }
+
+    /// Whether every peer failed, so no chunk can be requested anymore
+    fn is_failed(&self) -> bool {
+        self.failed_peers.len() >= self.peers.len()
+    }
+
+    /// Splits the `missing` chunks between the active peers, once their total is known
+    fn split(&mut self, missing: &[u32]) -> ChunkAssignmentsT {
+        self.split = true;
+        self.assign(missing)
+    }
+
+    /// Gives the `chunks` of the failed peers to the active ones.
+    /// Before the split only the first chunk was requested, so it is probed again.
+    fn reassign(&mut self, chunks: &[u32]) -> ChunkAssignmentsT {
+        if chunks.is_empty() {
+            return Vec::new();
+        }
+        if self.split {
+            self.assign(chunks)
+        } else {
+            self.probe()
+        }
+    }
+
+    /// Splits `chunks` in contiguous parts between the active peers
+    fn assign(&mut self, chunks: &[u32]) -> ChunkAssignmentsT {
+        let peers = self.active_peers();
+        if peers.is_empty() || chunks.is_empty() {
+            return Vec::new();
+        }
+
+        let part_size = chunks.len().div_ceil(peers.len());
+        peers
+            .into_iter()
+            .zip(chunks.chunks(part_size))
+            .map(|(peer, part)| self.assign_to(peer, part.to_vec()))
+            .collect()
+    }
+
+    /// Assigns the `missing` chunks no peer was asked for, needed by a session that joined
+    /// the download. Before the split nothing is assigned, the split covers every session.
+    fn assign_unassigned(&mut self, missing: &[u32]) -> ChunkAssignmentsT {
+        if !self.split {
+            return Vec::new();
+        }
+
+        let unassigned: Vec<u32> = missing
+            .iter()
+            .filter(|chunk| {
+                !self
+                    .peers
+                    .values()
+                    .any(|assignment| assignment.chunks.contains(chunk))
+            })
+            .copied()
+            .collect();
+        self.assign(&unassigned)
+    }
+
+    fn chunk_received(&mut self, chunk_index: u32) {
+        for assignment in self.peers.values_mut() {
+            if assignment.chunks.remove(&chunk_index) {
+                assignment.last_progress = Instant::now();
+                assignment.nacks = 0;
+            }
+        }
+    }
+
+    /// Moves `chunk_index` to a different active peer, if any
+    fn reassign_chunk(&mut self, chunk_index: u32) -> ChunkAssignmentsT {
+        let holder = self
+            .peers
+            .iter_mut()
+            .find(|(_, assignment)| assignment.chunks.contains(&chunk_index))
+            .map(|(peer, assignment)| {
+                assignment.chunks.remove(&chunk_index);
+                *peer
+            });
+
+        let peers = self.active_peers();
+        let next_peer = match holder.and_then(|h| peers.iter().position(|peer| *peer == h)) {
+            Some(pos) => peers.get((pos + 1) % peers.len()),
+            None => peers.first(),
+        };
+
+        next_peer
+            .map(|peer| self.assign_to(*peer, vec![chunk_index]))
+            .into_iter()
+            .collect()
+    }
+
+    /// Marks `peer` as failed and returns its pending chunks
+    fn fail_peer(&mut self, peer: NodeId) -> Vec<u32> {
+        self.failed_peers.insert(peer);
+        self.peers
+            .get_mut(&peer)
+            .map(|assignment| std::mem::take(&mut assignment.chunks).into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Marks as failed the peers that did not send any assigned chunk for `stall_timeout`
+    /// and returns their pending chunks
+    fn take_stalled(&mut self, stall_timeout: Duration) -> Vec<u32> {
+        let stalled: Vec<NodeId> = self
+            .peers
+            .iter()
+            .filter(|(_, assignment)| {
+                !assignment.chunks.is_empty() && assignment.last_progress.elapsed() > stall_timeout
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        stalled
+            .into_iter()
+            .flat_map(|peer| self.fail_peer(peer))
+            .collect()
+    }
+}
+
+/// Converts a list of chunk indexes to the smallest `Index`
+fn chunks_to_index(chunks: Vec<u32>) -> Index {
+    // Use a range if the chunks are contiguous
+    if let (Some(first), Some(last)) = (chunks.first(), chunks.last()) {
+        if (last - first) as usize + 1 == chunks.len() {
+            return Index::Range(*first..*last + 1);
+        }
+    }
+
+    Index::Indexes(chunks)
+}
+
+impl ClientVideo {
+    fn send_chunk_requests(&self, video_id: FileHash, requests: ChunkAssignmentsT) {
+        for (peer, chunks) in requests {
+            if chunks.is_empty() {
+                continue;
+            }
+
+            let msg = MessageType::ChunkRequest(ChunkRequest::new(
+                self.get_id(),
+                video_id,
+                chunks_to_index(chunks),
+            ));
+
+            // Peers that cannot be reached will stall and get their chunks reassigned
+            let res = send_msg(&self.state, peer, msg);
+            if let Err(err) = res {
+                self.state.read().logger.log_error(&err);
+            }
+        }
+    }
+
+    /// Joins the download of `video_id` in flight, requesting the chunks that only the
+    /// sessions created since need. Returns `false` if the video is not being downloaded.
+    pub(crate) fn join_swarm_download(&self, video_id: FileHash) -> bool {
+        let requests = {
+            let mut swarms = self.swarm_downloads.write();
+            let Some(swarm) = swarms.get_mut(&video_id) else {
+                return false;
+            };
+            self.playback_sessions
+                .read()
+                .missing_chunks(video_id)
+                .map(|missing| swarm.assign_unassigned(&missing))
+                .unwrap_or_default()
+        };
+
+        self.send_chunk_requests(video_id, requests);
+        true
+    }
+
+    /// Starts downloading `video_id` in parallel from all the `peers`,
+    /// or adds them to the download already in flight
+    pub(crate) fn start_swarm_download(&self, video_id: FileHash, peers: &[NodeId]) {
+        if let Some(swarm) = self.swarm_downloads.write().get_mut(&video_id) {
+            swarm.add_peers(peers);
+        }
+        if self.join_swarm_download(video_id) {
+            return;
+        }
+
+        let missing = self.playback_sessions.read().missing_chunks(video_id);
+        let has_manifest = self.chunk_verifier.read().has_manifest(video_id);
+
+        let mut swarm = SwarmDownload::new(peers);
+        if swarm.is_failed() {
+            self.fail_swarm_download(video_id);
+            return;
+        }
+
+        let requests = match missing {
+            Some(missing) if has_manifest || missing.contains(&0) => swarm.split(&missing),
+            // The total number of chunks or the manifest is unknown, request the first chunk only
+            _ => swarm.probe(),
+        };
+        self.swarm_downloads.write().insert(video_id, swarm);
+
+        self.send_chunk_requests(video_id, requests);
+    }
+
+    /// Updates the swarm download of `video_id` after receiving `chunk_index`
+    pub(crate) fn swarm_chunk_received(
+        &self,
+        video_id: FileHash,
+        chunk_index: u32,
+        completed: bool,
+    ) {
+        if completed {
+            self.swarm_downloads.write().remove(&video_id);
+            return;
+        }
+
+        let requests = {
+            let mut swarms = self.swarm_downloads.write();
+            let Some(swarm) = swarms.get_mut(&video_id) else {
+                return;
+            };
+            swarm.chunk_received(chunk_index);
+
+            if swarm.split {
+                return;
+            }
+
+            // First chunk received, split the remaining ones between the peers
+            let Some(missing) = self.playback_sessions.read().missing_chunks(video_id) else {
+                return;
+            };
+            swarm.split(&missing)
+        };
+
+        self.send_chunk_requests(video_id, requests);
+    }
+
+    /// Requests again a chunk that failed verification, from a different peer if possible
+    pub(crate) fn swarm_chunk_invalid(&self, video_id: FileHash, chunk_index: u32) {
+        let requests = {
+            let mut swarms = self.swarm_downloads.write();
+            let Some(swarm) = swarms.get_mut(&video_id) else {
+                return;
+            };
+            swarm.reassign_chunk(chunk_index)
+        };
+
+        self.send_reassigned_chunks(
+            vec![(video_id, requests)],
+            &format!("chunk {chunk_index} failed verification"),
+        );
+    }
+
+    /// Stops the download of a video no peer can send, failing the playback of its sessions
+    fn fail_swarm_download(&self, video_id: FileHash) {
+        self.state.read().logger.log_error(&format!(
+            "[{}, {}] every peer of video {video_id} failed, download stopped",
+            file!(),
+            line!()
+        ));
+        self.swarm_downloads.write().remove(&video_id);
+        self.chunk_verifier.write().remove(video_id);
+        self.playback_sessions.write().fail(video_id);
+    }
+
+    /// Sends the chunks reassigned in each swarm download, failing the downloads left without peers
+    fn send_reassigned_chunks(&self, requests: Vec<(FileHash, ChunkAssignmentsT)>, reason: &str) {
+        for (video_id, assignments) in requests {
+            let failed = self
+                .swarm_downloads
+                .read()
+                .get(&video_id)
+                .is_some_and(SwarmDownload::is_failed);
+            if failed {
+                self.fail_swarm_download(video_id);
+                continue;
+            }
+
+            self.state.read().logger.log_warn(&format!(
+                "[{}, {}] {reason}, reassigning chunks of video {video_id}",
+                file!(),
+                line!()
+            ));
+            self.send_chunk_requests(video_id, assignments);
+        }
+    }
+
+    /// Reassigns the chunks of a peer that keeps sending nacks
+    pub(crate) fn swarm_peer_nacked(&self, peer: NodeId) {
+        let max_nacks = self.state.read().config.max_peer_nacks;
+        let mut requests = Vec::new();
+        {
+            let mut swarms = self.swarm_downloads.write();
+            for (video_id, swarm) in swarms.iter_mut() {
+                let Some(assignment) = swarm.peers.get_mut(&peer) else {
+                    continue;
+                };
+                assignment.nacks += 1;
+                if assignment.nacks < max_nacks {
+                    continue;
+                }
+
+                let chunks = swarm.fail_peer(peer);
+                requests.push((*video_id, swarm.reassign(&chunks)));
+            }
+        }
+
+        self.send_reassigned_chunks(requests, &format!("peer {peer} keeps sending nacks"));
+    }
+
+    /// Reassigns the chunks of a peer that cannot be reached
+    pub(crate) fn swarm_peer_unreachable(&self, peer: NodeId) {
+        let mut requests = Vec::new();
+        {
+            let mut swarms = self.swarm_downloads.write();
+            for (video_id, swarm) in swarms.iter_mut() {
+                if swarm.peers.contains_key(&peer) {
+                    let chunks = swarm.fail_peer(peer);
+                    requests.push((*video_id, swarm.reassign(&chunks)));
+                }
+            }
+        }
+
+        self.send_reassigned_chunks(requests, &format!("peer {peer} unreachable"));
+    }
+
+    /// Reassigns the chunks of the peers that stalled
+    pub(crate) fn check_swarm_downloads(&self) {
+        let stall_timeout = self.state.read().config.peer_stall_timeout();
+        let mut requests = Vec::new();
+        {
+            let mut swarms = self.swarm_downloads.write();
+            for (video_id, swarm) in swarms.iter_mut() {
+                let stalled = swarm.take_stalled(stall_timeout);
+                if !stalled.is_empty() {
+                    requests.push((*video_id, swarm.reassign(&stalled)));
+                }
+            }
+        }
+
+        self.send_reassigned_chunks(requests, "peers stalled");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contiguous_chunks_become_a_range() {
+        assert!(matches!(chunks_to_index(vec![3, 4, 5]), Index::Range(range) if range == (3..6)));
+        assert!(matches!(chunks_to_index(vec![7]), Index::Range(range) if range == (7..8)));
+    }
+
+    #[test]
+    fn scattered_chunks_stay_indexes() {
+        assert!(matches!(
+            chunks_to_index(vec![1, 3, 4]),
+            Index::Indexes(indexes) if indexes == [1, 3, 4]
+        ));
+        assert!(
+            matches!(chunks_to_index(Vec::new()), Index::Indexes(indexes) if indexes.is_empty())
+        );
+    }
+
+    #[test]
+    fn split_divides_chunks_between_peers() {
+        let mut swarm = SwarmDownload::new(&[2, 1]);
+        let assignments = swarm.split(&[1, 2, 3, 4, 5]);
+        assert_eq!(assignments, vec![(1, vec![1, 2, 3]), (2, vec![4, 5])]);
+        assert!(swarm.split);
+    }
+
+    #[test]
+    fn failed_probe_is_probed_again() {
+        let mut swarm = SwarmDownload::new(&[1, 2]);
+        assert_eq!(swarm.probe(), vec![(1, vec![0])]);
+
+        // The total is still unknown, the first chunk is requested from the next peer
+        let chunks = swarm.fail_peer(1);
+        assert_eq!(swarm.reassign(&chunks), vec![(2, vec![0])]);
+        assert!(!swarm.split);
+        assert!(!swarm.is_failed());
+    }
+
+    #[test]
+    fn joined_sessions_get_only_unassigned_chunks() {
+        let mut swarm = SwarmDownload::new(&[1]);
+        assert!(swarm.assign_unassigned(&[0, 1]).is_empty());
+
+        swarm.split(&[1, 2]);
+        swarm.add_peers(&[2]);
+        assert_eq!(
+            swarm.assign_unassigned(&[1, 2, 5, 6]),
+            vec![(1, vec![5]), (2, vec![6])]
+        );
+    }
+
+    #[test]
+    fn swarm_fails_once_every_peer_failed() {
+        let mut swarm = SwarmDownload::new(&[1]);
+        swarm.split(&[0, 1]);
+
+        let chunks = swarm.fail_peer(1);
+        assert_eq!(chunks, vec![0, 1]);
+        assert!(swarm.reassign(&chunks).is_empty());
+        assert!(swarm.is_failed());
+        assert!(SwarmDownload::new(&[]).is_failed());
+    }
+}
@@ -1,2 +1,8 @@
+pub(crate) mod congestion;
+pub(crate) mod flood_scheduler;
+pub(crate) mod pending_requests;
+pub(crate) mod reassembly;
+pub(crate) mod retransmission;
 pub(crate) mod sends;
+pub(crate) mod shutdown;
 pub(crate) mod start_flooding;
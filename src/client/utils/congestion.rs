@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use packet_forge::SessionIdT;
+use wg_internal::{network::NodeId, packet::Packet};
+
+use crate::client::StateT;
+
+use super::sends::send_packet;
+
+const INITIAL_WINDOW: f64 = 4.0;
+const MIN_WINDOW: f64 = 1.0;
+const MAX_WINDOW: f64 = 256.0;
+const INITIAL_SSTHRESH: f64 = 64.0;
+const DECREASE_INTERVAL: Duration = Duration::from_millis(500); // Min time between two window reductions
+
+type PacketKeyT = (u64, SessionIdT); // (fragment_index, session_id)
+
+/// AIMD sending window towards a single destination
+struct SendWindow {
+    cwnd: f64,
+    ssthresh: f64,
+    in_flight: HashSet<PacketKeyT>,
+    queue: VecDeque<Packet>,
+    last_decrease: Instant,
+}
+
+impl SendWindow {
+    fn new() -> Self {
+        Self {
+            cwnd: INITIAL_WINDOW,
+            ssthresh: INITIAL_SSTHRESH,
+            in_flight: HashSet::new(),
+            queue: VecDeque::new(),
+            last_decrease: Instant::now()
+                .checked_sub(DECREASE_INTERVAL)
+                .unwrap_or_else(Instant::now),
+        }
+    }
+
+    fn grow(&mut self) {
+        if self.cwnd < self.ssthresh {
+            // Slow start
+            self.cwnd += 1.0;
+        } else {
+            // Congestion avoidance
+            self.cwnd += 1.0 / self.cwnd;
+        }
+        self.cwnd = self.cwnd.min(MAX_WINDOW);
+    }
+
+    fn shrink(&mut self) {
+        // Reduce at most once per interval, a burst of drops is a single congestion event
+        if self.last_decrease.elapsed() < DECREASE_INTERVAL {
+            return;
+        }
+        self.last_decrease = Instant::now();
+
+        self.cwnd = (self.cwnd / 2.0).max(MIN_WINDOW);
+        self.ssthresh = self.cwnd;
+    }
+}
+
+/// Sending windows of every destination, capping the unacked fragments in flight
+#[derive(Default)]
+pub(crate) struct SendWindows {
+    windows: HashMap<NodeId, SendWindow>,
+    destinations: HashMap<PacketKeyT, NodeId>, // Destination of the fragments in flight
+    undelivered: HashSet<(NodeId, SessionIdT)>, // Sessions with packets dropped because unsendable
+}
+
+impl SendWindows {
+    /// Queues `packets` to be sent to `dest_id`
+    pub fn enqueue(&mut self, dest_id: NodeId, packets: Vec<Packet>) {
+        self.windows
+            .entry(dest_id)
+            .or_insert_with(SendWindow::new)
+            .queue
+            .extend(packets);
+    }
+
+    /// Whether every queued fragment has been sent and acked
+    pub fn is_idle(&self) -> bool {
+        self.windows
+            .values()
+            .all(|window| window.queue.is_empty() && window.in_flight.is_empty())
+    }
+
+    /// Takes the queued packets for `dest_id` that fit in its window, marking them in flight
+    pub fn ready(&mut self, dest_id: NodeId) -> Vec<Packet> {
+        let Some(window) = self.windows.get_mut(&dest_id) else {
+            return Vec::new();
+        };
+
+        let mut packets = Vec::new();
+        while window.in_flight.len() < window.cwnd as usize {
+            let Some(packet) = window.queue.pop_front() else {
+                break;
+            };
+
+            let key = (packet.get_fragment_index(), packet.session_id);
+            window.in_flight.insert(key);
+            self.destinations.insert(key, dest_id);
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// Drops the packets for `dest_id` that could not be sent and the ones queued after them,
+    /// since no flush would send them while nothing is in flight.
+    /// Their sessions are returned by `take_undelivered`.
+    pub fn drop_unsent(&mut self, dest_id: NodeId, packets: &[Packet]) {
+        let Some(window) = self.windows.get_mut(&dest_id) else {
+            return;
+        };
+
+        for packet in packets.iter().chain(&window.queue) {
+            let key = (packet.get_fragment_index(), packet.session_id);
+            window.in_flight.remove(&key);
+            self.destinations.remove(&key);
+            self.undelivered.insert((dest_id, packet.session_id));
+        }
+        window.queue.clear();
+    }
+
+    /// Returns the sessions whose packets were dropped since the last call, with their destination
+    pub fn take_undelivered(&mut self) -> Vec<(NodeId, SessionIdT)> {
+        self.undelivered.drain().collect()
+    }
+
+    fn remove_in_flight(&mut self, key: PacketKeyT) -> Option<(NodeId, &mut SendWindow)> {
+        let dest_id = self.destinations.remove(&key)?;
+        let window = self.windows.get_mut(&dest_id)?;
+        window.in_flight.remove(&key);
+        Some((dest_id, window))
+    }
+
+    /// Grows the window of the acked fragment, returning its destination
+    pub fn on_ack(&mut self, key: PacketKeyT) -> Option<NodeId> {
+        let (dest_id, window) = self.remove_in_flight(key)?;
+        window.grow();
+        Some(dest_id)
+    }
+
+    /// Shrinks the window of a dropped fragment, which stays in flight until it is resent
+    pub fn on_dropped(&mut self, key: PacketKeyT) {
+        let Some(dest_id) = self.destinations.get(&key) else {
+            return;
+        };
+        if let Some(window) = self.windows.get_mut(dest_id) {
+            window.shrink();
+        }
+    }
+
+    /// Removes a fragment that will not be acked, returning its destination
+    pub fn on_lost(&mut self, key: PacketKeyT) -> Option<NodeId> {
+        let (dest_id, window) = self.remove_in_flight(key)?;
+        window.shrink();
+        Some(dest_id)
+    }
+}
+
+/// Sends a queued packet to its next hop, routing it again if the next hop is no longer a neighbor
+fn send_queued_packet(state: &StateT, packet: &mut Packet) -> Result<(), String> {
+    let mut next_hop = packet.routing_header.hops[1];
+    if !state.read().senders.contains_key(&next_hop) {
+        let source_id = state.read().id;
+        let dest_id = packet.routing_header.hops[packet.routing_header.hops.len() - 1];
+        if let Some(srh) = state.write().routing_handler.best_path(source_id, dest_id) {
+            next_hop = srh.hops[1];
+            packet.routing_header = srh;
+        }
+    }
+
+    let sender = state.read().senders.get(&next_hop).cloned();
+    match sender {
+        Some(sender) => send_packet(state, &sender, packet),
+        None => Err(format!(
+            "[{}, {}] Sender {next_hop} not found",
+            file!(),
+            line!()
+        )),
+    }
+}
+
+/// Sends the queued packets for `dest_id` allowed by its window
+pub fn flush_send_window(state: &StateT, dest_id: NodeId) -> Result<(), String> {
+    let mut packets = state.write().send_windows.ready(dest_id);
+
+    for i in 0..packets.len() {
+        // The packets that cannot be sent are dropped and their messages reported as undelivered
+        if let Err(err) = send_queued_packet(state, &mut packets[i]) {
+            state
+                .write()
+                .send_windows
+                .drop_unsent(dest_id, &packets[i..]);
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wg_internal::{
+        network::SourceRoutingHeader,
+        packet::{Fragment, Packet},
+    };
+
+    use super::*;
+
+    fn fragment(session_id: SessionIdT, fragment_index: u64) -> Packet {
+        let fragment = Fragment {
+            fragment_index,
+            total_n_fragments: 8,
+            length: 128,
+            data: [0; 128],
+        };
+        Packet::new_fragment(
+            SourceRoutingHeader::new(vec![1, 2, 3], 1),
+            session_id,
+            fragment,
+        )
+    }
+
+    fn indexes(packets: &[Packet]) -> Vec<u64> {
+        packets.iter().map(Packet::get_fragment_index).collect()
+    }
+
+    #[test]
+    fn ready_respects_the_window() {
+        let mut windows = SendWindows::default();
+        windows.enqueue(3, (0..8).map(|i| fragment(1, i)).collect());
+
+        assert_eq!(indexes(&windows.ready(3)), vec![0, 1, 2, 3]);
+        assert!(windows.ready(3).is_empty());
+
+        // Each ack frees a slot and grows the window during slow start
+        assert_eq!(windows.on_ack((0, 1)), Some(3));
+        assert_eq!(indexes(&windows.ready(3)), vec![4, 5]);
+    }
+
+    #[test]
+    fn unsent_packets_are_dropped_and_reported() {
+        let mut windows = SendWindows::default();
+        windows.enqueue(3, (0..6).map(|i| fragment(1, i)).collect());
+        windows.enqueue(3, vec![fragment(2, 0)]);
+
+        let ready = windows.ready(3);
+        windows.drop_unsent(3, &ready[2..]);
+
+        // The queue is dropped too, only the two sent packets are still in flight
+        assert!(windows.ready(3).is_empty());
+        assert_eq!(windows.take_undelivered().len(), 2);
+        assert!(windows.take_undelivered().is_empty());
+        assert_eq!(windows.on_lost((4, 1)), None);
+        assert_eq!(windows.on_lost((1, 1)), Some(3));
+        assert_eq!(windows.on_ack((0, 1)), Some(3));
+        assert!(windows.is_idle());
+    }
+}
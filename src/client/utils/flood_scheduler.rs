@@ -0,0 +1,293 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Condvar, Mutex};
+use serde::Serialize;
+
+use crate::client::{config::ClientConfig, StateT};
+
+use super::{shutdown::ShutdownToken, start_flooding::init_flood_request};
+
+const MAX_HISTORY: usize = 32; // Floods kept in the history
+
+/// Why a flood was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub(crate) enum FloodReason {
+    Startup,
+    Periodic,
+    TopologyChange, // A sender was added or removed
+    ErrorInRouting, // A nack reported a broken route
+    Manual,         // Requested from the frontend
+}
+
+/// A flood sent by the scheduler
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FloodRecord {
+    pub at_ms: u64, // Unix time in milliseconds
+    pub reasons: Vec<FloodReason>,
+}
+
+/// Next flood and the last ones, shown to the frontend
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FloodSchedulerStatus {
+    pub next_run_ms: u64,          // Unix time in milliseconds of the next flood
+    pub pending: Vec<FloodReason>, // Signals waiting for the next flood
+    pub history: Vec<FloodRecord>, // Oldest first
+}
+
+struct SchedulerState {
+    next_periodic: Instant,
+    soon: Option<Instant>, // Flood requested by a signal, debounced
+    reasons: BTreeSet<FloodReason>,
+    last_run: Option<Instant>,
+    history: VecDeque<FloodRecord>,
+}
+
+impl SchedulerState {
+    fn next_run(&self) -> Instant {
+        self.soon
+            .map_or(self.next_periodic, |soon| soon.min(self.next_periodic))
+    }
+}
+
+fn unix_ms(at: Instant) -> u64 {
+    let now = Instant::now();
+    let time = if at >= now {
+        SystemTime::now() + (at - now)
+    } else {
+        SystemTime::now() - (now - at)
+    };
+    time.duration_since(UNIX_EPOCH).map_or(0, |time| {
+        u64::try_from(time.as_millis()).unwrap_or(u64::MAX)
+    })
+}
+
+/// Owns the periodic flood timer of the client.
+/// Every other event asks for a flood with `flood_soon`: the signals received within
+/// `flood_debounce_millis` are merged in a single flood, sent at most once every
+/// `flood_throttle_secs`.
+#[derive(Clone)]
+pub(crate) struct FloodScheduler {
+    inner: Arc<(Mutex<SchedulerState>, Condvar)>,
+    interval: Duration,
+    debounce: Duration,
+    throttle: Duration,
+}
+
+impl FloodScheduler {
+    /// Creates a scheduler sending its first flood as soon as it starts
+    pub fn new(config: &ClientConfig) -> Self {
+        let state = SchedulerState {
+            next_periodic: Instant::now(),
+            soon: None,
+            reasons: BTreeSet::from([FloodReason::Startup]),
+            last_run: None,
+            history: VecDeque::new(),
+        };
+
+        Self {
+            inner: Arc::new((Mutex::new(state), Condvar::new())),
+            interval: config.flooding_interval(),
+            debounce: config.flood_debounce(),
+            throttle: config.flood_throttle(),
+        }
+    }
+
+    /// Asks for a flood in the next `debounce`, unless one is already scheduled
+    pub fn flood_soon(&self, reason: FloodReason) {
+        let (lock, condvar) = &*self.inner;
+        let mut state = lock.lock();
+
+        state.reasons.insert(reason);
+        if state.soon.is_none() {
+            let mut soon = Instant::now() + self.debounce;
+            if let Some(last_run) = state.last_run {
+                soon = soon.max(last_run + self.throttle);
+            }
+            state.soon = Some(soon);
+        }
+        condvar.notify_all();
+    }
+
+    /// Wakes the thread waiting for the next flood, to check the shutdown
+    fn wake(&self) {
+        let (lock, condvar) = &*self.inner;
+        // Taking the lock ensures the thread is either waiting or about to check the shutdown
+        let _state = lock.lock();
+        condvar.notify_all();
+    }
+
+    pub fn status(&self) -> FloodSchedulerStatus {
+        let state = self.inner.0.lock();
+        FloodSchedulerStatus {
+            next_run_ms: unix_ms(state.next_run()),
+            pending: state.reasons.iter().copied().collect(),
+            history: state.history.iter().cloned().collect(),
+        }
+    }
+
+    /// Waits for the next flood, returning its reasons, or `None` on shutdown
+    fn wait_next(&self, shutdown: &ShutdownToken) -> Option<Vec<FloodReason>> {
+        let (lock, condvar) = &*self.inner;
+        let mut state = lock.lock();
+
+        loop {
+            if shutdown.is_cancelled() {
+                return None;
+            }
+
+            let next_run = state.next_run();
+            if next_run <= Instant::now() {
+                break;
+            }
+            // Woken up early by `flood_soon` or by the shutdown
+            condvar.wait_until(&mut state, next_run);
+        }
+
+        let now = Instant::now();
+        let mut reasons: Vec<FloodReason> =
+            std::mem::take(&mut state.reasons).into_iter().collect();
+        if reasons.is_empty() {
+            reasons.push(FloodReason::Periodic);
+        }
+
+        // Signals received before this flood are served by it
+        state.soon = None;
+        state.next_periodic = now + self.interval;
+        state.last_run = Some(now);
+        state.history.push_back(FloodRecord {
+            at_ms: unix_ms(now),
+            reasons: reasons.clone(),
+        });
+        if state.history.len() > MAX_HISTORY {
+            state.history.pop_front();
+        }
+        Some(reasons)
+    }
+
+    /// Starts the thread sending the floods, until `shutdown` is cancelled
+    pub fn start(&self, state: StateT, shutdown: ShutdownToken) -> JoinHandle<()> {
+        let scheduler = self.clone();
+        shutdown.on_cancel(move || scheduler.wake());
+
+        let scheduler = self.clone();
+        thread::spawn(move || {
+            while let Some(reasons) = scheduler.wait_next(&shutdown) {
+                state.read().logger.log_debug(&format!(
+                    "[{}, {}] flooding, reasons: {reasons:?}",
+                    file!(),
+                    line!()
+                ));
+                init_flood_request(&state);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBOUNCE: Duration = Duration::from_millis(50);
+
+    /// Scheduler whose periodic flood never comes during a test
+    fn scheduler(throttle_secs: u64) -> FloodScheduler {
+        FloodScheduler::new(&ClientConfig {
+            flooding_interval_secs: 3600,
+            flood_throttle_secs: throttle_secs,
+            flood_debounce_millis: DEBOUNCE.as_millis() as u64,
+            ..ClientConfig::default()
+        })
+    }
+
+    #[test]
+    fn first_flood_is_sent_right_away() {
+        let scheduler = scheduler(0);
+        let shutdown = ShutdownToken::default();
+        assert_eq!(
+            scheduler.wait_next(&shutdown),
+            Some(vec![FloodReason::Startup])
+        );
+        assert!(scheduler.status().pending.is_empty());
+        assert_eq!(scheduler.status().history.len(), 1);
+    }
+
+    #[test]
+    fn burst_of_signals_is_merged_in_one_flood() {
+        let scheduler = scheduler(0);
+        let shutdown = ShutdownToken::default();
+        scheduler.wait_next(&shutdown);
+
+        let requested = Instant::now();
+        scheduler.flood_soon(FloodReason::TopologyChange);
+        scheduler.flood_soon(FloodReason::Manual);
+        scheduler.flood_soon(FloodReason::TopologyChange);
+        assert_eq!(
+            scheduler.status().pending,
+            vec![FloodReason::TopologyChange, FloodReason::Manual]
+        );
+
+        assert_eq!(
+            scheduler.wait_next(&shutdown),
+            Some(vec![FloodReason::TopologyChange, FloodReason::Manual])
+        );
+        assert!(requested.elapsed() >= DEBOUNCE);
+        assert!(scheduler.status().pending.is_empty());
+        assert_eq!(scheduler.status().history.len(), 2);
+    }
+
+    #[test]
+    fn signal_wakes_the_waiting_thread() {
+        let scheduler = scheduler(0);
+        let shutdown = ShutdownToken::default();
+        scheduler.wait_next(&shutdown);
+
+        let waiting = {
+            let scheduler = scheduler.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || scheduler.wait_next(&shutdown))
+        };
+        thread::sleep(DEBOUNCE);
+        scheduler.flood_soon(FloodReason::ErrorInRouting);
+        assert_eq!(
+            waiting.join().unwrap(),
+            Some(vec![FloodReason::ErrorInRouting])
+        );
+    }
+
+    #[test]
+    fn signals_after_a_flood_are_throttled() {
+        let scheduler = scheduler(10);
+        let shutdown = ShutdownToken::default();
+        scheduler.wait_next(&shutdown);
+
+        scheduler.flood_soon(FloodReason::Manual);
+        let state = scheduler.inner.0.lock();
+        let last_run = state.last_run.unwrap();
+        assert!(state.soon.unwrap() >= last_run + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn shutdown_wakes_the_waiting_thread() {
+        let scheduler = scheduler(0);
+        let shutdown = ShutdownToken::default();
+        scheduler.wait_next(&shutdown);
+        {
+            let scheduler = scheduler.clone();
+            shutdown.on_cancel(move || scheduler.wake());
+        }
+
+        let started = Instant::now();
+        let waiting = {
+            let scheduler = scheduler.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || scheduler.wait_next(&shutdown))
+        };
+        thread::sleep(DEBOUNCE);
+        shutdown.cancel();
+        assert_eq!(waiting.join().unwrap(), None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use packet_forge::FileHash;
+use serde::Serialize;
+use wg_internal::network::NodeId;
+
+use crate::client::ClientVideo;
+
+pub(crate) const REQUEST_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+pub(crate) const REQUEST_ERRORS_CAPACITY: usize = 16; // Errors buffered for the frontend
+
+type RequestKeyT = (RequestKind, NodeId); // (kind, destination)
+
+/// Message sent to a server that expects an answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub(crate) enum RequestKind {
+    SubscribeClient,    // Answered by the first `ResponseFileList` of the server
+    FileList,           // Answered by `ResponseFileList`
+    PeerList(FileHash), // Answered by `ResponsePeerList` of the video
+}
+
+/// Request that got no answer, sent to the frontend
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RequestError {
+    pub kind: RequestKind,
+    pub dest: Option<NodeId>, // Server the request was sent to, if any
+    pub message: String,
+}
+
+struct PendingRequest {
+    deadline: Instant,
+    retries: u32,
+}
+
+/// Requests sent to servers and still waiting for their answer, keyed by kind and destination
+#[derive(Default)]
+pub(crate) struct PendingRequests {
+    pending: HashMap<RequestKeyT, PendingRequest>,
+}
+
+impl PendingRequests {
+    /// Starts (or restarts) the deadline of a request, keeping its retries
+    pub fn on_sent(&mut self, kind: RequestKind, dest: NodeId, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.pending
+            .entry((kind, dest))
+            .and_modify(|pending| pending.deadline = deadline)
+            .or_insert(PendingRequest {
+                deadline,
+                retries: 0,
+            });
+    }
+
+    /// Stops waiting for a request
+    pub fn complete(&mut self, kind: RequestKind, dest: NodeId) {
+        self.pending.remove(&(kind, dest));
+    }
+
+    /// Stops waiting for a request sent to any destination, returns the destinations
+    pub fn complete_kind(&mut self, kind: RequestKind) -> Vec<NodeId> {
+        let dests: Vec<NodeId> = self
+            .pending
+            .keys()
+            .filter(|(pending_kind, _)| *pending_kind == kind)
+            .map(|(_, dest)| *dest)
+            .collect();
+        for dest in &dests {
+            self.pending.remove(&(kind, *dest));
+        }
+        dests
+    }
+
+    /// Stops waiting for every request sent to `dest`, returns their kinds
+    pub fn complete_dest(&mut self, dest: NodeId) -> Vec<RequestKind> {
+        let kinds: Vec<RequestKind> = self
+            .pending
+            .keys()
+            .filter(|(_, pending_dest)| *pending_dest == dest)
+            .map(|(kind, _)| *kind)
+            .collect();
+        for kind in &kinds {
+            self.pending.remove(&(*kind, dest));
+        }
+        kinds
+    }
+
+    /// Returns the requests past their deadline that can be retried, restarting their deadline,
+    /// and the ones that exceeded `max_retries`, which are no longer tracked
+    pub fn expired(
+        &mut self,
+        timeout: Duration,
+        max_retries: u32,
+    ) -> (Vec<RequestKeyT>, Vec<RequestKeyT>) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut failed = Vec::new();
+
+        for (key, pending) in &mut self.pending {
+            if pending.deadline > now {
+                continue;
+            }
+
+            if pending.retries >= max_retries {
+                failed.push(*key);
+                continue;
+            }
+
+            // Counted here, so a retry that cannot be sent still moves towards the failure
+            pending.retries += 1;
+            pending.deadline = now + timeout;
+            expired.push(*key);
+        }
+
+        for key in &failed {
+            self.pending.remove(key);
+        }
+
+        (expired, failed)
+    }
+}
+
+impl ClientVideo {
+    /// Starts the deadline of a request sent to `dest`
+    pub(crate) fn track_request(&self, kind: RequestKind, dest: NodeId) {
+        let mut state = self.state.write();
+        let timeout = state.config.request_timeout();
+        state.pending_requests.on_sent(kind, dest, timeout);
+    }
+
+    /// Sends a request error to the frontend
+    pub(crate) fn report_request_error(&self, error: RequestError) {
+        self.state.read().logger.log_error(&format!(
+            "[{}, {}] request {:?} failed: {}",
+            file!(),
+            line!(),
+            error.kind,
+            error.message
+        ));
+        // Fails only if the frontend is not listening
+        let _ = self.request_errors.send(error);
+    }
+
+    /// Resends the requests that got no answer in time and reports the ones
+    /// that exceeded the maximum number of retries
+    pub(crate) fn check_pending_requests(&self) {
+        let (expired, failed) = {
+            let mut state = self.state.write();
+            let timeout = state.config.request_timeout();
+            let max_retries = state.config.request_retries;
+            state.pending_requests.expired(timeout, max_retries)
+        };
+
+        for (kind, dest) in expired {
+            self.state.read().logger.log_warn(&format!(
+                "[{}, {}] no answer from {dest} to {kind:?}, retrying",
+                file!(),
+                line!()
+            ));
+            self.send_request(kind, dest);
+        }
+
+        for (kind, dest) in failed {
+            self.handle_request_failure(kind, dest, &format!("server {dest} did not answer"));
+        }
+    }
+
+    /// Fails the requests waiting for an answer from `dest`, which cannot be reached
+    pub(crate) fn fail_requests_to(&self, dest: NodeId) {
+        let kinds = self.state.write().pending_requests.complete_dest(dest);
+        for kind in kinds {
+            self.handle_request_failure(kind, dest, &format!("server {dest} cannot be reached"));
+        }
+    }
+
+    /// Sends the message of a request to `dest`
+    fn send_request(&self, kind: RequestKind, dest: NodeId) {
+        match kind {
+            RequestKind::SubscribeClient => self.send_subscribe_client(dest),
+            RequestKind::FileList => self.send_req_file_list_to(dest),
+            RequestKind::PeerList(video_id) => {
+                if let Err(err) = self.send_req_peer_list_to(dest, video_id) {
+                    self.fail_unsent_request(kind, dest, &err);
+                }
+            }
+        }
+    }
+
+    /// Reports a request whose message could not be sent to `dest` and stops waiting for it
+    pub(crate) fn fail_unsent_request(&self, kind: RequestKind, dest: NodeId, err: &str) {
+        self.state.read().logger.log_error(err);
+        self.state.write().pending_requests.complete(kind, dest);
+
+        let message = format!("cannot send to server {dest}");
+        // Unanswered peer lists are reported once every server was tried, a send failure right away
+        if let RequestKind::PeerList(_) = kind {
+            self.report_request_error(RequestError {
+                kind,
+                dest: Some(dest),
+                message: message.clone(),
+            });
+        }
+        self.handle_request_failure(kind, dest, &message);
+    }
+
+    /// Reports a request that got no answer after every retry, or could not be delivered.
+    /// The videos advertised by `dest` are dropped from the catalog.
+    fn handle_request_failure(&self, kind: RequestKind, dest: NodeId, message: &str) {
+        self.state.write().catalog.remove_server(dest);
+
+        // Another server may advertise the video, it is reported only if none answers
+        if let RequestKind::PeerList(video_id) = kind {
+            self.state.read().logger.log_warn(&format!(
+                "[{}, {}] peer list of video {video_id} failed: {message}",
+                file!(),
+                line!()
+            ));
+            self.send_req_peer_list_to_next(video_id);
+            return;
+        }
+
+        // The frontend waits for a list from every server, send it an empty one
+        if kind == RequestKind::FileList {
+            if let Some(sender) = &self.file_list_sender.read().clone() {
+                let _ = sender.send((dest, Vec::new()));
+            }
+        }
+
+        self.report_request_error(RequestError {
+            kind,
+            dest: Some(dest),
+            message: message.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Requests whose deadline is already past
+    fn expired_requests(keys: &[RequestKeyT]) -> PendingRequests {
+        let mut requests = PendingRequests::default();
+        for (kind, dest) in keys {
+            requests.on_sent(*kind, *dest, Duration::ZERO);
+        }
+        requests
+    }
+
+    #[test]
+    fn requests_are_retried_then_failed() {
+        let mut requests = expired_requests(&[(RequestKind::FileList, 1)]);
+
+        for _ in 0..2 {
+            let (expired, failed) = requests.expired(Duration::ZERO, 2);
+            assert_eq!(expired, vec![(RequestKind::FileList, 1)]);
+            assert!(failed.is_empty());
+        }
+
+        let (expired, failed) = requests.expired(Duration::ZERO, 2);
+        assert!(expired.is_empty());
+        assert_eq!(failed, vec![(RequestKind::FileList, 1)]);
+        assert_eq!(
+            requests.expired(Duration::ZERO, 2),
+            (Vec::new(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn requests_before_their_deadline_are_kept() {
+        let mut requests = PendingRequests::default();
+        requests.on_sent(RequestKind::FileList, 1, TIMEOUT);
+        assert_eq!(requests.expired(TIMEOUT, 0), (Vec::new(), Vec::new()));
+
+        // A retry restarts the deadline
+        let mut requests = expired_requests(&[(RequestKind::FileList, 1)]);
+        assert_eq!(requests.expired(TIMEOUT, 1).0.len(), 1);
+        assert_eq!(requests.expired(TIMEOUT, 1), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn resent_request_keeps_its_retries() {
+        let mut requests = expired_requests(&[(RequestKind::SubscribeClient, 1)]);
+        requests.expired(Duration::ZERO, 1);
+        requests.on_sent(RequestKind::SubscribeClient, 1, Duration::ZERO);
+
+        let (_, failed) = requests.expired(Duration::ZERO, 1);
+        assert_eq!(failed, vec![(RequestKind::SubscribeClient, 1)]);
+    }
+
+    #[test]
+    fn complete_kind_stops_every_destination() {
+        let mut requests = expired_requests(&[
+            (RequestKind::PeerList(7), 1),
+            (RequestKind::PeerList(7), 2),
+            (RequestKind::PeerList(8), 1),
+        ]);
+
+        let mut dests = requests.complete_kind(RequestKind::PeerList(7));
+        dests.sort_unstable();
+        assert_eq!(dests, vec![1, 2]);
+        assert!(requests.complete_kind(RequestKind::PeerList(7)).is_empty());
+
+        let (expired, _) = requests.expired(TIMEOUT, 1);
+        assert_eq!(expired, vec![(RequestKind::PeerList(8), 1)]);
+    }
+
+    #[test]
+    fn complete_dest_stops_every_kind() {
+        let mut requests = expired_requests(&[
+            (RequestKind::FileList, 1),
+            (RequestKind::PeerList(7), 1),
+            (RequestKind::FileList, 2),
+        ]);
+
+        let mut kinds = requests.complete_dest(1);
+        kinds.sort_by_key(|kind| format!("{kind:?}"));
+        assert_eq!(kinds, vec![RequestKind::FileList, RequestKind::PeerList(7)]);
+        assert!(requests.complete_dest(1).is_empty());
+
+        requests.complete(RequestKind::FileList, 2);
+        assert_eq!(requests.expired(TIMEOUT, 1), (Vec::new(), Vec::new()));
+    }
+}
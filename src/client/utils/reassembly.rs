@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use packet_forge::SessionIdT;
+use serde::Serialize;
+use wg_internal::{network::NodeId, packet::Fragment};
+
+use crate::client::ClientVideo;
+
+pub(crate) const REASSEMBLY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30); // Max time without receiving a fragment
+const MAX_SESSION_BYTES: usize = 16 * 1024 * 1024;
+const MAX_TOTAL_BYTES: usize = 64 * 1024 * 1024;
+const COMPLETED_TTL: Duration = Duration::from_secs(60); // Time late fragments of a completed session are dropped
+const MAX_COMPLETED_SESSIONS: usize = 4096;
+
+/// Session ids are chosen by each sender, so two senders may use the same one
+pub(crate) type SessionKeyT = (NodeId, SessionIdT); // (source, session_id)
+
+/// Fragments received so far of a single message
+struct PartialMessage {
+    fragments: BTreeMap<u64, Fragment>, // fragment_index -> Fragment
+    total_n_fragments: u64,
+    size: usize,
+    last_update: Instant,
+}
+
+/// Counters of the fragments and sessions dropped by the reassembler
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub(crate) struct ReassemblyMetrics {
+    pub duplicates: u64,
+    pub invalid: u64,
+    pub rejected_session_limit: u64,
+    pub evicted_timeout: u64,
+    pub evicted_total_limit: u64,
+}
+
+pub(crate) enum FragmentOutcome {
+    Pending,
+    Duplicate,
+    Complete(Vec<Fragment>),
+    Rejected(String),
+}
+
+/// Reassembles messages from their fragments, bounding the memory used
+#[derive(Default)]
+pub(crate) struct FragmentReassembler {
+    messages: HashMap<SessionKeyT, PartialMessage>,
+    completed: HashMap<SessionKeyT, Instant>, // Recently completed sessions and their completion time
+    completed_order: VecDeque<SessionKeyT>,   // Completed sessions, oldest first
+    total_size: usize,
+    metrics: ReassemblyMetrics,
+}
+
+impl FragmentReassembler {
+    /// Forgets the completed sessions older than `COMPLETED_TTL` or exceeding `MAX_COMPLETED_SESSIONS`
+    fn prune_completed(&mut self) {
+        while let Some(session) = self.completed_order.front().copied() {
+            let expired = self
+                .completed
+                .get(&session)
+                .is_none_or(|completed_at| completed_at.elapsed() > COMPLETED_TTL);
+            if !expired && self.completed_order.len() <= MAX_COMPLETED_SESSIONS {
+                break;
+            }
+            self.completed_order.pop_front();
+            self.completed.remove(&session);
+        }
+    }
+
+    fn mark_completed(&mut self, session: SessionKeyT) {
+        self.completed.insert(session, Instant::now());
+        self.completed_order.push_back(session);
+        self.prune_completed();
+    }
+
+    fn remove_session(&mut self, session: SessionKeyT) -> Option<PartialMessage> {
+        let message = self.messages.remove(&session)?;
+        self.total_size -= message.size;
+        Some(message)
+    }
+
+    /// Evicts the least recently updated sessions, except `session`, until the total limit is respected
+    fn enforce_total_limit(&mut self, session: SessionKeyT) {
+        while self.total_size > MAX_TOTAL_BYTES {
+            let oldest = self
+                .messages
+                .iter()
+                .filter(|(key, _)| **key != session)
+                .min_by_key(|(_, message)| message.last_update)
+                .map(|(id, _)| *id);
+
+            let Some(oldest) = oldest else {
+                break;
+            };
+            self.remove_session(oldest);
+            self.metrics.evicted_total_limit += 1;
+        }
+    }
+
+    /// Adds a fragment of the `session` of a sender, returning all the fragments once
+    /// the message is complete
+    pub fn insert(&mut self, session: SessionKeyT, frag: Fragment) -> FragmentOutcome {
+        if frag.fragment_index >= frag.total_n_fragments {
+            self.metrics.invalid += 1;
+            return FragmentOutcome::Rejected(format!(
+                "invalid fragment {} of {}",
+                frag.fragment_index, frag.total_n_fragments
+            ));
+        }
+
+        // Refuse messages that would exceed the session limit once complete
+        let max_fragment_size = frag.data.len() as u64;
+        if frag.total_n_fragments.saturating_mul(max_fragment_size) > MAX_SESSION_BYTES as u64 {
+            self.metrics.rejected_session_limit += 1;
+            return FragmentOutcome::Rejected(format!(
+                "message of {} fragments exceeds the session limit",
+                frag.total_n_fragments
+            ));
+        }
+
+        // Late copy of a fragment, e.g. retransmitted after its ack was lost
+        self.prune_completed();
+        if self.completed.contains_key(&session) {
+            self.metrics.duplicates += 1;
+            return FragmentOutcome::Duplicate;
+        }
+
+        let message = self
+            .messages
+            .entry(session)
+            .or_insert_with(|| PartialMessage {
+                fragments: BTreeMap::new(),
+                total_n_fragments: frag.total_n_fragments,
+                size: 0,
+                last_update: Instant::now(),
+            });
+
+        if message.total_n_fragments != frag.total_n_fragments {
+            self.metrics.invalid += 1;
+            return FragmentOutcome::Rejected(format!(
+                "fragment {} expects {} fragments instead of {}",
+                frag.fragment_index, frag.total_n_fragments, message.total_n_fragments
+            ));
+        }
+
+        if message.fragments.contains_key(&frag.fragment_index) {
+            self.metrics.duplicates += 1;
+            return FragmentOutcome::Duplicate;
+        }
+
+        let frag_size = usize::from(frag.length);
+        message.size += frag_size;
+        message.last_update = Instant::now();
+        message.fragments.insert(frag.fragment_index, frag);
+        self.total_size += frag_size;
+
+        if message.fragments.len() as u64 == message.total_n_fragments {
+            return match self.remove_session(session) {
+                Some(message) => {
+                    self.mark_completed(session);
+                    FragmentOutcome::Complete(message.fragments.into_values().collect())
+                }
+                None => FragmentOutcome::Pending,
+            };
+        }
+
+        self.enforce_total_limit(session);
+        FragmentOutcome::Pending
+    }
+
+    /// Evicts the sessions that did not receive fragments for too long,
+    /// returning their keys
+    pub fn evict_stale(&mut self) -> Vec<SessionKeyT> {
+        let stale: Vec<SessionKeyT> = self
+            .messages
+            .iter()
+            .filter(|(_, message)| message.last_update.elapsed() > SESSION_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for session in &stale {
+            self.remove_session(*session);
+            self.metrics.evicted_timeout += 1;
+        }
+        self.prune_completed();
+        stale
+    }
+
+    pub fn metrics(&self) -> ReassemblyMetrics {
+        self.metrics
+    }
+}
+
+impl ClientVideo {
+    /// Frees the incomplete messages that stopped receiving fragments
+    pub(crate) fn evict_stale_fragments(&self) {
+        let evicted = self.state.write().reassembler.evict_stale();
+
+        for (source, session_id) in evicted {
+            self.state.read().logger.log_warn(&format!(
+                "[{}, {}] evicted incomplete session {session_id} of {source}",
+                file!(),
+                line!()
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: NodeId = 7;
+
+    fn fragment(fragment_index: u64, total_n_fragments: u64) -> Fragment {
+        Fragment {
+            fragment_index,
+            total_n_fragments,
+            length: 128,
+            data: [0; 128],
+        }
+    }
+
+    #[test]
+    fn completes_once_every_fragment_arrived() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(matches!(
+            reassembler.insert((SOURCE, 1), fragment(1, 2)),
+            FragmentOutcome::Pending
+        ));
+        assert!(matches!(
+            reassembler.insert((SOURCE, 1), fragment(1, 2)),
+            FragmentOutcome::Duplicate
+        ));
+        assert!(matches!(
+            reassembler.insert((SOURCE, 1), fragment(0, 2)),
+            FragmentOutcome::Complete(fragments) if fragments.len() == 2
+        ));
+    }
+
+    #[test]
+    fn late_fragments_of_completed_sessions_are_duplicates() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(matches!(
+            reassembler.insert((SOURCE, 1), fragment(0, 1)),
+            FragmentOutcome::Complete(_)
+        ));
+
+        // A retransmission must not complete the message again
+        assert!(matches!(
+            reassembler.insert((SOURCE, 1), fragment(0, 1)),
+            FragmentOutcome::Duplicate
+        ));
+        assert_eq!(reassembler.metrics().duplicates, 1);
+        assert!(reassembler.messages.is_empty());
+    }
+
+    #[test]
+    fn completed_sessions_are_bounded() {
+        let mut reassembler = FragmentReassembler::default();
+        for session_id in 0..=MAX_COMPLETED_SESSIONS as u64 {
+            reassembler.insert((SOURCE, session_id), fragment(0, 1));
+        }
+
+        assert_eq!(reassembler.completed.len(), MAX_COMPLETED_SESSIONS);
+        assert!(matches!(
+            reassembler.insert((SOURCE, 0), fragment(0, 1)),
+            FragmentOutcome::Complete(_)
+        ));
+    }
+
+    #[test]
+    fn sessions_of_different_senders_do_not_mix() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(matches!(
+            reassembler.insert((1, 5), fragment(0, 2)),
+            FragmentOutcome::Pending
+        ));
+        assert!(matches!(
+            reassembler.insert((2, 5), fragment(0, 1)),
+            FragmentOutcome::Complete(fragments) if fragments.len() == 1
+        ));
+
+        // The completed session of the second sender is not a duplicate for the first one
+        assert!(matches!(
+            reassembler.insert((1, 5), fragment(1, 2)),
+            FragmentOutcome::Complete(fragments) if fragments.len() == 2
+        ));
+        assert_eq!(reassembler.metrics().duplicates, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_fragments() {
+        let mut reassembler = FragmentReassembler::default();
+        assert!(matches!(
+            reassembler.insert((SOURCE, 1), fragment(2, 2)),
+            FragmentOutcome::Rejected(_)
+        ));
+        reassembler.insert((SOURCE, 2), fragment(0, 3));
+        assert!(matches!(
+            reassembler.insert((SOURCE, 2), fragment(1, 4)),
+            FragmentOutcome::Rejected(_)
+        ));
+        assert_eq!(reassembler.metrics().invalid, 2);
+    }
+}
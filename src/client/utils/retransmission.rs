@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use packet_forge::SessionIdT;
+use wg_internal::network::NodeId;
+
+use crate::client::ClientVideo;
+
+use super::congestion::flush_send_window;
+
+pub(crate) const RETRANSMISSION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(10);
+const MAX_RETRIES: u32 = 5; // Timeouts after which the packet is considered lost
+
+type PacketKeyT = (u64, SessionIdT); // (fragment_index, session_id)
+
+/// Timer of a packet waiting for an `Ack`
+struct PendingPacket {
+    sent_at: Instant,
+    timeout: Duration,
+    retries: u32,
+    ambiguous: bool, // Sent more than once, its rtt cannot be sampled
+}
+
+/// Retransmission timers of the sent fragments, with an adaptive RTO (RFC 6298)
+pub(crate) struct RetransmissionTimers {
+    pending: HashMap<PacketKeyT, PendingPacket>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for RetransmissionTimers {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl RetransmissionTimers {
+    /// Starts (or restarts) the timer of a packet
+    pub fn on_sent(&mut self, key: PacketKeyT) {
+        let rto = self.rto;
+        self.pending
+            .entry(key)
+            .and_modify(|pending| {
+                pending.sent_at = Instant::now();
+                pending.ambiguous = true;
+            })
+            .or_insert(PendingPacket {
+                sent_at: Instant::now(),
+                timeout: rto,
+                retries: 0,
+                ambiguous: false,
+            });
+    }
+
+    /// Stops the timer of a packet and updates the RTO
+    pub fn on_acked(&mut self, key: PacketKeyT) {
+        let Some(pending) = self.pending.remove(&key) else {
+            return;
+        };
+
+        // Karn's algorithm: only sample packets sent once
+        if !pending.ambiguous {
+            self.sample(pending.sent_at.elapsed());
+        }
+    }
+
+    /// Whether no packet is waiting for an ack
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Stops the timer of a packet without updating the RTO
+    pub fn remove(&mut self, key: PacketKeyT) {
+        self.pending.remove(&key);
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+
+        let srtt = self.srtt.unwrap_or(INITIAL_RTO);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Returns the packets whose timer expired, backing off their timeout, and
+    /// the packets that exceeded `MAX_RETRIES`, which are no longer tracked
+    pub fn expired(&mut self) -> (Vec<PacketKeyT>, Vec<PacketKeyT>) {
+        let mut expired = Vec::new();
+        let mut lost = Vec::new();
+
+        for (key, pending) in &mut self.pending {
+            if pending.sent_at.elapsed() < pending.timeout {
+                continue;
+            }
+
+            if pending.retries >= MAX_RETRIES {
+                lost.push(*key);
+                continue;
+            }
+
+            pending.retries += 1;
+            pending.timeout = (pending.timeout * 2).min(MAX_RTO);
+            pending.sent_at = Instant::now();
+            expired.push(*key);
+        }
+
+        for key in &lost {
+            self.pending.remove(key);
+        }
+
+        (expired, lost)
+    }
+}
+
+impl ClientVideo {
+    /// Resends the packets that did not receive an `Ack` in time and gives up on
+    /// the ones that exceeded the maximum number of retries
+    pub(crate) fn check_retransmissions(&self) {
+        let (expired, lost) = self.state.write().retransmissions.expired();
+
+        for key in expired {
+            let Some(packet) = self.state.read().packets_history.get(&key).cloned() else {
+                self.state.write().retransmissions.remove(key);
+                continue;
+            };
+
+            self.state.read().logger.log_debug(&format!(
+                "[{}, {}] retransmitting packet ({}, {}) after timeout",
+                file!(),
+                line!(),
+                key.0,
+                key.1
+            ));
+            Self::retransmit_packet(&self.state, packet);
+        }
+
+        // Report each failed message once and free their sending windows
+        let mut failed = HashSet::new();
+        let mut windows = HashSet::new();
+        for key in lost {
+            let packet = self.state.write().packets_history.remove(&key);
+            if let Some(dest) = packet.and_then(|p| p.routing_header.hops.last().copied()) {
+                failed.insert((dest, key.1));
+            }
+            if let Some(dest) = self.state.write().send_windows.on_lost(key) {
+                windows.insert(dest);
+            }
+        }
+
+        for dest_id in windows {
+            if let Err(err) = flush_send_window(&self.state, dest_id) {
+                self.state.read().logger.log_error(&err);
+            }
+        }
+
+        // Messages with fragments that could not be sent by any flush
+        failed.extend(self.state.write().send_windows.take_undelivered());
+
+        for (dest_id, session_id) in failed {
+            self.handle_delivery_failure(dest_id, session_id);
+        }
+    }
+
+    /// Reports that a message for `dest_id` could not be delivered
+    fn handle_delivery_failure(&self, dest_id: NodeId, session_id: SessionIdT) {
+        self.state.read().logger.log_error(&format!(
+            "[{}, {}] giving up on session {session_id} to {dest_id}, not delivered",
+            file!(),
+            line!()
+        ));
+
+        // Reassign the chunks requested to an unreachable peer
+        self.swarm_peer_unreachable(dest_id);
+        // Stop waiting for the answers of an unreachable server
+        self.fail_requests_to(dest_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: PacketKeyT = (0, 1);
+
+    /// Moves the timer of `key` past its timeout
+    fn expire(timers: &mut RetransmissionTimers, key: PacketKeyT) {
+        let pending = timers.pending.get_mut(&key).unwrap();
+        pending.sent_at = Instant::now() - pending.timeout;
+    }
+
+    #[test]
+    fn rto_follows_the_samples() {
+        let mut timers = RetransmissionTimers::default();
+        assert_eq!(timers.rto, INITIAL_RTO);
+
+        // First sample: srtt = rtt, rttvar = rtt / 2
+        timers.sample(Duration::from_millis(100));
+        assert_eq!(timers.srtt, Some(Duration::from_millis(100)));
+        assert_eq!(timers.rto, Duration::from_millis(300));
+
+        // rttvar = 3/4 * 50 + 1/4 * 100, srtt = 7/8 * 100 + 1/8 * 200
+        timers.sample(Duration::from_millis(200));
+        assert_eq!(timers.rttvar, Duration::from_micros(62_500));
+        assert_eq!(timers.srtt, Some(Duration::from_micros(112_500)));
+        assert_eq!(timers.rto, Duration::from_micros(362_500));
+    }
+
+    #[test]
+    fn rto_is_clamped() {
+        let mut timers = RetransmissionTimers::default();
+        timers.sample(Duration::from_millis(1));
+        assert_eq!(timers.rto, MIN_RTO);
+
+        let mut timers = RetransmissionTimers::default();
+        timers.sample(Duration::from_secs(20));
+        assert_eq!(timers.rto, MAX_RTO);
+    }
+
+    #[test]
+    fn retransmitted_packets_are_not_sampled() {
+        let mut timers = RetransmissionTimers::default();
+        timers.on_sent(KEY);
+        timers.on_sent(KEY);
+        timers.on_acked(KEY);
+        assert_eq!(timers.srtt, None);
+        assert_eq!(timers.rto, INITIAL_RTO);
+        assert!(timers.is_empty());
+
+        timers.on_sent(KEY);
+        timers.on_acked(KEY);
+        assert!(timers.srtt.is_some());
+    }
+
+    #[test]
+    fn timeout_doubles_until_the_packet_is_lost() {
+        let mut timers = RetransmissionTimers::default();
+        timers.on_sent(KEY);
+        assert_eq!(timers.expired(), (Vec::new(), Vec::new()));
+
+        let mut timeout = INITIAL_RTO;
+        for _ in 0..MAX_RETRIES {
+            expire(&mut timers, KEY);
+            assert_eq!(timers.expired(), (vec![KEY], Vec::new()));
+
+            timeout = (timeout * 2).min(MAX_RTO);
+            assert_eq!(timers.pending[&KEY].timeout, timeout);
+        }
+        assert_eq!(timeout, MAX_RTO);
+
+        expire(&mut timers, KEY);
+        assert_eq!(timers.expired(), (Vec::new(), vec![KEY]));
+        assert!(timers.is_empty());
+    }
+}
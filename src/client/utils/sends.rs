@@ -1,9 +1,15 @@
 use crossbeam::channel::Sender;
 use packet_forge::MessageType;
-use wg_internal::{controller::DroneEvent, network::NodeId, packet::Packet};
+use wg_internal::{
+    controller::DroneEvent,
+    network::NodeId,
+    packet::{Packet, PacketType},
+};
 
 use crate::client::{FsmStatus, StateT};
 
+use super::congestion::flush_send_window;
+
 /// Send a `Packet` to a client and update the history
 pub fn send_packet(state: &StateT, sender: &Sender<Packet>, packet: &Packet) -> Result<(), String> {
     if let Err(e) = sender.send(packet.clone()) {
@@ -16,11 +22,12 @@ pub fn send_packet(state: &StateT, sender: &Sender<Packet>, packet: &Packet) ->
         ));
     }
 
-    // Update history
-    state.write().packets_history.insert(
-        (packet.get_fragment_index(), packet.session_id),
-        packet.clone(),
-    );
+    // Update history and start the retransmission timer of fragments
+    let key = (packet.get_fragment_index(), packet.session_id);
+    state.write().packets_history.insert(key, packet.clone());
+    if matches!(packet.pack_type, PacketType::MsgFragment(_)) {
+        state.write().retransmissions.on_sent(key);
+    }
 
     send_sc_packet(state, &DroneEvent::PacketSent(packet.clone()))?;
 
@@ -51,7 +58,8 @@ pub fn send_sc_packet(state: &StateT, drone_event: &DroneEvent) -> Result<(), St
 pub fn send_msg(state: &StateT, dest_id: NodeId, msg: MessageType) -> Result<(), String> {
     let source_id = state.read().id;
     let srh = state.write().routing_handler.best_path(source_id, dest_id);
-    // If srh is None, remove dest_id if is a server
+    // If srh is None, remove dest_id if is a server.
+    // Its videos stay in the catalog until a request to it fails.
     if srh.is_none() {
         state.write().servers.remove(&dest_id);
         let servers_is_empty = state.read().servers.is_empty();
@@ -68,23 +76,19 @@ pub fn send_msg(state: &StateT, dest_id: NodeId, msg: MessageType) -> Result<(),
         return Err(format!("[{}, {}] disassemble failed", file!(), line!()));
     };
 
-    // Get sender
+    // Check that the next hop is reachable
     let next_hop = srh.hops[1];
-    let sender = if let Some(s) = state.read().senders.get(&next_hop) {
-        s.clone()
-    } else {
+    if !state.read().senders.contains_key(&next_hop) {
         return Err(format!(
             "[{}, {}] Sender {dest_id} not found",
             file!(),
             line!()
         ));
-    };
-
-    for packet in packets {
-        send_packet(state, &sender, &packet)?;
     }
 
-    Ok(())
+    // Queue the packets and send the ones allowed by the window
+    state.write().send_windows.enqueue(dest_id, packets);
+    flush_send_window(state, dest_id)
 }
 
 /// Send an `Ack` to `sender_id`
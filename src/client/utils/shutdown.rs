@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+type CancelCallbackT = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: bool,
+    callbacks: Vec<CancelCallbackT>, // Wake the threads blocked waiting for work
+}
+
+struct ShutdownInner {
+    state: Mutex<CancelState>,
+    sender: watch::Sender<bool>, // Wakes the async tasks waiting in `cancelled`
+}
+
+/// Cancellation token shared by every background loop of the client
+#[derive(Clone)]
+pub(crate) struct ShutdownToken {
+    inner: Arc<ShutdownInner>,
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            inner: Arc::new(ShutdownInner {
+                state: Mutex::new(CancelState::default()),
+                sender,
+            }),
+        }
+    }
+}
+
+impl ShutdownToken {
+    /// Asks every loop to stop, can be called more than once
+    pub fn cancel(&self) {
+        let callbacks = {
+            let mut state = self.inner.state.lock();
+            state.cancelled = true;
+            std::mem::take(&mut state.callbacks)
+        };
+        self.inner.sender.send_replace(true);
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.state.lock().cancelled
+    }
+
+    /// Runs `callback` once the token is cancelled, right away if it already is
+    pub fn on_cancel(&self, callback: impl FnOnce() + Send + 'static) {
+        let mut state = self.inner.state.lock();
+        if state.cancelled {
+            drop(state);
+            callback();
+            return;
+        }
+        state.callbacks.push(Box::new(callback));
+    }
+
+    /// Completes once the token is cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.inner.sender.subscribe();
+        // Fails only if the sender is dropped, which cannot happen while `self` is alive
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+}
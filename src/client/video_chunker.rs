@@ -1,79 +1,308 @@
 use bytes::{Bytes, BytesMut};
-use std::io::{self, Cursor, Read};
+use std::io::{self, Read};
 
-pub struct VideoChunker {
-    data: Cursor<Vec<u8>>,
+/// Size of the stored chunks, shared by every client of the network.
+/// It cannot be negotiated: `ChunkRequest` has no field for a preferred size, and the
+/// chunk indexes, digests and seek indexes of the stored videos all depend on it.
+pub const CHUNK_SIZE: usize = 256 * 256;
+
+const BOX_HEADER_SIZE: usize = 8;
+const LARGE_BOX_HEADER_SIZE: usize = 16;
+const TOP_LEVEL_BOXES: [&[u8; 4]; 9] = [
+    b"ftyp", b"styp", b"moov", b"mdat", b"moof", b"mfra", b"free", b"skip", b"wide",
+];
+// Boxes that can start a media segment, a new chunk starts before them
+const SEGMENT_START_BOXES: [&[u8; 4]; 5] = [b"styp", b"sidx", b"prft", b"emsg", b"moof"];
+
+pub struct VideoChunker<R: Read> {
+    data: R,
     chunk_size: usize,
-    position: u64,
-    data_size: u64,
 }
 
-pub struct ChunkIterator {
-    chunker: VideoChunker,
+pub struct ChunkIterator<R: Read> {
+    chunker: VideoChunker<R>,
 }
 
-impl VideoChunker {
-    pub fn new(video_data: Vec<u8>, chunk_size: usize) -> Self {
-        let data_size = video_data.len() as u64;
+impl<R: Read> VideoChunker<R> {
+    pub fn new(video_data: R, chunk_size: usize) -> Self {
         VideoChunker {
-            data: Cursor::new(video_data),
+            data: video_data,
             chunk_size,
-            position: 0,
-            data_size,
         }
     }
 
     pub fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
-        if self.position >= self.data_size {
-            return Ok(None);
-        }
-
         let mut buffer = BytesMut::with_capacity(self.chunk_size);
         buffer.resize(self.chunk_size, 0);
 
-        self.data.set_position(self.position);
-        let bytes_read = self.data.read(&mut buffer)?;
+        // A single read may return less than a chunk before the end of the data
+        let bytes_read = read_up_to(&mut self.data, &mut buffer)?;
 
         if bytes_read == 0 {
             return Ok(None);
         }
 
         buffer.truncate(bytes_read);
-        self.position += bytes_read as u64;
-
         Ok(Some(buffer.freeze()))
     }
+}
+
+impl<R: Read> Iterator for ChunkIterator<R> {
+    type Item = io::Result<Bytes>;
 
-    pub fn reset(&mut self) {
-        self.position = 0;
-        self.data.set_position(0);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunker.next_chunk().transpose()
     }
 }
 
-impl Iterator for ChunkIterator {
-    type Item = Bytes;
+/// Reads until `buffer` is full or the data ends, returning the bytes read
+fn read_up_to<R: Read>(data: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        match data.read(&mut buffer[bytes_read..]) {
+            Ok(0) => break,
+            Ok(n) => bytes_read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(bytes_read)
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(Some(chunk)) = self.chunker.next_chunk() {
-            Some(chunk)
+/// Header of a top-level MP4 box
+struct BoxHeader {
+    box_type: [u8; 4],
+    bytes: Vec<u8>,
+    body_size: u64, // `u64::MAX` if the box extends to the end of the data
+}
+
+/// Splits an MP4 file at fragment boundaries:
+/// - the init segment (`ftyp` and `moov`) comes first, alone in its chunks
+/// - every media segment (`moof` and `mdat`) starts a new chunk
+/// - the `mdat` of a non-fragmented file starts a new chunk after the `moov`
+///
+/// so each segment can be appended to a `MediaSource` buffer on its own.
+/// Segments larger than `chunk_size` continue in the following chunks.
+/// Data that is not made of MP4 boxes is split at fixed offsets.
+pub struct Mp4Chunker<R: Read> {
+    data: R,
+    chunk_size: usize,
+    box_remaining: u64,       // Bytes of the current box not read yet
+    pending: Vec<u8>,         // Header read ahead, belonging to the next chunk
+    has_media: bool,          // The current segment contains a `moov` or a `mdat`
+    init_pending: bool,       // The `moov` was read but no media box after it yet
+    structured: Option<bool>, // Whether the data is made of MP4 boxes, known after the first box
+}
+
+impl<R: Read> Mp4Chunker<R> {
+    pub fn new(video_data: R, chunk_size: usize) -> Self {
+        Mp4Chunker {
+            data: video_data,
+            chunk_size,
+            box_remaining: 0,
+            pending: Vec::new(),
+            has_media: false,
+            init_pending: false,
+            structured: None,
+        }
+    }
+
+    /// Reads the header of the next box, `None` at the end of the data.
+    /// Returns the bytes read if they are not a valid header.
+    fn read_box_header(&mut self) -> io::Result<Option<Result<BoxHeader, Vec<u8>>>> {
+        let mut bytes = vec![0; BOX_HEADER_SIZE];
+        let bytes_read = read_up_to(&mut self.data, &mut bytes)?;
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        if bytes_read < BOX_HEADER_SIZE {
+            bytes.truncate(bytes_read);
+            return Ok(Some(Err(bytes)));
+        }
+
+        let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let box_type = [bytes[4], bytes[5], bytes[6], bytes[7]];
+        let box_size = match size {
+            0 => u64::MAX,
+            1 => {
+                bytes.resize(LARGE_BOX_HEADER_SIZE, 0);
+                let bytes_read = read_up_to(&mut self.data, &mut bytes[BOX_HEADER_SIZE..])?;
+                if bytes_read < LARGE_BOX_HEADER_SIZE - BOX_HEADER_SIZE {
+                    bytes.truncate(BOX_HEADER_SIZE + bytes_read);
+                    return Ok(Some(Err(bytes)));
+                }
+                let mut large_size = [0; 8];
+                large_size.copy_from_slice(&bytes[BOX_HEADER_SIZE..]);
+                u64::from_be_bytes(large_size)
+            }
+            size => u64::from(size),
+        };
+
+        let header_size = bytes.len() as u64;
+        if box_size < header_size {
+            return Ok(Some(Err(bytes)));
+        }
+        let body_size = if box_size == u64::MAX {
+            u64::MAX
         } else {
-            self.chunker.reset();
-            None
+            box_size - header_size
+        };
+
+        Ok(Some(Ok(BoxHeader {
+            box_type,
+            bytes,
+            body_size,
+        })))
+    }
+
+    pub fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let mut chunk = BytesMut::with_capacity(self.chunk_size);
+        chunk.extend_from_slice(&self.pending);
+        self.pending.clear();
+
+        while chunk.len() < self.chunk_size {
+            // Copy the body of the current box, or the raw data
+            if self.box_remaining > 0 || self.structured == Some(false) {
+                let free = self.chunk_size - chunk.len();
+                let to_read = match self.structured {
+                    Some(false) => free,
+                    _ => usize::try_from(self.box_remaining)
+                        .map_or(free, |remaining| remaining.min(free)),
+                };
+
+                let start = chunk.len();
+                chunk.resize(start + to_read, 0);
+                let bytes_read = read_up_to(&mut self.data, &mut chunk[start..])?;
+                chunk.truncate(start + bytes_read);
+                if bytes_read == 0 {
+                    break;
+                }
+                self.box_remaining = self.box_remaining.saturating_sub(bytes_read as u64);
+                continue;
+            }
+
+            let header = match self.read_box_header()? {
+                None => break,
+                Some(Ok(header)) => header,
+                Some(Err(bytes)) => {
+                    // Not an MP4 box, split the rest at fixed offsets
+                    self.structured = Some(false);
+                    chunk.extend_from_slice(&bytes);
+                    continue;
+                }
+            };
+
+            if self.structured.is_none() {
+                let structured = TOP_LEVEL_BOXES.contains(&&header.box_type);
+                self.structured = Some(structured);
+                if !structured {
+                    chunk.extend_from_slice(&header.bytes);
+                    continue;
+                }
+            }
+
+            // A new segment starts after the previous one carried media,
+            // and the media following the init segment always starts a new chunk
+            let is_media = header.box_type == *b"mdat" || header.box_type == *b"moof";
+            let segment_start =
+                SEGMENT_START_BOXES.contains(&&header.box_type) || (is_media && self.init_pending);
+            if is_media {
+                self.init_pending = false;
+            }
+            if segment_start && self.has_media {
+                self.has_media = false;
+                if !chunk.is_empty() {
+                    self.pending = header.bytes;
+                    self.box_remaining = header.body_size;
+                    return Ok(Some(chunk.freeze()));
+                }
+            }
+
+            if header.box_type == *b"moov" || header.box_type == *b"mdat" {
+                self.has_media = true;
+            }
+            if header.box_type == *b"moov" {
+                self.init_pending = true;
+            }
+            chunk.extend_from_slice(&header.bytes);
+            self.box_remaining = header.body_size;
+        }
+
+        if chunk.is_empty() {
+            return Ok(None);
         }
+        Ok(Some(chunk.freeze()))
     }
 }
 
-impl ExactSizeIterator for ChunkIterator {
-    fn len(&self) -> usize {
-        match usize::try_from(self.chunker.data_size) {
-            Ok(data_size) => data_size.div_ceil(self.chunker.chunk_size),
-            Err(_) => 0,
-        }
+pub struct Mp4ChunkIterator<R: Read> {
+    chunker: Mp4Chunker<R>,
+}
+
+impl<R: Read> Iterator for Mp4ChunkIterator<R> {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunker.next_chunk().transpose()
     }
 }
 
-pub fn get_video_chunks(video_data: Vec<u8>) -> ChunkIterator {
+/// Splits `video_data` in chunks of at most `CHUNK_SIZE`, starting a new chunk
+/// at each MP4 fragment boundary. Reads one chunk at a time.
+pub fn get_mp4_chunks<R: Read>(video_data: R) -> Mp4ChunkIterator<R> {
+    let chunker = Mp4Chunker::new(video_data, CHUNK_SIZE);
+    Mp4ChunkIterator { chunker }
+}
+
+/// Splits `video_data` in chunks of `CHUNK_SIZE`, reading one chunk at a time
+pub fn get_video_chunks<R: Read>(video_data: R) -> ChunkIterator<R> {
     // Create the chunker with a 65KB chunk size
-    let chunker = VideoChunker::new(video_data, 256 * 256);
+    let chunker = VideoChunker::new(video_data, CHUNK_SIZE);
     ChunkIterator { chunker }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(box_type: &[u8; 4], body_size: usize) -> Vec<u8> {
+        let mut data = u32::try_from(body_size + BOX_HEADER_SIZE)
+            .unwrap()
+            .to_be_bytes()
+            .to_vec();
+        data.extend_from_slice(box_type);
+        data.resize(body_size + BOX_HEADER_SIZE, 0);
+        data
+    }
+
+    fn chunk_sizes(data: &[u8]) -> Vec<usize> {
+        get_mp4_chunks(data)
+            .map(|chunk| chunk.unwrap().len())
+            .collect()
+    }
+
+    #[test]
+    fn non_fragmented_mdat_starts_a_new_chunk() {
+        let data = [
+            mp4_box(b"ftyp", 16),
+            mp4_box(b"moov", 100),
+            mp4_box(b"mdat", 500),
+        ]
+        .concat();
+        assert_eq!(chunk_sizes(&data), vec![24 + 108, 508]);
+    }
+
+    #[test]
+    fn every_fragment_starts_a_new_chunk() {
+        let data = [
+            mp4_box(b"ftyp", 16),
+            mp4_box(b"moov", 100),
+            mp4_box(b"moof", 20),
+            mp4_box(b"mdat", 200),
+            mp4_box(b"moof", 20),
+            mp4_box(b"mdat", 300),
+        ]
+        .concat();
+        assert_eq!(chunk_sizes(&data), vec![24 + 108, 28 + 208, 28 + 308]);
+    }
+}
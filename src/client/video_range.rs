@@ -0,0 +1,365 @@
+use std::io::Cursor;
+use std::time::Duration;
+
+use bytes::Bytes;
+use packet_forge::FileHash;
+use rocket::{
+    futures::stream,
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome},
+    response::{self, stream::ReaderStream, Responder},
+    Request, Response,
+};
+
+use super::{video_chunker::CHUNK_SIZE, ClientVideo};
+
+const DEFAULT_MIME_TYPE: &str = "video/mp4";
+const PROGRESSIVE_WAIT: Duration = Duration::from_millis(100); // Polling interval while downloading
+const PROGRESSIVE_TIMEOUT: Duration = Duration::from_secs(10); // Max wait for missing bytes
+const MAX_RANGE_LEN: usize = 16 * CHUNK_SIZE; // Max bytes of a partial response
+
+/// Value of the `Range` header, if present
+pub(crate) struct RangeHeader(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RangeHeader(
+            req.headers().get_one("Range").map(str::to_string),
+        ))
+    }
+}
+
+/// Chunks of a stored video, read from the db while the response is sent
+type ChunkIter = Box<dyn Iterator<Item = Result<Bytes, String>> + Send>;
+
+/// Response of the `/video/<video_id>` route
+pub(crate) enum VideoRange {
+    Full {
+        chunks: ChunkIter,
+        mime_type: String,
+    },
+    Partial {
+        data: Vec<u8>,
+        start: usize,
+        total: Option<usize>, // `None` while the video is still being downloaded
+        mime_type: String,
+    },
+    NotSatisfiable {
+        total: Option<usize>,
+    },
+    NotFound,
+}
+
+impl<'r> Responder<'r, 'static> for VideoRange {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let content_type = |mime_type: &str| {
+            ContentType::parse_flexible(mime_type).unwrap_or(ContentType::new("video", "mp4"))
+        };
+
+        match self {
+            VideoRange::Full { chunks, mime_type } => {
+                // Stop at the first chunk that cannot be read
+                let chunks = chunks.map_while(Result::ok).map(Cursor::new);
+                Response::build()
+                    .status(Status::Ok)
+                    .header(content_type(&mime_type))
+                    .raw_header("Accept-Ranges", "bytes")
+                    .streamed_body(ReaderStream::from(stream::iter(chunks)))
+                    .ok()
+            }
+            VideoRange::Partial {
+                data,
+                start,
+                total,
+                mime_type,
+            } => {
+                let end = start + data.len().saturating_sub(1);
+                let total = total.map_or_else(|| "*".to_string(), |t| t.to_string());
+
+                Response::build()
+                    .status(Status::PartialContent)
+                    .header(content_type(&mime_type))
+                    .raw_header("Accept-Ranges", "bytes")
+                    .raw_header("Content-Range", format!("bytes {start}-{end}/{total}"))
+                    .sized_body(data.len(), Cursor::new(data))
+                    .ok()
+            }
+            VideoRange::NotSatisfiable { total } => {
+                let mut response = Response::build();
+                response.status(Status::RangeNotSatisfiable);
+                if let Some(total) = total {
+                    response.raw_header("Content-Range", format!("bytes */{total}"));
+                }
+                response.ok()
+            }
+            VideoRange::NotFound => Response::build().status(Status::NotFound).ok(),
+        }
+    }
+}
+
+/// `Range` header of a request, checked against the size of the video
+#[derive(Debug, PartialEq, Eq)]
+enum ParsedRange {
+    Bytes(usize, Option<usize>), // Inclusive bounds, the end is `None` while the size is unknown
+    NotSatisfiable,              // Well formed, but starting past the end of the video
+    Ignored,                     // Malformed or multiple ranges, the whole video is sent
+}
+
+/// Parses a single `bytes=start-end` range, clamping its end to `len`.
+/// When `len` is `None` the size is unknown and suffix ranges are ignored.
+fn parse_range(header: &str, len: Option<usize>) -> ParsedRange {
+    let Some(range) = header.trim().strip_prefix("bytes=") else {
+        return ParsedRange::Ignored;
+    };
+
+    // Multiple ranges are not supported
+    if range.contains(',') {
+        return ParsedRange::Ignored;
+    }
+
+    let Some((start, end)) = range.split_once('-') else {
+        return ParsedRange::Ignored;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    // Suffix range: last `end` bytes
+    if start.is_empty() {
+        let (Some(len), Ok(suffix)) = (len, end.parse::<usize>()) else {
+            return ParsedRange::Ignored;
+        };
+        if suffix == 0 || len == 0 {
+            return ParsedRange::NotSatisfiable;
+        }
+        return ParsedRange::Bytes(len.saturating_sub(suffix), Some(len - 1));
+    }
+
+    let Ok(start) = start.parse::<usize>() else {
+        return ParsedRange::Ignored;
+    };
+    let end = match end {
+        "" => None,
+        end => match end.parse::<usize>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return ParsedRange::Ignored,
+        },
+    };
+
+    match len {
+        Some(len) if start >= len => ParsedRange::NotSatisfiable,
+        Some(len) => ParsedRange::Bytes(start, Some(end.map_or(len - 1, |end| end.min(len - 1)))),
+        None => ParsedRange::Bytes(start, end),
+    }
+}
+
+/// Returns the end of a range starting at `start`, limited to `MAX_RANGE_LEN` bytes.
+/// The player requests the following bytes as it plays.
+fn capped_end(start: usize, end: Option<usize>) -> usize {
+    let max_end = start.saturating_add(MAX_RANGE_LEN - 1);
+    end.map_or(max_end, |end| end.min(max_end))
+}
+
+impl ClientVideo {
+    /// Serves the requested range of a video stored in the db, reading only the needed chunks
+    fn get_stored_video_range(&self, video_id: FileHash, range: Option<&str>) -> VideoRange {
+        let Ok(content_info) = self.db.get_video_content_info(video_id) else {
+            return VideoRange::NotFound;
+        };
+        let Ok(total) = usize::try_from(content_info.size) else {
+            return VideoRange::NotFound;
+        };
+
+        let mime_type = self
+            .db
+            .get_video_metadata(video_id)
+            .map_or_else(|_| DEFAULT_MIME_TYPE.to_string(), |m| m.mime_type);
+
+        let parsed = range.map_or(ParsedRange::Ignored, |range| {
+            parse_range(range, Some(total))
+        });
+        let (start, end) = match parsed {
+            ParsedRange::Bytes(start, Some(end)) => (start, capped_end(start, Some(end))),
+            ParsedRange::NotSatisfiable => {
+                return VideoRange::NotSatisfiable { total: Some(total) };
+            }
+            ParsedRange::Bytes(_, None) | ParsedRange::Ignored => {
+                return VideoRange::Full {
+                    chunks: Box::new(self.db.get_video_chunks(video_id, 0)),
+                    mime_type,
+                };
+            }
+        };
+
+        match self.db.read_video_range(video_id, start, end) {
+            Ok(data) => VideoRange::Partial {
+                data,
+                start,
+                total: Some(total),
+                mime_type,
+            },
+            Err(_) => VideoRange::NotFound,
+        }
+    }
+
+    /// Serves the requested range of the video being downloaded, waiting for
+    /// the first requested byte to arrive
+    async fn get_downloading_video_range(
+        &self,
+        video_id: FileHash,
+        range: Option<&str>,
+    ) -> VideoRange {
+        // Ranges that cannot be served while the size is unknown start from the first byte
+        let (start, end) = match range.map(|range| parse_range(range, None)) {
+            Some(ParsedRange::Bytes(start, end)) => (start, end),
+            _ => (0, None),
+        };
+
+        let mime_type = self
+            .state
+            .read()
+            .videos_metadata
+            .get(&video_id)
+            .map_or_else(|| DEFAULT_MIME_TYPE.to_string(), |m| m.mime_type.clone());
+
+        let mut waited = Duration::ZERO;
+        loop {
+            {
+                let sessions = self.playback_sessions.read();
+                let Some(session) = sessions.downloading_full(video_id) else {
+                    // The download completed meanwhile
+                    drop(sessions);
+                    return self.get_stored_video_range(video_id, range);
+                };
+
+                if let Some(data) = session.read_bytes(start, Some(capped_end(start, end))) {
+                    return VideoRange::Partial {
+                        data,
+                        start,
+                        total: None,
+                        mime_type,
+                    };
+                }
+            }
+
+            if waited >= PROGRESSIVE_TIMEOUT {
+                return VideoRange::NotSatisfiable { total: None };
+            }
+            tokio::time::sleep(PROGRESSIVE_WAIT).await;
+            waited += PROGRESSIVE_WAIT;
+        }
+    }
+
+    /// Returns the requested range of a video, either from the db or progressively
+    /// from the chunks received so far
+    pub(crate) async fn get_video_range(
+        &self,
+        video_id: FileHash,
+        range: RangeHeader,
+    ) -> VideoRange {
+        let range = range.0.as_deref();
+
+        let is_downloading = self
+            .playback_sessions
+            .read()
+            .downloading_full(video_id)
+            .is_some();
+        if is_downloading {
+            return self.get_downloading_video_range(video_id, range).await;
+        }
+
+        self.get_stored_video_range(video_id, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capped_end, parse_range, ParsedRange, MAX_RANGE_LEN};
+
+    #[test]
+    fn parses_bounded_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-99", Some(1000)),
+            ParsedRange::Bytes(0, Some(99))
+        );
+        assert_eq!(
+            parse_range(" bytes=10 - 20 ", Some(1000)),
+            ParsedRange::Bytes(10, Some(20))
+        );
+        assert_eq!(
+            parse_range("bytes=0-5000", Some(1000)),
+            ParsedRange::Bytes(0, Some(999))
+        );
+    }
+
+    #[test]
+    fn parses_open_ranges() {
+        assert_eq!(
+            parse_range("bytes=500-", Some(1000)),
+            ParsedRange::Bytes(500, Some(999))
+        );
+        assert_eq!(
+            parse_range("bytes=500-", None),
+            ParsedRange::Bytes(500, None)
+        );
+        assert_eq!(
+            parse_range("bytes=10-20", None),
+            ParsedRange::Bytes(10, Some(20))
+        );
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(
+            parse_range("bytes=-100", Some(1000)),
+            ParsedRange::Bytes(900, Some(999))
+        );
+        assert_eq!(
+            parse_range("bytes=-2000", Some(1000)),
+            ParsedRange::Bytes(0, Some(999))
+        );
+        assert_eq!(parse_range("bytes=-100", None), ParsedRange::Ignored);
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(
+            parse_range("bytes=1000-", Some(1000)),
+            ParsedRange::NotSatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=1500-2000", Some(1000)),
+            ParsedRange::NotSatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=-0", Some(1000)),
+            ParsedRange::NotSatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=-10", Some(0)),
+            ParsedRange::NotSatisfiable
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_ranges() {
+        assert_eq!(parse_range("bytes=20-10", Some(1000)), ParsedRange::Ignored);
+        assert_eq!(
+            parse_range("bytes=0-1,5-6", Some(1000)),
+            ParsedRange::Ignored
+        );
+        assert_eq!(parse_range("items=0-1", Some(1000)), ParsedRange::Ignored);
+        assert_eq!(parse_range("bytes=a-b", Some(1000)), ParsedRange::Ignored);
+        assert_eq!(parse_range("bytes=10", Some(1000)), ParsedRange::Ignored);
+        assert_eq!(parse_range("bytes=-x", Some(1000)), ParsedRange::Ignored);
+    }
+
+    #[test]
+    fn caps_long_ranges() {
+        assert_eq!(capped_end(10, Some(20)), 20);
+        assert_eq!(capped_end(0, None), MAX_RANGE_LEN - 1);
+        assert_eq!(capped_end(100, Some(usize::MAX)), 100 + MAX_RANGE_LEN - 1);
+        assert_eq!(capped_end(usize::MAX, None), usize::MAX);
+    }
+}
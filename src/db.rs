@@ -1,2 +1,7 @@
+pub mod ids;
+pub mod library;
+pub mod mp4;
 pub mod queries;
+pub mod search;
 pub mod structures;
+pub mod sync;
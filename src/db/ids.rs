@@ -0,0 +1,347 @@
+use packet_forge::FileHash;
+use sha2::{Digest, Sha256};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::Transactional;
+
+use super::structures::VideoDb;
+
+/// Max number of re-hashes tried before giving up on a free id
+const MAX_REHASH_ATTEMPTS: u32 = 1024;
+
+/// Wide identifier of a video. Unlike the 16 bit `FileHash` sent on the wire,
+/// two different videos never share it.
+pub(crate) type VideoKey = [u8; 32];
+
+/// Key of a video imported from the local library, derived from the digest of its file,
+/// so a video keeps its id when its file is moved or renamed
+pub(crate) fn local_video_key(digest: &[u8; 32]) -> VideoKey {
+    Sha256::new()
+        .chain_update("local:")
+        .chain_update(digest)
+        .finalize()
+        .into()
+}
+
+/// Key of a video downloaded from the network, which can only be known by its id
+pub(crate) fn remote_video_key(id: FileHash) -> VideoKey {
+    Sha256::digest(format!("remote:{id}")).into()
+}
+
+/// Id derived from `key` at the given re-hash `attempt`
+fn rehash(key: &VideoKey, attempt: u32) -> FileHash {
+    let digest = Sha256::new()
+        .chain_update(key)
+        .chain_update(attempt.to_be_bytes())
+        .finalize();
+    FileHash::from_be_bytes([digest[0], digest[1]])
+}
+
+/// Result of assigning an id to a video
+pub(crate) enum IdAssignment {
+    /// The video owns `id`. `previous` is the id it owned before, if it changed.
+    Assigned {
+        id: FileHash,
+        previous: Option<FileHash>,
+    },
+    /// The requested id belongs to another video, nothing was changed
+    Conflict { id: FileHash },
+}
+
+/// Trees mapping the video keys to their ids, changed together in a transaction
+struct IdTrees<'a> {
+    ids: &'a TransactionalTree,    // `video_ids_tree`
+    owners: &'a TransactionalTree, // `video_id_owners_tree`
+}
+
+type IdTxResult<T> = ConflictableTransactionResult<T, String>;
+
+fn abort<T>(err: String) -> IdTxResult<T> {
+    Err(ConflictableTransactionError::Abort(err))
+}
+
+impl IdTrees<'_> {
+    fn key_id(&self, key: &VideoKey) -> IdTxResult<Option<FileHash>> {
+        match self.ids.get(key)? {
+            None => Ok(None),
+            Some(data) => match data.as_ref().try_into() {
+                Ok(bytes) => Ok(Some(FileHash::from_be_bytes(bytes))),
+                Err(_) => abort("Invalid video id in database".to_string()),
+            },
+        }
+    }
+
+    fn id_owner(&self, id: FileHash) -> IdTxResult<Option<VideoKey>> {
+        match self.owners.get(id.to_be_bytes())? {
+            None => Ok(None),
+            Some(data) => match data.as_ref().try_into() {
+                Ok(key) => Ok(Some(key)),
+                Err(_) => abort("Invalid video key in database".to_string()),
+            },
+        }
+    }
+
+    fn is_id_free(&self, id: FileHash, key: &VideoKey) -> IdTxResult<bool> {
+        let owner = self.id_owner(id)?;
+        Ok(id != 0 && owner.is_none_or(|owner| owner == *key))
+    }
+
+    /// Makes `key` the owner of `id`, freeing the id `key` owned before
+    fn set_owner(&self, key: &VideoKey, id: FileHash) -> IdTxResult<()> {
+        if let Some(previous) = self.key_id(key)? {
+            self.owners.remove(&previous.to_be_bytes())?;
+        }
+        if let Some(previous_owner) = self.id_owner(id)? {
+            self.ids.remove(&previous_owner)?;
+        }
+        self.owners.insert(&id.to_be_bytes(), key.as_slice())?;
+        self.ids
+            .insert(key.as_slice(), id.to_be_bytes().as_slice())?;
+        Ok(())
+    }
+}
+
+impl VideoDb {
+    /// Runs `f` on the id trees in a single transaction
+    fn with_id_trees<T>(&self, f: impl Fn(&IdTrees) -> IdTxResult<T>) -> Result<T, String> {
+        (&self.video_ids_tree, &self.video_id_owners_tree)
+            .transaction(|(ids, owners)| f(&IdTrees { ids, owners }))
+            .map_err(|e| match e {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(e) => format!("Error accessing database: {e}"),
+            })
+    }
+
+    /// Returns the id owned by the video identified by `key`, if any
+    pub(super) fn get_video_key_id(&self, key: &VideoKey) -> Result<Option<FileHash>, String> {
+        self.with_id_trees(|trees| trees.key_id(key))
+    }
+
+    /// Returns the key of the video owning `id`, if any
+    pub(super) fn get_video_id_owner(&self, id: FileHash) -> Result<Option<VideoKey>, String> {
+        self.with_id_trees(|trees| trees.id_owner(id))
+    }
+
+    /// Assigns an id to the video identified by `key` and records the mapping.
+    /// - `requested`: id chosen by the video, e.g. set in the JSON manifest or by the network.
+    ///   It is never changed, a conflict is returned if another video owns it.
+    /// - `derived`: id to try first when none is requested, usually the compact hash
+    ///   of the metadata. On collision, ids derived from `key` are tried.
+    ///
+    /// A video keeps the id it got in the previous runs, unless it requests another one.
+    pub(super) fn assign_video_id(
+        &self,
+        key: &VideoKey,
+        requested: Option<FileHash>,
+        derived: FileHash,
+    ) -> Result<IdAssignment, String> {
+        self.with_id_trees(|trees| {
+            let current = trees.key_id(key)?;
+
+            let id = match (requested, current) {
+                (Some(id), _) if !trees.is_id_free(id, key)? => {
+                    return Ok(IdAssignment::Conflict { id });
+                }
+                (Some(id), _) | (None, Some(id)) => id,
+                (None, None) => {
+                    let mut candidates = std::iter::once(derived)
+                        .chain((0..MAX_REHASH_ATTEMPTS).map(|i| rehash(key, i)));
+                    loop {
+                        let Some(id) = candidates.next() else {
+                            return abort("No free video id left".to_string());
+                        };
+                        if trees.is_id_free(id, key)? {
+                            break id;
+                        }
+                    }
+                }
+            };
+
+            if current == Some(id) {
+                return Ok(IdAssignment::Assigned { id, previous: None });
+            }
+            trees.set_owner(key, id)?;
+            Ok(IdAssignment::Assigned {
+                id,
+                previous: current,
+            })
+        })
+    }
+
+    /// Gives `id` to the video identified by `key`, e.g. a file whose content changed.
+    /// The video owning `id` before loses it.
+    pub(super) fn transfer_video_id(&self, id: FileHash, key: &VideoKey) -> Result<(), String> {
+        self.with_id_trees(|trees| trees.set_owner(key, id))
+    }
+
+    /// Frees `id`, so it can be assigned to another video
+    pub(super) fn release_video_id(&self, id: FileHash) -> Result<(), String> {
+        self.with_id_trees(|trees| {
+            if let Some(key) = trees.id_owner(id)? {
+                trees.ids.remove(key.as_slice())?;
+                trees.owners.remove(&id.to_be_bytes())?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use packet_forge::{Metadata, VideoMetaData};
+
+    use super::*;
+
+    fn key(n: u8) -> VideoKey {
+        local_video_key(&[n; 32])
+    }
+
+    fn assigned(assignment: IdAssignment) -> (FileHash, Option<FileHash>) {
+        match assignment {
+            IdAssignment::Assigned { id, previous } => (id, previous),
+            IdAssignment::Conflict { id } => panic!("unexpected conflict on id {id}"),
+        }
+    }
+
+    #[test]
+    fn keys_depend_on_the_origin() {
+        assert_eq!(key(1), key(1));
+        assert_ne!(key(1), key(2));
+        assert_eq!(remote_video_key(7), remote_video_key(7));
+        assert_ne!(remote_video_key(7), remote_video_key(8));
+    }
+
+    #[test]
+    fn derived_id_is_kept_by_its_video() {
+        let db = VideoDb::temporary();
+        assert_eq!(
+            assigned(db.assign_video_id(&key(1), None, 42).unwrap()),
+            (42, None)
+        );
+        assert_eq!(
+            assigned(db.assign_video_id(&key(1), None, 7).unwrap()),
+            (42, None)
+        );
+        assert_eq!(db.get_video_key_id(&key(1)).unwrap(), Some(42));
+        assert_eq!(db.get_video_id_owner(42).unwrap(), Some(key(1)));
+    }
+
+    #[test]
+    fn colliding_ids_are_rehashed() {
+        let db = VideoDb::temporary();
+        assigned(db.assign_video_id(&key(1), None, 42).unwrap());
+        let (id, _) = assigned(db.assign_video_id(&key(2), None, 42).unwrap());
+        assert_eq!(id, rehash(&key(2), 0));
+
+        // Id 0 is never assigned
+        let (id, _) = assigned(db.assign_video_id(&key(3), None, 0).unwrap());
+        assert_ne!(id, 0);
+    }
+
+    #[test]
+    fn same_metadata_gets_different_ids() {
+        let metadata = VideoMetaData {
+            id: 0,
+            title: "Same video".to_string(),
+            description: String::new(),
+            duration: 10,
+            mime_type: "video/mp4".to_string(),
+            created_at: String::new(),
+        };
+        let derived = metadata.compact_hash_u16();
+
+        let db = VideoDb::temporary();
+        let (first, _) = assigned(db.assign_video_id(&key(1), None, derived).unwrap());
+        let (second, _) = assigned(db.assign_video_id(&key(2), None, derived).unwrap());
+        assert_ne!(first, second);
+        assert!(first != 0 && second != 0);
+    }
+
+    #[test]
+    fn requested_id_of_another_video_is_a_conflict() {
+        let db = VideoDb::temporary();
+        assigned(db.assign_video_id(&key(1), Some(42), 0).unwrap());
+        let (id, _) = assigned(db.assign_video_id(&key(2), None, 7).unwrap());
+
+        assert!(matches!(
+            db.assign_video_id(&key(2), Some(42), 0).unwrap(),
+            IdAssignment::Conflict { id: 42 }
+        ));
+        // Nothing changed
+        assert_eq!(db.get_video_key_id(&key(2)).unwrap(), Some(id));
+        assert_eq!(db.get_video_id_owner(42).unwrap(), Some(key(1)));
+
+        // A free requested id replaces the previous one
+        assert_eq!(
+            assigned(db.assign_video_id(&key(2), Some(100), 0).unwrap()),
+            (100, Some(id))
+        );
+        assert_eq!(db.get_video_id_owner(id).unwrap(), None);
+    }
+
+    #[test]
+    fn transfer_and_release_update_both_trees() {
+        let db = VideoDb::temporary();
+        assigned(db.assign_video_id(&key(1), Some(42), 0).unwrap());
+        assigned(db.assign_video_id(&key(2), Some(7), 0).unwrap());
+
+        db.transfer_video_id(42, &key(2)).unwrap();
+        assert_eq!(db.get_video_key_id(&key(1)).unwrap(), None);
+        assert_eq!(db.get_video_key_id(&key(2)).unwrap(), Some(42));
+        assert_eq!(db.get_video_id_owner(7).unwrap(), None);
+
+        db.release_video_id(42).unwrap();
+        assert_eq!(db.get_video_key_id(&key(2)).unwrap(), None);
+        assert_eq!(db.get_video_id_owner(42).unwrap(), None);
+    }
+
+    #[test]
+    fn concurrent_assignments_get_different_ids() {
+        let db = VideoDb::temporary();
+        let ids: HashSet<FileHash> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|n| {
+                    let db = &db;
+                    scope.spawn(move || assigned(db.assign_video_id(&key(n), None, 42).unwrap()).0)
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        assert_eq!(ids.len(), 8);
+        for n in 0..8 {
+            let id = db.get_video_key_id(&key(n)).unwrap().unwrap();
+            assert_eq!(db.get_video_id_owner(id).unwrap(), Some(key(n)));
+        }
+    }
+
+    #[test]
+    fn ids_are_kept_across_runs() {
+        let path = std::env::temp_dir().join(format!("client-video-ids-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let path_str = path.to_string_lossy().to_string();
+
+        let id = {
+            let db = VideoDb::new(&path_str);
+            assigned(db.assign_video_id(&key(1), None, 42).unwrap());
+            let (id, _) = assigned(db.assign_video_id(&key(2), None, 42).unwrap());
+            db.flush().unwrap();
+            id
+        };
+
+        let db = VideoDb::new(&path_str);
+        assert_eq!(db.get_video_key_id(&key(1)).unwrap(), Some(42));
+        assert_eq!(
+            assigned(db.assign_video_id(&key(2), None, 9).unwrap()),
+            (id, None)
+        );
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use packet_forge::VideoMetaData;
+
+const VIDEOS_FOLDER: &str = "videos";
+const PATH_FIELD: &str = "path"; // Optional field of the JSON entries with the video file path
+
+/// Container extensions accepted by the importer and their mime type
+const VIDEO_EXTENSIONS: [(&str, &str); 6] = [
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("ogv", "video/ogg"),
+];
+
+/// Video of the local library with the file it is stored in
+#[derive(Clone)]
+pub(crate) struct LocalVideo {
+    pub metadata: VideoMetaData,
+    pub path: PathBuf,
+}
+
+/// Videos found in the local library and the files that could not be read
+#[derive(Default)]
+pub(crate) struct LibraryScan {
+    pub videos: Vec<LocalVideo>,
+    pub failed: Vec<PathBuf>, // Kept in the db, they may be readable again on the next run
+    pub errors: Vec<String>,
+}
+
+fn mime_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    VIDEO_EXTENSIONS
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+/// Path used by the JSON entries without an explicit path
+fn legacy_video_path(local_path: &str, metadata: &VideoMetaData) -> PathBuf {
+    let video_title_parsed = metadata.title.replace(' ', "").to_lowercase();
+    Path::new(local_path)
+        .join(VIDEOS_FOLDER)
+        .join(format!("{video_title_parsed}.mp4"))
+}
+
+/// Loads the videos listed in a JSON manifest.
+/// Each entry can set its file with a `path` relative to `local_path`.
+fn load_json_manifest(local_path: &str, json_file_path: &Path) -> Result<Vec<LocalVideo>, String> {
+    let json_array = "videos";
+
+    let file_content = std::fs::read_to_string(json_file_path).map_err(|e| {
+        format!(
+            "Error reading file {}: {e}",
+            json_file_path.to_string_lossy()
+        )
+    })?;
+
+    let json_data: serde_json::Value =
+        serde_json::from_str(&file_content).map_err(|e| format!("Error parsing JSON: {e}"))?;
+
+    let videos_array = json_data[json_array]
+        .as_array()
+        .ok_or_else(|| format!("Invalid JSON: '{json_array}' is not an array"))?;
+
+    videos_array
+        .iter()
+        .map(|video| {
+            let mut video = video.clone();
+            let path = video
+                .as_object_mut()
+                .and_then(|entry| entry.remove(PATH_FIELD))
+                .and_then(|path| path.as_str().map(|path| Path::new(local_path).join(path)));
+
+            let metadata: VideoMetaData =
+                serde_json::from_value(video).map_err(|e| format!("Invalid video data: {e}"))?;
+            let path = path.unwrap_or_else(|| legacy_video_path(local_path, &metadata));
+            Ok(LocalVideo { metadata, path })
+        })
+        .collect()
+}
+
+/// Collects the files with a video extension inside `folder` and its subfolders.
+/// Symlinked subfolders are skipped, they may point to one of their parents.
+fn scan_folder(folder: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = std::fs::read_dir(folder)
+        .map_err(|e| format!("Error reading folder {}: {e}", folder.to_string_lossy()))?;
+
+    for entry in entries {
+        let entry =
+            entry.map_err(|e| format!("Error reading folder {}: {e}", folder.to_string_lossy()))?;
+        let path = entry.path();
+        if path.is_dir() {
+            let is_symlink = entry
+                .file_type()
+                .is_ok_and(|file_type| file_type.is_symlink());
+            if !is_symlink {
+                scan_folder(&path, files)?;
+            }
+        } else if mime_type(&path).is_some() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Turns a file name like `dancing_pirate` into a title like `dancing pirate`
+fn title_from_file_name(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+
+    stem.split(['_', '-', '.', ' '])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Builds the metadata of a video from its file name and stats
+fn metadata_from_file(path: &Path) -> Result<VideoMetaData, String> {
+    let stats = std::fs::metadata(path)
+        .map_err(|e| format!("Error reading video file {}: {e}", path.to_string_lossy()))?;
+
+    // Creation time is not available on every filesystem
+    let created_at = stats
+        .created()
+        .or_else(|_| stats.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_secs());
+
+    Ok(VideoMetaData {
+        id: 0,
+        title: title_from_file_name(path),
+        description: String::new(),
+        duration: 0,
+        mime_type: mime_type(path).unwrap_or("video/mp4").to_string(),
+        created_at: created_at.to_string(),
+    })
+}
+
+/// Lists the videos of the local library:
+/// - the ones described in the JSON manifest `file_video_name`, if present
+/// - every other file with a video extension inside the `videos` folder
+pub(crate) fn scan_library(
+    local_path: &str,
+    file_video_name: Option<&str>,
+) -> Result<LibraryScan, String> {
+    let videos = match file_video_name {
+        Some(file_name) if Path::new(local_path).join(file_name).is_file() => {
+            load_json_manifest(local_path, &Path::new(local_path).join(file_name))?
+        }
+        _ => Vec::new(),
+    };
+    let mut scan = LibraryScan {
+        videos,
+        ..LibraryScan::default()
+    };
+
+    let videos_folder = Path::new(local_path).join(VIDEOS_FOLDER);
+    if !videos_folder.is_dir() {
+        return Ok(scan);
+    }
+
+    let mut files = Vec::new();
+    scan_folder(&videos_folder, &mut files)?;
+    files.sort();
+
+    // The manifest entries take precedence over the metadata built from the file
+    let listed: HashSet<PathBuf> = scan.videos.iter().map(|video| video.path.clone()).collect();
+    for path in files {
+        if listed.contains(&path) {
+            continue;
+        }
+
+        // A file that cannot be read does not stop the import of the others
+        match metadata_from_file(&path) {
+            Ok(metadata) => scan.videos.push(LocalVideo { metadata, path }),
+            Err(err) => {
+                scan.errors.push(err);
+                scan.failed.push(path);
+            }
+        }
+    }
+    Ok(scan)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Library folder deleted when dropped
+    pub(in crate::db) struct TestLibrary {
+        pub dir: PathBuf,
+    }
+
+    impl TestLibrary {
+        pub fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "client-video-library-{name}-{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self { dir }
+        }
+
+        /// Writes a file at `path`, relative to the library folder
+        pub fn write(&self, path: &str, content: &[u8]) -> PathBuf {
+            let path = self.dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+
+        fn scan(&self, file_video_name: Option<&str>) -> LibraryScan {
+            scan_library(&self.dir.to_string_lossy(), file_video_name).unwrap()
+        }
+    }
+
+    impl Drop for TestLibrary {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn titles(scan: &LibraryScan) -> Vec<&str> {
+        scan.videos
+            .iter()
+            .map(|video| video.metadata.title.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn titles_come_from_file_names() {
+        assert_eq!(
+            title_from_file_name(Path::new("videos/dancing_pirate-2.mp4")),
+            "dancing pirate 2"
+        );
+        assert_eq!(title_from_file_name(Path::new("__.webm")), "");
+    }
+
+    #[test]
+    fn scan_finds_the_videos_of_every_subfolder() {
+        let library = TestLibrary::new("scan");
+        library.write("videos/b_movie.MP4", b"b");
+        library.write("videos/clips/a-clip.webm", b"a");
+        library.write("videos/notes.txt", b"not a video");
+        library.write("other.mp4", b"outside the videos folder");
+
+        let scan = library.scan(None);
+        assert_eq!(titles(&scan), vec!["b movie", "a clip"]);
+        assert_eq!(scan.videos[0].metadata.mime_type, "video/mp4");
+        assert_eq!(scan.videos[1].metadata.mime_type, "video/webm");
+        assert!(scan.failed.is_empty() && scan.errors.is_empty());
+    }
+
+    #[test]
+    fn scan_without_videos_folder_is_empty() {
+        let library = TestLibrary::new("empty");
+        assert!(library.scan(Some("videos.json")).videos.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_skips_symlinked_folders() {
+        let library = TestLibrary::new("symlink");
+        library.write("videos/clips/clip.mkv", b"clip");
+        std::os::unix::fs::symlink(
+            library.dir.join("videos"),
+            library.dir.join("videos/clips/loop"),
+        )
+        .unwrap();
+
+        let scan = library.scan(None);
+        assert_eq!(titles(&scan), vec!["clip"]);
+    }
+
+    #[test]
+    fn manifest_entries_take_precedence_over_the_files() {
+        let library = TestLibrary::new("manifest");
+        let listed = library.write("videos/listed.mp4", b"listed");
+        let legacy = library.write("videos/legacyvideo.mp4", b"legacy");
+        library.write("videos/unlisted.mov", b"unlisted");
+        library.write(
+            "videos.json",
+            br#"{"videos": [
+                {"id": 3, "title": "Listed", "description": "From the manifest", "duration": 10,
+                 "mime_type": "video/mp4", "created_at": "", "path": "videos/listed.mp4"},
+                {"id": 0, "title": "Legacy Video", "description": "", "duration": 0,
+                 "mime_type": "video/mp4", "created_at": ""}
+            ]}"#,
+        );
+
+        let scan = library.scan(Some("videos.json"));
+        assert_eq!(titles(&scan), vec!["Listed", "Legacy Video", "unlisted"]);
+        assert_eq!(scan.videos[0].path, listed);
+        assert_eq!(scan.videos[0].metadata.id, 3);
+        assert_eq!(scan.videos[1].path, legacy);
+        assert_eq!(scan.videos[2].metadata.mime_type, "video/quicktime");
+    }
+
+    #[test]
+    fn invalid_manifest_is_an_error() {
+        let library = TestLibrary::new("invalid");
+        library.write("videos.json", br#"{"videos": {}}"#);
+        assert!(scan_library(&library.dir.to_string_lossy(), Some("videos.json")).is_err());
+    }
+}
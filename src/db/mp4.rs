@@ -0,0 +1,748 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use serde::{Deserialize, Serialize};
+
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024; // Larger movie boxes are rejected
+const TOP_LEVEL_BOXES: [&[u8; 4]; 9] = [
+    b"ftyp", b"styp", b"moov", b"mdat", b"moof", b"mfra", b"free", b"skip", b"wide",
+];
+
+// Boxes that can start a media segment
+const SEGMENT_START_BOXES: [&[u8; 4]; 5] = [b"styp", b"sidx", b"prft", b"emsg", b"moof"];
+
+type FourCC = [u8; 4];
+
+/// Media properties read from the `moov` box of an MP4 file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MediaInfo {
+    pub duration: f64, // Seconds
+    pub width: u32,
+    pub height: u32,
+    pub codecs: Vec<String>, // RFC 6381 codec of each track
+    pub bitrate: u64,        // Average bits per second
+}
+
+impl MediaInfo {
+    /// Mime type with the codecs parameter, as expected by `MediaSource`
+    pub fn mime_type(&self, container_mime_type: &str) -> String {
+        let container = container_mime_type
+            .split(';')
+            .next()
+            .unwrap_or(container_mime_type)
+            .trim();
+        if self.codecs.is_empty() {
+            return container.to_string();
+        }
+        format!("{container}; codecs=\"{}\"", self.codecs.join(", "))
+    }
+}
+
+/// Whether the mime type is an ISO base media container that can be parsed
+pub(crate) fn is_mp4_mime_type(mime_type: &str) -> bool {
+    ["video/mp4", "audio/mp4", "video/quicktime"]
+        .iter()
+        .any(|mp4| mime_type.trim().starts_with(mp4))
+}
+
+/// Iterates over the boxes contained in `data`
+struct BoxIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for BoxIter<'a> {
+    type Item = Result<(FourCC, &'a [u8]), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let res = parse_box(self.data);
+        match res {
+            Ok((box_type, body, box_size)) => {
+                self.data = &self.data[box_size..];
+                Some(Ok((box_type, body)))
+            }
+            Err(err) => {
+                self.data = &[];
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Splits the first box of `data`, returning its type, its body and its total size
+fn parse_box(data: &[u8]) -> Result<(FourCC, &[u8], usize), String> {
+    let size = read_u32(data, 0)?;
+    let box_type = read_fourcc(data, 4)?;
+
+    let (header_size, box_size) = match size {
+        0 => (8, data.len()),
+        1 => {
+            let large_size =
+                usize::try_from(read_u64(data, 8)?).map_err(|_| "Box too large".to_string())?;
+            (16, large_size)
+        }
+        size => (8, size as usize),
+    };
+
+    if box_size < header_size || box_size > data.len() {
+        return Err(format!(
+            "Invalid size {box_size} of box '{}'",
+            String::from_utf8_lossy(&box_type)
+        ));
+    }
+    Ok((box_type, &data[header_size..box_size], box_size))
+}
+
+fn boxes(data: &[u8]) -> BoxIter<'_> {
+    BoxIter { data }
+}
+
+/// Returns the body of the first child box of type `box_type`
+fn find_box<'a>(data: &'a [u8], box_type: &FourCC) -> Result<Option<&'a [u8]>, String> {
+    for entry in boxes(data) {
+        let (child_type, body) = entry?;
+        if child_type == *box_type {
+            return Ok(Some(body));
+        }
+    }
+    Ok(None)
+}
+
+/// Follows a path of nested boxes
+fn find_path<'a>(data: &'a [u8], path: &[&FourCC]) -> Result<Option<&'a [u8]>, String> {
+    let mut current = data;
+    for box_type in path {
+        match find_box(current, box_type)? {
+            Some(body) => current = body,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(current))
+}
+
+fn read_bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    data.get(offset..offset + len)
+        .ok_or_else(|| "Unexpected end of box".to_string())
+}
+
+fn read_u8(data: &[u8], offset: usize) -> Result<u8, String> {
+    Ok(read_bytes(data, offset, 1)?[0])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = read_bytes(data, offset, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+    let high = u64::from(read_u32(data, offset)?);
+    let low = u64::from(read_u32(data, offset + 4)?);
+    Ok((high << 32) | low)
+}
+
+fn read_fourcc(data: &[u8], offset: usize) -> Result<FourCC, String> {
+    let bytes = read_bytes(data, offset, 4)?;
+    Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Reads (timescale, duration) from a `mvhd` or `mdhd` body
+fn parse_header_duration(body: &[u8]) -> Result<(u32, u64), String> {
+    match read_u8(body, 0)? {
+        1 => Ok((read_u32(body, 20)?, read_u64(body, 24)?)),
+        _ => Ok((read_u32(body, 12)?, u64::from(read_u32(body, 16)?))),
+    }
+}
+
+/// Reads the track dimensions from a `tkhd` body, stored as 16.16 fixed point
+fn parse_tkhd(body: &[u8]) -> Result<(u32, u32), String> {
+    let offset = match read_u8(body, 0)? {
+        1 => 88,
+        _ => 76,
+    };
+    Ok((
+        read_u32(body, offset)? >> 16,
+        read_u32(body, offset + 4)? >> 16,
+    ))
+}
+
+/// Reads the length of an MPEG-4 descriptor, returning it with the bytes it used
+fn parse_descriptor_len(data: &[u8], offset: usize) -> Result<(usize, usize), String> {
+    let mut len = 0;
+    for i in 0..4 {
+        let byte = read_u8(data, offset + i)?;
+        len = (len << 7) | usize::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Ok((len, i + 1));
+        }
+    }
+    Ok((len, 4))
+}
+
+/// Builds the `mp4a.<object type>.<audio object type>` codec from an `esds` body
+fn parse_esds(body: &[u8]) -> Result<Option<String>, String> {
+    // ES_Descriptor
+    let mut offset = 4;
+    if read_u8(body, offset)? != 0x03 {
+        return Ok(None);
+    }
+    let (_, len_size) = parse_descriptor_len(body, offset + 1)?;
+    offset += 1 + len_size + 2;
+    let flags = read_u8(body, offset)?;
+    offset += 1;
+    if flags & 0x80 != 0 {
+        offset += 2;
+    }
+    if flags & 0x40 != 0 {
+        offset += 1 + usize::from(read_u8(body, offset)?);
+    }
+    if flags & 0x20 != 0 {
+        offset += 2;
+    }
+
+    // DecoderConfigDescriptor
+    if read_u8(body, offset)? != 0x04 {
+        return Ok(None);
+    }
+    let (_, len_size) = parse_descriptor_len(body, offset + 1)?;
+    offset += 1 + len_size;
+    let object_type = read_u8(body, offset)?;
+    offset += 13;
+
+    // DecoderSpecificInfo, missing for some object types
+    if read_u8(body, offset).ok() != Some(0x05) {
+        return Ok(Some(format!("mp4a.{object_type:x}")));
+    }
+    let (_, len_size) = parse_descriptor_len(body, offset + 1)?;
+    offset += 1 + len_size;
+    let audio_object_type = read_u8(body, offset)? >> 3;
+    Ok(Some(format!("mp4a.{object_type:x}.{audio_object_type}")))
+}
+
+/// Builds the codec of the first sample entry of a `stsd` body
+fn parse_stsd(body: &[u8]) -> Result<Option<String>, String> {
+    if read_u32(body, 4)? == 0 {
+        return Ok(None);
+    }
+
+    let Some(entry) = boxes(&body[8..]).next() else {
+        return Ok(None);
+    };
+    let (entry_type, entry_body) = entry?;
+    let fourcc = String::from_utf8_lossy(&entry_type).to_string();
+
+    match &entry_type {
+        b"avc1" | b"avc3" => {
+            // The visual sample entry header is followed by the `avcC` box
+            let avcc = entry_body
+                .get(78..)
+                .map(|children| find_box(children, b"avcC"))
+                .transpose()?
+                .flatten();
+            match avcc {
+                Some(avcc) => Ok(Some(format!(
+                    "{fourcc}.{:02X}{:02X}{:02X}",
+                    read_u8(avcc, 1)?,
+                    read_u8(avcc, 2)?,
+                    read_u8(avcc, 3)?
+                ))),
+                None => Ok(Some(fourcc)),
+            }
+        }
+        b"mp4a" => {
+            // The audio sample entry header is followed by the `esds` box
+            let esds = entry_body
+                .get(28..)
+                .map(|children| find_box(children, b"esds"))
+                .transpose()?
+                .flatten();
+            match esds {
+                Some(esds) => Ok(parse_esds(esds)?.or(Some(fourcc))),
+                None => Ok(Some(fourcc)),
+            }
+        }
+        _ => Ok(Some(fourcc)),
+    }
+}
+
+/// Reads the track id from a `tkhd` body
+fn parse_track_id(tkhd: &[u8]) -> Result<u32, String> {
+    match read_u8(tkhd, 0)? {
+        1 => read_u32(tkhd, 20),
+        _ => read_u32(tkhd, 12),
+    }
+}
+
+/// Extracts the media properties from the body of the `moov` box
+fn parse_moov(moov: &[u8], file_size: u64) -> Result<MediaInfo, String> {
+    let mvhd = find_box(moov, b"mvhd")?.ok_or_else(|| "Missing 'mvhd' box".to_string())?;
+    let (timescale, mut duration) = parse_header_duration(mvhd)?;
+    if timescale == 0 {
+        return Err("Invalid timescale 0".to_string());
+    }
+
+    // Fragmented files store the duration in the movie extends header
+    if duration == 0 {
+        if let Some(mehd) = find_path(moov, &[b"mvex", b"mehd"])? {
+            duration = match read_u8(mehd, 0)? {
+                1 => read_u64(mehd, 4)?,
+                _ => u64::from(read_u32(mehd, 4)?),
+            };
+        }
+    }
+
+    let mut info = MediaInfo {
+        duration: duration as f64 / f64::from(timescale),
+        width: 0,
+        height: 0,
+        codecs: Vec::new(),
+        bitrate: 0,
+    };
+
+    let mut n_tracks = 0;
+    for entry in boxes(moov) {
+        let (box_type, trak) = entry?;
+        if box_type != *b"trak" {
+            continue;
+        }
+        n_tracks += 1;
+
+        let handler = find_path(trak, &[b"mdia", b"hdlr"])?
+            .map(|hdlr| read_fourcc(hdlr, 8))
+            .transpose()?;
+        if handler == Some(*b"vide") {
+            if let Some(tkhd) = find_box(trak, b"tkhd")? {
+                (info.width, info.height) = parse_tkhd(tkhd)?;
+            }
+        }
+
+        if let Some(stsd) = find_path(trak, &[b"mdia", b"minf", b"stbl", b"stsd"])? {
+            if let Some(codec) = parse_stsd(stsd)? {
+                info.codecs.push(codec);
+            }
+        }
+    }
+
+    if n_tracks == 0 {
+        return Err("No track found".to_string());
+    }
+    if info.duration > 0.0 {
+        info.bitrate = (file_size as f64 * 8.0 / info.duration).round() as u64;
+    }
+    Ok(info)
+}
+
+/// Parses the box structure of an MP4 file, reading only the `moov` box in memory.
+/// Returns an error if the file is not a valid MP4.
+pub(crate) fn parse_mp4<R: Read + Seek>(reader: &mut R) -> Result<MediaInfo, String> {
+    let file_size = reader
+        .seek(SeekFrom::End(0))
+        .map_err(|e| format!("Error reading file: {e}"))?;
+    reader
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| format!("Error reading file: {e}"))?;
+
+    let mut offset = 0;
+    let mut moov = None;
+    while offset < file_size {
+        let mut header = [0; 16];
+        let header_len = if file_size - offset >= 16 { 16 } else { 8 };
+        reader
+            .read_exact(&mut header[..header_len])
+            .map_err(|e| format!("Invalid box header at {offset}: {e}"))?;
+
+        let box_type = read_fourcc(&header, 4)?;
+        if offset == 0 && !TOP_LEVEL_BOXES.contains(&&box_type) {
+            return Err("Not an MP4 file".to_string());
+        }
+
+        let (header_size, box_size) = match read_u32(&header, 0)? {
+            0 => (8, file_size - offset),
+            1 => (16, read_u64(&header, 8)?),
+            size => (8, u64::from(size)),
+        };
+        if box_size < header_size || box_size > file_size - offset {
+            return Err(format!(
+                "Invalid size {box_size} of box '{}'",
+                String::from_utf8_lossy(&box_type)
+            ));
+        }
+
+        if box_type == *b"moov" {
+            if box_size > MAX_MOOV_SIZE {
+                return Err(format!("'moov' box of {box_size} bytes is too large"));
+            }
+            let mut body = vec![0; (box_size - header_size) as usize];
+            reader
+                .seek(SeekFrom::Start(offset + header_size))
+                .and_then(|_| reader.read_exact(&mut body))
+                .map_err(|e| format!("Error reading 'moov' box: {e}"))?;
+            moov = Some(body);
+        }
+
+        offset += box_size;
+        reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| format!("Error reading file: {e}"))?;
+    }
+
+    let moov = moov.ok_or_else(|| "Missing 'moov' box".to_string())?;
+    parse_moov(&moov, file_size)
+}
+
+/// Chunk from which playback can start at `time`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SeekPoint {
+    pub time: f64, // Seconds
+    pub chunk_index: u32,
+}
+
+/// Fragments of a fragmented MP4 with their start time, built when the video is stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SeekIndex {
+    pub init_chunks: u32, // Chunks of the init segment, needed by every playback
+    pub points: Vec<SeekPoint>, // Sorted by time
+}
+
+impl SeekIndex {
+    /// Returns the chunks to skip to start playing at `time`, `None` to play from the beginning
+    pub fn skipped_chunks(&self, time: f64) -> Option<std::ops::Range<u32>> {
+        let start = self
+            .points
+            .iter()
+            .take_while(|point| point.time <= time)
+            .last()?
+            .chunk_index;
+
+        if start <= self.init_chunks {
+            return None;
+        }
+        Some(self.init_chunks..start)
+    }
+}
+
+/// Reads the start time in seconds of the media segment contained in `chunk`
+fn parse_segment_time(
+    chunk: &[u8],
+    tracks: &HashMap<u32, u32>,
+    video_track: Option<u32>,
+) -> Option<f64> {
+    // The chunk may end inside the `mdat`, the `moof` always comes first
+    let moof = boxes(chunk)
+        .map_while(Result::ok)
+        .find(|(box_type, _)| box_type == b"moof")
+        .map(|(_, body)| body)?;
+
+    let mut times = Vec::new();
+    for (box_type, traf) in boxes(moof).map_while(Result::ok) {
+        if box_type != *b"traf" {
+            continue;
+        }
+        let (Ok(Some(tfhd)), Ok(Some(tfdt))) = (find_box(traf, b"tfhd"), find_box(traf, b"tfdt"))
+        else {
+            continue;
+        };
+        let Ok(track_id) = read_u32(tfhd, 4) else {
+            continue;
+        };
+        let decode_time = match read_u8(tfdt, 0) {
+            Ok(1) => read_u64(tfdt, 4).ok(),
+            Ok(_) => read_u32(tfdt, 4).ok().map(u64::from),
+            Err(_) => None,
+        };
+        if let (Some(decode_time), Some(timescale)) = (decode_time, tracks.get(&track_id)) {
+            times.push((track_id, decode_time as f64 / f64::from(*timescale)));
+        }
+    }
+
+    // Prefer the video track, playback starts from its keyframes
+    times
+        .iter()
+        .find(|(track_id, _)| Some(*track_id) == video_track)
+        .or(times.first())
+        .map(|(_, time)| *time)
+}
+
+/// Builds the `SeekIndex` of a fragmented MP4 while its chunks are stored
+#[derive(Default)]
+pub(crate) struct SeekIndexBuilder {
+    init: Vec<u8>,             // Init segment, until the first media segment
+    tracks: HashMap<u32, u32>, // track id -> timescale
+    video_track: Option<u32>,
+    init_chunks: Option<u32>,
+    points: Vec<SeekPoint>,
+}
+
+impl SeekIndexBuilder {
+    /// Reads the timescale of every track from the init segment
+    fn parse_init(&mut self) {
+        let Ok(Some(moov)) = find_box(&self.init, b"moov") else {
+            return;
+        };
+
+        for (box_type, trak) in boxes(moov).map_while(Result::ok) {
+            if box_type != *b"trak" {
+                continue;
+            }
+            let track_id = find_box(trak, b"tkhd")
+                .ok()
+                .flatten()
+                .and_then(|tkhd| parse_track_id(tkhd).ok());
+            let timescale = find_path(trak, &[b"mdia", b"mdhd"])
+                .ok()
+                .flatten()
+                .and_then(|mdhd| parse_header_duration(mdhd).ok())
+                .map(|(timescale, _)| timescale)
+                .filter(|timescale| *timescale > 0);
+            let handler = find_path(trak, &[b"mdia", b"hdlr"])
+                .ok()
+                .flatten()
+                .and_then(|hdlr| read_fourcc(hdlr, 8).ok());
+
+            if let (Some(track_id), Some(timescale)) = (track_id, timescale) {
+                self.tracks.insert(track_id, timescale);
+                if handler == Some(*b"vide") && self.video_track.is_none() {
+                    self.video_track = Some(track_id);
+                }
+            }
+        }
+    }
+
+    pub fn add_chunk(&mut self, chunk_index: u32, chunk: &[u8]) {
+        let segment_start =
+            read_fourcc(chunk, 4).is_ok_and(|box_type| SEGMENT_START_BOXES.contains(&&box_type));
+
+        if self.init_chunks.is_none() {
+            if !segment_start {
+                // Not a fragmented file if the init segment is too large
+                if self.init.len() as u64 <= MAX_MOOV_SIZE {
+                    self.init.extend_from_slice(chunk);
+                }
+                return;
+            }
+            self.init_chunks = Some(chunk_index);
+            self.parse_init();
+            self.init = Vec::new();
+        }
+
+        if segment_start {
+            if let Some(time) = parse_segment_time(chunk, &self.tracks, self.video_track) {
+                self.points.push(SeekPoint { time, chunk_index });
+            }
+        }
+    }
+
+    /// Returns the index, `None` if the video has no media segment
+    pub fn finish(mut self) -> Option<SeekIndex> {
+        let init_chunks = self.init_chunks?;
+        if self.points.is_empty() {
+            return None;
+        }
+
+        self.points.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Some(SeekIndex {
+            init_chunks,
+            points: self.points,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn mp4_box(box_type: &FourCC, body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// Box with its size in the 64-bit `largesize` field
+    fn large_box(box_type: &FourCC, body: &[u8]) -> Vec<u8> {
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(&((body.len() + 16) as u64).to_be_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// Box with a raw `size` field, whatever the length of its body
+    fn sized_box(size: u32, box_type: &FourCC, body: &[u8]) -> Vec<u8> {
+        let mut data = size.to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn container(box_type: &FourCC, children: &[Vec<u8>]) -> Vec<u8> {
+        mp4_box(box_type, &children.concat())
+    }
+
+    /// `mvhd` or `mdhd` body of version 0
+    fn header(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut body = vec![0; 20];
+        body[12..16].copy_from_slice(&timescale.to_be_bytes());
+        body[16..20].copy_from_slice(&duration.to_be_bytes());
+        body
+    }
+
+    fn tkhd(track_id: u32, width: u32, height: u32) -> Vec<u8> {
+        let mut body = vec![0; 84];
+        body[12..16].copy_from_slice(&track_id.to_be_bytes());
+        body[76..80].copy_from_slice(&(width << 16).to_be_bytes());
+        body[80..84].copy_from_slice(&(height << 16).to_be_bytes());
+        mp4_box(b"tkhd", &body)
+    }
+
+    fn hdlr(handler: &FourCC) -> Vec<u8> {
+        let mut body = vec![0; 12];
+        body[8..12].copy_from_slice(handler);
+        mp4_box(b"hdlr", &body)
+    }
+
+    fn stsd(entry: Vec<u8>) -> Vec<u8> {
+        let mut body = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        body.extend_from_slice(&entry);
+        mp4_box(b"stsd", &body)
+    }
+
+    /// `avc1` sample entry of the High profile, level 3.1
+    fn avc1() -> Vec<u8> {
+        let mut body = vec![0; 78];
+        body.extend_from_slice(&mp4_box(b"avcC", &[1, 0x64, 0x00, 0x1F, 0xFF]));
+        mp4_box(b"avc1", &body)
+    }
+
+    /// `mp4a` sample entry of AAC LC
+    fn mp4a() -> Vec<u8> {
+        let mut esds = vec![0, 0, 0, 0];
+        esds.extend_from_slice(&[0x03, 25, 0, 1, 0]); // ES_Descriptor
+        esds.extend_from_slice(&[0x04, 17, 0x40]); // DecoderConfigDescriptor
+        esds.extend_from_slice(&[0; 12]);
+        esds.extend_from_slice(&[0x05, 2, 0x12, 0x10]); // DecoderSpecificInfo
+        let mut body = vec![0; 28];
+        body.extend_from_slice(&mp4_box(b"esds", &esds));
+        mp4_box(b"mp4a", &body)
+    }
+
+    fn trak(tkhd: Vec<u8>, handler: &FourCC, entry: Vec<u8>) -> Vec<u8> {
+        let stbl = container(b"stbl", &[stsd(entry)]);
+        let minf = container(b"minf", &[stbl]);
+        let mdia = container(
+            b"mdia",
+            &[mp4_box(b"mdhd", &header(1000, 0)), hdlr(handler), minf],
+        );
+        container(b"trak", &[tkhd, mdia])
+    }
+
+    /// `moov` of a 10 seconds video with a 1280x720 H.264 track and an AAC track
+    fn moov() -> Vec<u8> {
+        container(
+            b"moov",
+            &[
+                mp4_box(b"mvhd", &header(1000, 10_000)),
+                trak(tkhd(1, 1280, 720), b"vide", avc1()),
+                trak(tkhd(2, 0, 0), b"soun", mp4a()),
+            ],
+        )
+    }
+
+    fn ftyp() -> Vec<u8> {
+        mp4_box(b"ftyp", b"isom\0\0\0\0isomavc1")
+    }
+
+    fn parse(file: &[u8]) -> Result<MediaInfo, String> {
+        parse_mp4(&mut Cursor::new(file))
+    }
+
+    #[test]
+    fn parses_the_tracks_of_the_moov() {
+        let file = [ftyp(), moov(), mp4_box(b"mdat", &[0; 100])].concat();
+        let info = parse(&file).unwrap();
+        assert_eq!(info.duration, 10.0);
+        assert_eq!((info.width, info.height), (1280, 720));
+        assert_eq!(info.codecs, vec!["avc1.64001F", "mp4a.40.2"]);
+        assert_eq!(info.bitrate, (file.len() * 8 / 10) as u64);
+        assert_eq!(
+            info.mime_type("video/mp4"),
+            "video/mp4; codecs=\"avc1.64001F, mp4a.40.2\""
+        );
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let file = [ftyp(), moov(), mp4_box(b"mdat", &[0; 100])].concat();
+        let moov_end = ftyp().len() + moov().len();
+
+        // A file cut between two boxes is still valid
+        for len in 0..file.len() {
+            let res = parse(&file[..len]);
+            assert_eq!(res.is_ok(), len == moov_end, "file cut at {len}");
+        }
+    }
+
+    #[test]
+    fn truncated_boxes_are_errors() {
+        let moov = moov();
+        let body = &moov[8..];
+        for len in 0..body.len() {
+            // Cut inside a child box, never past the end of the data
+            let _ = parse_moov(&body[..len], 0);
+        }
+        assert!(parse_moov(&body[..body.len() - 1], 0).is_err());
+        assert!(parse_box(&[0, 0, 0]).is_err());
+        assert!(parse_box(&[0, 0, 0, 16, b'f', b'r', b'e', b'e']).is_err());
+    }
+
+    #[test]
+    fn large_size_boxes_are_parsed() {
+        let data = large_box(b"free", b"data");
+        let (box_type, body, size) = parse_box(&data).unwrap();
+        assert_eq!((&box_type, body, size), (b"free", &b"data"[..], 20));
+
+        let file = [ftyp(), large_box(b"mdat", &[0; 10]), moov()].concat();
+        assert_eq!(parse(&file).unwrap().duration, 10.0);
+    }
+
+    #[test]
+    fn size_zero_box_runs_to_the_end() {
+        let data = sized_box(0, b"mdat", b"data");
+        let (box_type, body, size) = parse_box(&data).unwrap();
+        assert_eq!((&box_type, body, size), (b"mdat", &b"data"[..], 12));
+
+        let file = [ftyp(), moov(), sized_box(0, b"mdat", &[0; 10])].concat();
+        assert!(parse(&file).is_ok());
+
+        // The last box is the `moov` itself
+        let file = [ftyp(), sized_box(0, b"moov", &moov()[8..])].concat();
+        assert_eq!(parse(&file).unwrap().codecs.len(), 2);
+    }
+
+    #[test]
+    fn malformed_sizes_are_errors() {
+        let moov = moov();
+        let malformed = [
+            sized_box(4, b"mdat", &[0; 8]),             // Smaller than its header
+            sized_box(1000, b"mdat", &[0; 8]),          // Past the end of the file
+            sized_box(1, b"mdat", &8u64.to_be_bytes()), // Large size smaller than its header
+            sized_box(1, b"mdat", &u64::MAX.to_be_bytes()),
+        ];
+        for mdat in malformed {
+            let file = [ftyp(), mdat.clone(), moov.clone()].concat();
+            assert!(parse(&file).is_err());
+            assert!(boxes(&mdat).all(|entry| entry.is_err()));
+        }
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(parse(&mp4_box(b"RIFF", &[0; 16])).is_err());
+        assert!(parse(&[ftyp(), mp4_box(b"mdat", &[0; 16])].concat()).is_err());
+        assert!(parse(&[]).is_err());
+    }
+}
@@ -1,6 +1,12 @@
+use bytes::Bytes;
 use packet_forge::{FileHash, VideoMetaData};
 
-use super::structures::VideoDb;
+use super::{
+    mp4::{MediaInfo, SeekIndex},
+    structures::{
+        chunk_key, offset_key, VideoContentInfo, VideoDb, VideoDigests, VideoDigestsBuilder,
+    },
+};
 
 impl VideoDb {
     pub(crate) fn get_video_list(&self) -> Vec<VideoMetaData> {
@@ -17,12 +23,150 @@ impl VideoDb {
             .collect()
     }
 
-    /// Retrieves video payload from the database by ID.
-    pub(crate) fn get_video_content(&self, id: FileHash) -> Result<Vec<u8>, String> {
-        self.content_tree
+    /// Retrieves video metadata from the database by ID.
+    pub(crate) fn get_video_metadata(&self, id: FileHash) -> Result<VideoMetaData, String> {
+        let data = self
+            .metadata_tree
+            .get(id.to_be_bytes())
+            .map_err(|e| format!("Error accessing database: {e}"))?
+            .ok_or_else(|| "Video metadata not found".to_string())?;
+
+        bincode::deserialize::<VideoMetaData>(&data)
+            .map_err(|e| format!("Deserialization error: {e}"))
+    }
+
+    /// Retrieves the size and the number of chunks of a video payload by ID.
+    pub(crate) fn get_video_content_info(&self, id: FileHash) -> Result<VideoContentInfo, String> {
+        let data = self
+            .content_info_tree
             .get(id.to_be_bytes())
             .map_err(|e| format!("Error accessing database: {e}"))?
-            .map(|data| data.to_vec())
-            .ok_or_else(|| "Video payload not found".to_string())
+            .ok_or_else(|| "Video payload not found".to_string())?;
+
+        bincode::deserialize::<VideoContentInfo>(&data)
+            .map_err(|e| format!("Deserialization error: {e}"))
+    }
+
+    /// Retrieves the digests of a video payload and of its chunks by ID.
+    /// Videos stored before the digests were kept get them computed and stored once.
+    pub(crate) fn get_video_digests(&self, id: FileHash) -> Result<VideoDigests, String> {
+        let data = self
+            .digests_tree
+            .get(id.to_be_bytes())
+            .map_err(|e| format!("Error accessing database: {e}"))?;
+        if let Some(data) = data {
+            return bincode::deserialize::<VideoDigests>(&data)
+                .map_err(|e| format!("Deserialization error: {e}"));
+        }
+
+        // Only videos with a stored payload have digests
+        self.get_video_content_info(id)?;
+        let mut digests = VideoDigestsBuilder::default();
+        for chunk in self.get_video_chunks(id, 0) {
+            digests.add_chunk(&chunk?);
+        }
+        let digests = digests.finish();
+        self.insert_video_digests(id, &digests)?;
+        Ok(digests)
+    }
+
+    /// Retrieves the properties parsed from the MP4 boxes of a local video by ID.
+    pub(crate) fn get_video_media_info(&self, id: FileHash) -> Result<MediaInfo, String> {
+        let data = self
+            .media_tree
+            .get(id.to_be_bytes())
+            .map_err(|e| format!("Error accessing database: {e}"))?
+            .ok_or_else(|| "Video media info not found".to_string())?;
+
+        bincode::deserialize::<MediaInfo>(&data).map_err(|e| format!("Deserialization error: {e}"))
+    }
+
+    /// Retrieves the fragment start times of a fragmented MP4 video by ID.
+    pub(crate) fn get_video_seek_index(&self, id: FileHash) -> Result<SeekIndex, String> {
+        let data = self
+            .seek_tree
+            .get(id.to_be_bytes())
+            .map_err(|e| format!("Error accessing database: {e}"))?
+            .ok_or_else(|| "Video seek index not found".to_string())?;
+
+        bincode::deserialize::<SeekIndex>(&data).map_err(|e| format!("Deserialization error: {e}"))
+    }
+
+    /// Retrieves a single chunk of a video payload.
+    pub(crate) fn get_video_chunk(&self, id: FileHash, chunk_index: u32) -> Result<Bytes, String> {
+        self.content_tree
+            .get(chunk_key(id, chunk_index))
+            .map_err(|e| format!("Error accessing database: {e}"))?
+            .map(|data| Bytes::from(data.to_vec()))
+            .ok_or_else(|| format!("Chunk {chunk_index} of video {id} not found"))
+    }
+
+    /// Lazily iterates over the chunks of a video payload, starting from `start_index`.
+    /// Each chunk is read from the database only when requested.
+    pub(crate) fn get_video_chunks(
+        &self,
+        id: FileHash,
+        start_index: u32,
+    ) -> impl Iterator<Item = Result<Bytes, String>> {
+        self.content_tree
+            .range(chunk_key(id, start_index)..=chunk_key(id, u32::MAX))
+            .values()
+            .map(|entry| {
+                entry
+                    .map(|data| Bytes::from(data.to_vec()))
+                    .map_err(|e| format!("Error accessing database: {e}"))
+            })
+    }
+
+    /// Returns the index and the offset of the chunk containing the byte at `offset`
+    fn find_chunk_at(&self, id: FileHash, offset: u64) -> Result<(u32, u64), String> {
+        let (key, index) = self
+            .content_offsets_tree
+            .range(offset_key(id, 0)..=offset_key(id, offset))
+            .next_back()
+            .ok_or_else(|| format!("Offset {offset} of video {id} not found"))?
+            .map_err(|e| format!("Error accessing database: {e}"))?;
+
+        let id_len = id.to_be_bytes().len();
+        let chunk_offset = key
+            .get(id_len..)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes);
+        let chunk_index = index.as_ref().try_into().ok().map(u32::from_be_bytes);
+        match (chunk_index, chunk_offset) {
+            (Some(chunk_index), Some(chunk_offset)) => Ok((chunk_index, chunk_offset)),
+            _ => Err(format!("Invalid offset entry of video {id}")),
+        }
+    }
+
+    /// Reads the bytes in `start..=end` of a video payload, loading only the chunks containing them.
+    pub(crate) fn read_video_range(
+        &self,
+        id: FileHash,
+        start: usize,
+        end: usize,
+    ) -> Result<Vec<u8>, String> {
+        let (first_chunk, chunk_offset) = self.find_chunk_at(id, start as u64)?;
+        let mut offset = usize::try_from(chunk_offset)
+            .map_err(|e| format!("Invalid offset {chunk_offset}: {e}"))?;
+
+        let mut data = Vec::with_capacity(end + 1 - start);
+        for chunk in self.get_video_chunks(id, first_chunk) {
+            let chunk = chunk?;
+            let chunk_end = offset + chunk.len();
+
+            let from = start.saturating_sub(offset);
+            if from >= chunk.len() {
+                break;
+            }
+            let to = (end + 1 - offset).min(chunk.len());
+            data.extend_from_slice(&chunk[from..to]);
+
+            if chunk_end > end {
+                break;
+            }
+            offset = chunk_end;
+        }
+        Ok(data)
     }
 }
@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use packet_forge::{FileHash, VideoMetaData};
+use serde::Serialize;
+
+use super::structures::VideoDb;
+
+/// Max number of videos returned by a single query
+pub(crate) const MAX_PAGE_SIZE: usize = 100;
+
+/// Field the results of a query are sorted by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum VideoSort {
+    #[default]
+    Title,
+    Duration,
+    CreatedAt,
+    Id,
+}
+
+impl FromStr for VideoSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "title" => Ok(Self::Title),
+            "duration" => Ok(Self::Duration),
+            "created_at" => Ok(Self::CreatedAt),
+            "id" => Ok(Self::Id),
+            _ => Err(format!("Invalid sort field '{s}'")),
+        }
+    }
+}
+
+/// Filters, order and page of a search in the local library.
+/// Every filter is optional, the results must match all of them.
+#[derive(Debug, Clone)]
+pub(crate) struct VideoQuery {
+    pub title: Option<String>,     // Case insensitive substring of the title
+    pub mime_type: Option<String>, // Prefix of the container mime type, e.g. `video/` or `video/mp4`
+    pub min_duration: Option<u64>, // Seconds
+    pub max_duration: Option<u64>, // Seconds
+    pub sort: VideoSort,
+    pub descending: bool,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Page of the videos matching a query
+#[derive(Debug, Serialize)]
+pub(crate) struct VideoPage {
+    pub total: usize, // Matching videos, in every page
+    pub offset: usize,
+    pub limit: usize,
+    pub videos: Vec<VideoMetaData>,
+}
+
+/// Separates the indexed value from the video id inside the index keys
+const KEY_SEPARATOR: u8 = 0;
+
+/// Key of an index entry: indexed value followed by the video id, so entries are
+/// sorted by value and two videos with the same value do not overwrite each other
+fn index_key(value: &[u8], id: FileHash) -> Vec<u8> {
+    let mut key = value.to_vec();
+    key.push(KEY_SEPARATOR);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Video id stored at the end of an index key
+fn id_from_index_key(key: &[u8]) -> Option<FileHash> {
+    let id = key.get(key.len().checked_sub(2)?..)?;
+    Some(FileHash::from_be_bytes(id.try_into().ok()?))
+}
+
+/// Indexed value at the start of an index key
+fn value_from_index_key(key: &[u8]) -> &[u8] {
+    &key[..key.len().saturating_sub(3)]
+}
+
+fn title_value(metadata: &VideoMetaData) -> Vec<u8> {
+    metadata.title.to_lowercase().into_bytes()
+}
+
+/// Container mime type, without the codecs parameter
+fn mime_value(mime_type: &str) -> Vec<u8> {
+    let container = mime_type.split(';').next().unwrap_or(mime_type);
+    container.trim().to_lowercase().into_bytes()
+}
+
+fn duration_value(metadata: &VideoMetaData) -> Vec<u8> {
+    (metadata.duration as u64).to_be_bytes().to_vec()
+}
+
+/// Creation dates stored as Unix timestamps are sorted by value, before the other formats
+fn created_at_value(metadata: &VideoMetaData) -> Vec<u8> {
+    match metadata.created_at.trim().parse::<u64>() {
+        Ok(timestamp) => [&[0], timestamp.to_be_bytes().as_slice()].concat(),
+        Err(_) => [&[1], metadata.created_at.as_bytes()].concat(),
+    }
+}
+
+impl VideoDb {
+    /// Index trees of the library and the key of `metadata` in each of them
+    fn index_entries(&self, metadata: &VideoMetaData) -> [(&sled::Tree, Vec<u8>); 4] {
+        let id = metadata.id;
+        [
+            (
+                &self.title_index_tree,
+                index_key(&title_value(metadata), id),
+            ),
+            (
+                &self.mime_index_tree,
+                index_key(&mime_value(&metadata.mime_type), id),
+            ),
+            (
+                &self.duration_index_tree,
+                index_key(&duration_value(metadata), id),
+            ),
+            (
+                &self.created_at_index_tree,
+                index_key(&created_at_value(metadata), id),
+            ),
+        ]
+    }
+
+    /// Adds a video to the index trees
+    pub(super) fn index_video(&self, metadata: &VideoMetaData) -> Result<(), String> {
+        for (tree, key) in self.index_entries(metadata) {
+            tree.insert(key, &[])
+                .map_err(|e| format!("Error inserting video index: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Removes a video from the index trees
+    pub(super) fn unindex_video(&self, metadata: &VideoMetaData) -> Result<(), String> {
+        for (tree, key) in self.index_entries(metadata) {
+            tree.remove(key)
+                .map_err(|e| format!("Error removing video index: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Ids of the videos whose index key matches `filter`, scanning only `entries`
+    fn filter_index(
+        entries: sled::Iter,
+        filter: impl Fn(&[u8]) -> bool,
+    ) -> Result<HashSet<FileHash>, String> {
+        let mut ids = HashSet::new();
+        for key in entries.keys() {
+            let key = key.map_err(|e| format!("Error accessing database: {e}"))?;
+            if filter(&key) {
+                ids.extend(id_from_index_key(&key));
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Ids of every video, sorted by `sort`
+    fn sorted_ids(&self, sort: VideoSort) -> Result<Vec<FileHash>, String> {
+        // The keys of `metadata_tree` are the ids alone, which is also the end of an index key
+        let tree = match sort {
+            VideoSort::Title => &self.title_index_tree,
+            VideoSort::Duration => &self.duration_index_tree,
+            VideoSort::CreatedAt => &self.created_at_index_tree,
+            VideoSort::Id => &self.metadata_tree,
+        };
+
+        let mut ids = Vec::new();
+        for key in tree.iter().keys() {
+            let key = key.map_err(|e| format!("Error accessing database: {e}"))?;
+            ids.extend(id_from_index_key(&key));
+        }
+        Ok(ids)
+    }
+
+    /// Searches the local library using the index trees.
+    /// The metadata is read only for the videos of the requested page.
+    pub(crate) fn search_videos(&self, query: &VideoQuery) -> Result<VideoPage, String> {
+        let mut filters = Vec::new();
+        if let Some(title) = &query.title {
+            let title = title.to_lowercase();
+            filters.push(Self::filter_index(self.title_index_tree.iter(), |key| {
+                String::from_utf8_lossy(value_from_index_key(key)).contains(&title)
+            })?);
+        }
+        if let Some(mime_type) = &query.mime_type {
+            filters.push(Self::filter_index(
+                self.mime_index_tree.scan_prefix(mime_value(mime_type)),
+                |_| true,
+            )?);
+        }
+        if query.min_duration.is_some() || query.max_duration.is_some() {
+            // Durations are stored big endian, the keys are sorted by duration then by id
+            let min = index_key(&query.min_duration.unwrap_or(0).to_be_bytes(), 0);
+            let max = index_key(
+                &query.max_duration.unwrap_or(u64::MAX).to_be_bytes(),
+                FileHash::MAX,
+            );
+            filters.push(Self::filter_index(
+                self.duration_index_tree.range(min..=max),
+                |_| true,
+            )?);
+        }
+
+        let mut ids = self.sorted_ids(query.sort)?;
+        ids.retain(|id| filters.iter().all(|filter| filter.contains(id)));
+        if query.descending {
+            ids.reverse();
+        }
+
+        let limit = query.limit.min(MAX_PAGE_SIZE);
+        let videos = ids
+            .iter()
+            .skip(query.offset)
+            .take(limit)
+            .map(|id| self.get_video_metadata(*id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(VideoPage {
+            total: ids.len(),
+            offset: query.offset,
+            limit,
+            videos,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_key_round_trips() {
+        let key = index_key(b"big buck bunny", 0x1234);
+        assert_eq!(id_from_index_key(&key), Some(0x1234));
+        assert_eq!(value_from_index_key(&key), b"big buck bunny");
+
+        let empty = index_key(&[], 7);
+        assert_eq!(id_from_index_key(&empty), Some(7));
+        assert!(value_from_index_key(&empty).is_empty());
+    }
+
+    #[test]
+    fn index_keys_sort_by_value_then_id() {
+        let mut keys = [
+            index_key(b"b", 1),
+            index_key(b"ab", 9),
+            index_key(b"a", 0x0100),
+            index_key(b"a", 2),
+        ];
+        keys.sort();
+        let ids: Vec<_> = keys
+            .iter()
+            .filter_map(|key| id_from_index_key(key))
+            .collect();
+        assert_eq!(ids, vec![2, 0x0100, 9, 1]);
+    }
+
+    #[test]
+    fn duration_keys_bound_the_range() {
+        let min = index_key(&10u64.to_be_bytes(), 0);
+        let max = index_key(&20u64.to_be_bytes(), FileHash::MAX);
+        let range = min..=max;
+        assert!(range.contains(&index_key(&10u64.to_be_bytes(), 5)));
+        assert!(range.contains(&index_key(&20u64.to_be_bytes(), FileHash::MAX)));
+        assert!(!range.contains(&index_key(&9u64.to_be_bytes(), FileHash::MAX)));
+        assert!(!range.contains(&index_key(&21u64.to_be_bytes(), 0)));
+        assert!(!range.contains(&index_key(&256u64.to_be_bytes(), 0)));
+    }
+
+    #[test]
+    fn mime_value_drops_codecs() {
+        assert_eq!(mime_value("Video/MP4; codecs=\"avc1\""), b"video/mp4");
+        assert_eq!(mime_value("video/webm"), b"video/webm");
+    }
+}
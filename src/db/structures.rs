@@ -1,15 +1,120 @@
-use packet_forge::{FileHash, Metadata, VideoMetaData};
+use std::io::{self, Read};
+
+use packet_forge::{FileHash, VideoMetaData};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use bytes::Bytes;
+
+use crate::client::video_chunker::{get_mp4_chunks, get_video_chunks};
+
+use super::{
+    ids::{remote_video_key, IdAssignment},
+    library::scan_library,
+    mp4::{is_mp4_mime_type, SeekIndexBuilder},
+    sync::SyncReport,
+};
+
+/// Version of the layout of the trees, older databases are migrated when opened
+const SCHEMA_VERSION: u32 = 6;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// Oldest version that can be migrated, older databases split the chunks differently
+/// and are rebuilt
+const MIN_MIGRATED_VERSION: u32 = 3;
+/// Trees of older versions, dropped when the database is migrated or cleared
+const LEGACY_TREES: [&str; 1] = [
+    "content", // Whole video payloads, replaced by `content_tree`
+];
+
+/// Size of a video payload stored as chunks in `content_tree`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct VideoContentInfo {
+    pub size: u64,
+    pub n_chunks: u32,
+}
+
+/// SHA-256 digests of a video payload and of each of its chunks, computed when it is stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct VideoDigests {
+    pub file_digest: [u8; 32],
+    pub chunk_digests: Vec<[u8; 32]>,
+}
+
+/// Computes the `VideoDigests` of a payload one chunk at a time
+#[derive(Default)]
+pub(super) struct VideoDigestsBuilder {
+    file_hasher: Sha256,
+    chunk_digests: Vec<[u8; 32]>,
+}
+
+impl VideoDigestsBuilder {
+    pub fn add_chunk(&mut self, chunk: &[u8]) {
+        self.file_hasher.update(chunk);
+        self.chunk_digests.push(Sha256::digest(chunk).into());
+    }
+
+    pub fn finish(self) -> VideoDigests {
+        VideoDigests {
+            file_digest: self.file_hasher.finalize().into(),
+            chunk_digests: self.chunk_digests,
+        }
+    }
+}
+
+/// Key of a chunk inside `content_tree`: video id followed by the chunk index,
+/// so the chunks of a video are contiguous and ordered
+pub(crate) fn chunk_key(video_id: FileHash, chunk_index: u32) -> Vec<u8> {
+    let mut key = video_id.to_be_bytes().to_vec();
+    key.extend_from_slice(&chunk_index.to_be_bytes());
+    key
+}
+
+/// Key inside `content_offsets_tree`: video id followed by the offset of the first byte of a chunk
+pub(crate) fn offset_key(video_id: FileHash, offset: u64) -> Vec<u8> {
+    let mut key = video_id.to_be_bytes().to_vec();
+    key.extend_from_slice(&offset.to_be_bytes());
+    key
+}
 
 pub(crate) struct VideoDb {
-    db: sled::Db,
+    pub(super) db: sled::Db,
     pub metadata_tree: sled::Tree,
-    pub content_tree: sled::Tree,
+    pub content_tree: sled::Tree, // (video id, chunk index) -> chunk
+    pub content_info_tree: sled::Tree, // video id -> VideoContentInfo
+    pub content_offsets_tree: sled::Tree, // (video id, byte offset) -> chunk index
+    pub digests_tree: sled::Tree, // video id -> VideoDigests of the stored chunks
+    pub sources_tree: sled::Tree, // video id -> LocalSource of the videos imported from disk
+    pub media_tree: sled::Tree,   // video id -> MediaInfo parsed from the MP4 boxes
+    pub seek_tree: sled::Tree,    // video id -> SeekIndex of the fragmented MP4 videos
+    pub video_ids_tree: sled::Tree, // VideoKey -> video id
+    pub video_id_owners_tree: sled::Tree, // video id -> VideoKey of the video owning it
+    pub title_index_tree: sled::Tree, // (lowercase title, video id) -> ()
+    pub mime_index_tree: sled::Tree, // (container mime type, video id) -> ()
+    pub duration_index_tree: sled::Tree, // (duration, video id) -> ()
+    pub created_at_index_tree: sled::Tree, // (creation date, video id) -> ()
 }
 
 impl VideoDb {
+    /// Writes to disk every change not persisted yet
+    pub fn flush(&self) -> Result<(), String> {
+        self.db
+            .flush()
+            .map(|_| ())
+            .map_err(|e| format!("Error flushing database: {e}"))
+    }
+
     /// Creates or opens a database at the specified path
     pub fn new(database: &str) -> Self {
-        let db = sled::open(database).unwrap_or_else(|e| {
+        Self::open(sled::open(database))
+    }
+
+    /// Creates an empty database deleted when dropped, nothing is left on disk
+    pub fn temporary() -> Self {
+        Self::open(sled::Config::new().temporary(true).open())
+    }
+
+    fn open(db: sled::Result<sled::Db>) -> Self {
+        let db = db.unwrap_or_else(|e| {
             eprintln!("Error opening database: {e}");
             std::process::exit(1);
         });
@@ -23,18 +128,128 @@ impl VideoDb {
         };
 
         let metadata_tree = open_tree("metadata");
-        let content_tree = open_tree("content");
+        let content_tree = open_tree("content_chunks");
+        let content_info_tree = open_tree("content_info");
+        let content_offsets_tree = open_tree("content_offsets");
+        let digests_tree = open_tree("content_digests");
+        let sources_tree = open_tree("sources");
+        let media_tree = open_tree("media_info");
+        let seek_tree = open_tree("seek_index");
+        let video_ids_tree = open_tree("video_ids");
+        let video_id_owners_tree = open_tree("video_id_owners");
+        let title_index_tree = open_tree("title_index");
+        let mime_index_tree = open_tree("mime_index");
+        let duration_index_tree = open_tree("duration_index");
+        let created_at_index_tree = open_tree("created_at_index");
 
-        Self {
+        let video_db = Self {
             db,
             metadata_tree,
             content_tree,
+            content_info_tree,
+            content_offsets_tree,
+            digests_tree,
+            sources_tree,
+            media_tree,
+            seek_tree,
+            video_ids_tree,
+            video_id_owners_tree,
+            title_index_tree,
+            mime_index_tree,
+            duration_index_tree,
+            created_at_index_tree,
+        };
+
+        if let Err(e) = video_db.check_schema_version() {
+            eprintln!("Error checking database version: {e}");
+            std::process::exit(1);
+        }
+        video_db
+    }
+
+    /// Migrates the database if it was created with an older schema version,
+    /// clears it if it cannot be migrated
+    fn check_schema_version(&self) -> Result<(), String> {
+        let stored_version = self
+            .db
+            .get(SCHEMA_VERSION_KEY)
+            .map_err(|e| format!("Error accessing database: {e}"))?
+            .and_then(|data| bincode::deserialize::<u32>(&data).ok());
+
+        match stored_version {
+            Some(SCHEMA_VERSION) => return Ok(()),
+            Some(version) if (MIN_MIGRATED_VERSION..SCHEMA_VERSION).contains(&version) => {
+                self.migrate(version)?;
+            }
+            _ => self.clear_database()?,
+        }
+        let serialized_version =
+            bincode::serialize(&SCHEMA_VERSION).map_err(|e| format!("Serialization error: {e}"))?;
+        self.db
+            .insert(SCHEMA_VERSION_KEY, serialized_version)
+            .map_err(|e| format!("Error inserting schema version: {e}"))?;
+        self.db
+            .flush()
+            .map_err(|e| format!("Error flushing database: {e}"))?;
+        Ok(())
+    }
+
+    /// Removes the trees no longer used by this version
+    fn drop_legacy_trees(&self) -> Result<(), String> {
+        for tree_name in LEGACY_TREES {
+            self.db
+                .drop_tree(tree_name)
+                .map_err(|e| format!("Error dropping {tree_name} tree: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Fills the trees added after version `from` from `metadata_tree`
+    fn migrate(&self, from: u32) -> Result<(), String> {
+        self.drop_legacy_trees()?;
+
+        for entry in &self.metadata_tree {
+            let (_, data) = entry.map_err(|e| format!("Error accessing database: {e}"))?;
+            let metadata = bincode::deserialize::<VideoMetaData>(&data)
+                .map_err(|e| format!("Deserialization error: {e}"))?;
+
+            // Version 4 added the id trees, version 6 keys the local videos by their content
+            if from < 6 {
+                let key = match self.get_local_video_key(metadata.id)? {
+                    Some(key) => key,
+                    None => remote_video_key(metadata.id),
+                };
+                self.transfer_video_id(metadata.id, &key)?;
+            }
+            // Version 5 added the index trees
+            if from < 5 {
+                self.index_video(&metadata)?;
+            }
         }
+        Ok(())
     }
 
     // Clear all entries in the database
     fn clear_database(&self) -> Result<(), String> {
-        let trees = [&self.db, &self.metadata_tree, &self.content_tree];
+        self.drop_legacy_trees()?;
+
+        let trees = [
+            &self.db,
+            &self.metadata_tree,
+            &self.content_tree,
+            &self.content_info_tree,
+            &self.content_offsets_tree,
+            &self.digests_tree,
+            &self.sources_tree,
+            &self.media_tree,
+            &self.seek_tree,
+            &self.video_ids_tree,
+            &self.video_id_owners_tree,
+            &self.title_index_tree,
+            &self.mime_index_tree,
+            &self.duration_index_tree,
+            &self.created_at_index_tree,
+        ];
 
         for tree in trees {
             tree.clear()
@@ -45,90 +260,184 @@ impl VideoDb {
         Ok(())
     }
 
-    fn load_json_metadata(json_file_path: &str) -> Result<Vec<VideoMetaData>, String> {
-        let json_array = "videos";
+    /// Initializes the database, keeping the videos stored by previous runs:
+    /// - adds the local videos not stored yet
+    /// - updates the local videos whose metadata or file changed
+    /// - if `prune` is set, removes the local videos no longer in the library
+    /// ### Arguments
+    /// - `local_path`: folder containing the JSON file and the `videos` folder
+    /// - `file_video_name`: file name with video metadata (.json). If `None` or missing,
+    ///   the metadata of every video is built from its file.
+    /// - `prune`: whether to remove the local videos no longer in the library
+    pub fn init(
+        &self,
+        local_path: &str,
+        file_video_name: Option<&str>,
+        prune: bool,
+    ) -> Result<SyncReport, String> {
+        let scan = scan_library(local_path, file_video_name)?;
+        self.sync_local_videos(scan, prune)
+    }
 
-        let file_content = std::fs::read_to_string(json_file_path)
-            .map_err(|e| format!("Error reading file {json_file_path}: {e}"))?;
+    /// Insert `VideoMetaData` into `metadata_tree` and the index trees,
+    /// the id must be assigned with `assign_video_id`
+    pub(super) fn insert_video_metadata(
+        &self,
+        file_metadata: &VideoMetaData,
+    ) -> Result<(), String> {
+        self.remove_video_metadata(file_metadata.id)?;
 
-        let json_data: serde_json::Value =
-            serde_json::from_str(&file_content).map_err(|e| format!("Error parsing JSON: {e}"))?;
+        let serialized_entry =
+            bincode::serialize(file_metadata).map_err(|e| format!("Serialization error: {e}"))?;
+        self.metadata_tree
+            .insert(file_metadata.id.to_be_bytes(), serialized_entry)
+            .map_err(|e| format!("Error inserting song metadata: {e}"))?;
+        self.index_video(file_metadata)
+    }
 
-        let videos_array = json_data[json_array]
-            .as_array()
-            .ok_or_else(|| format!("Invalid JSON: '{json_array}' is not an array"))?;
+    /// Removes a video from `metadata_tree` and the index trees
+    pub(super) fn remove_video_metadata(&self, video_id: FileHash) -> Result<(), String> {
+        let Some(data) = self
+            .metadata_tree
+            .remove(video_id.to_be_bytes())
+            .map_err(|e| format!("Error removing video metadata: {e}"))?
+        else {
+            return Ok(());
+        };
 
-        videos_array
-            .iter()
-            .map(|song| {
-                serde_json::from_value(song.clone()).map_err(|e| format!("Invalid song data: {e}"))
-            })
-            .collect()
+        let metadata = bincode::deserialize::<VideoMetaData>(&data)
+            .map_err(|e| format!("Deserialization error: {e}"))?;
+        self.unindex_video(&metadata)
     }
 
-    /// Initializes the database:
-    /// - clears existing entries
-    /// - checks for data from local files
-    /// ### Arguments
-    /// - `local_path`: folder containing the JSON file
-    /// - `file_video_name`: file name with video metadata (.json). If `None`, the database will be empty.
-    pub fn init(&self, local_path: &str, file_video_name: Option<&str>) -> Result<(), String> {
-        self.clear_database()?;
-
-        if let Some(file_name) = file_video_name {
-            let videos_metadata_path = format!("{local_path}/{file_name}");
-            let mut videos_array = Self::load_json_metadata(&videos_metadata_path)?;
-            self.insert_videos_from_vec(local_path, &mut videos_array)?;
+    /// Removes every chunk of a video from `content_tree`
+    pub(super) fn remove_video_content(&self, video_id: FileHash) -> Result<(), String> {
+        for tree in [&self.content_info_tree, &self.digests_tree, &self.seek_tree] {
+            tree.remove(video_id.to_be_bytes())
+                .map_err(|e| format!("Error removing song payload: {e}"))?;
         }
 
+        for tree in [&self.content_tree, &self.content_offsets_tree] {
+            for key in tree.scan_prefix(video_id.to_be_bytes()).keys() {
+                let key = key.map_err(|e| format!("Error accessing database: {e}"))?;
+                tree.remove(key)
+                    .map_err(|e| format!("Error removing song payload: {e}"))?;
+            }
+        }
         Ok(())
     }
 
-    /// Insert `VideoMetaData` into `metadata_tree`
-    fn insert_video_metadata(
+    /// Stores the chunks of a video, their offsets and their digests
+    fn insert_video_chunks(
         &self,
-        mut file_hash: FileHash,
-        file_metadata: &mut VideoMetaData,
-    ) -> Result<FileHash, String> {
-        // Generate file id
-        if file_hash == 0 {
-            file_hash = file_metadata.compact_hash_u16();
-            file_metadata.id = file_hash;
+        video_id: FileHash,
+        chunks: impl Iterator<Item = io::Result<Bytes>>,
+        mut seek_index: Option<&mut SeekIndexBuilder>,
+    ) -> Result<VideoContentInfo, String> {
+        let mut info = VideoContentInfo {
+            size: 0,
+            n_chunks: 0,
+        };
+        let mut digests = VideoDigestsBuilder::default();
+        for chunk in chunks {
+            let chunk = chunk.map_err(|e| format!("Error reading song payload: {e}"))?;
+            if let Some(seek_index) = seek_index.as_deref_mut() {
+                seek_index.add_chunk(info.n_chunks, &chunk);
+            }
+            digests.add_chunk(&chunk);
+
+            self.content_tree
+                .insert(chunk_key(video_id, info.n_chunks), chunk.as_ref())
+                .map_err(|e| format!("Error inserting song payload: {e}"))?;
+            self.content_offsets_tree
+                .insert(
+                    offset_key(video_id, info.size),
+                    info.n_chunks.to_be_bytes().to_vec(),
+                )
+                .map_err(|e| format!("Error inserting song payload: {e}"))?;
+
+            info.size += chunk.len() as u64;
+            info.n_chunks = info
+                .n_chunks
+                .checked_add(1)
+                .ok_or_else(|| format!("Too many chunks in video {video_id}"))?;
         }
 
-        let serialized_entry =
-            bincode::serialize(&file_metadata).map_err(|e| format!("Serialization error: {e}"))?;
-        self.metadata_tree
-            .insert(file_hash.to_be_bytes(), serialized_entry)
-            .map(|_| file_hash)
-            .map_err(|e| format!("Error inserting song metadata: {e}"))
+        self.insert_video_digests(video_id, &digests.finish())?;
+        Ok(info)
     }
 
-    /// Inserts video content inside `content_tree`
-    fn insert_video_content(&self, video_id: FileHash, payload: Vec<u8>) -> Result<(), String> {
-        match self.content_tree.insert(video_id.to_be_bytes(), payload) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Error inserting song payload: {e}")),
-        }
+    /// Stores the digests sent to the peers downloading a video
+    pub(super) fn insert_video_digests(
+        &self,
+        video_id: FileHash,
+        digests: &VideoDigests,
+    ) -> Result<(), String> {
+        let serialized_digests =
+            bincode::serialize(digests).map_err(|e| format!("Serialization error: {e}"))?;
+        self.digests_tree
+            .insert(video_id.to_be_bytes(), serialized_digests)
+            .map(|_| ())
+            .map_err(|e| format!("Error inserting video digests: {e}"))
     }
 
-    /// Insert a vector of `VideoMetaData` inside `metadata_tree`
-    fn insert_videos_from_vec(
+    /// Inserts video content inside `content_tree`, reading one chunk at a time from `payload`.
+    /// MP4 videos are split at fragment boundaries, the other formats at fixed offsets.
+    pub(super) fn insert_video_content(
         &self,
-        local_path: &str,
-        videos: &mut [VideoMetaData],
+        video_id: FileHash,
+        mime_type: &str,
+        payload: impl Read,
     ) -> Result<(), String> {
-        for video_metadata in videos.iter_mut() {
-            let video_id = self.insert_video_metadata(video_metadata.id, video_metadata)?;
+        self.remove_video_content(video_id)?;
 
-            let video_title_parsed = video_metadata.title.replace(' ', "").to_lowercase();
-            let video_file_path = format!("{local_path}/videos/{video_title_parsed}.mp4");
+        let info = if is_mp4_mime_type(mime_type) {
+            let mut seek_index = SeekIndexBuilder::default();
+            let info =
+                self.insert_video_chunks(video_id, get_mp4_chunks(payload), Some(&mut seek_index))?;
 
-            let video_content = std::fs::read(&video_file_path)
-                .map_err(|e| format!("Error reading video file {video_file_path}: {e}"))?;
+            // Fragmented videos can start playing from any media segment
+            if let Some(seek_index) = seek_index.finish() {
+                let serialized_index = bincode::serialize(&seek_index)
+                    .map_err(|e| format!("Serialization error: {e}"))?;
+                self.seek_tree
+                    .insert(video_id.to_be_bytes(), serialized_index)
+                    .map_err(|e| format!("Error inserting seek index: {e}"))?;
+            }
+            info
+        } else {
+            self.insert_video_chunks(video_id, get_video_chunks(payload), None)?
+        };
 
-            self.insert_video_content(video_id, video_content)?;
+        // Written last, the payload is available only once every chunk is stored
+        let serialized_info =
+            bincode::serialize(&info).map_err(|e| format!("Serialization error: {e}"))?;
+        self.content_info_tree
+            .insert(video_id.to_be_bytes(), serialized_info)
+            .map(|_| ())
+            .map_err(|e| format!("Error inserting song payload: {e}"))
+    }
+
+    /// Inserts a video received from the network, keeping its original id.
+    /// Fails if the id already belongs to a local video, instead of overwriting it.
+    pub fn insert_video(&self, metadata: &VideoMetaData, payload: impl Read) -> Result<(), String> {
+        let video_id = metadata.id;
+        if video_id == 0 {
+            return Err("Invalid video id 0".to_string());
+        }
+
+        let key = remote_video_key(video_id);
+        if let IdAssignment::Conflict { id } =
+            self.assign_video_id(&key, Some(video_id), video_id)?
+        {
+            return Err(format!("Video id {id} is already used by another video"));
         }
+        self.insert_video_metadata(metadata)?;
+        self.insert_video_content(video_id, &metadata.mime_type, payload)?;
+
+        self.db
+            .flush()
+            .map_err(|e| format!("Error flushing database: {e}"))?;
         Ok(())
     }
 }
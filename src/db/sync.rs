@@ -0,0 +1,532 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::time::UNIX_EPOCH;
+
+use packet_forge::{FileHash, Metadata};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    ids::{local_video_key, IdAssignment, VideoKey},
+    library::{LibraryScan, LocalVideo},
+    mp4::{is_mp4_mime_type, parse_mp4, MediaInfo},
+    structures::VideoDb,
+};
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// File a video was imported from, used to detect changes between runs.
+/// The path is explicit, it is never rebuilt from the metadata.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LocalSource {
+    path: String,
+    modified: u64, // Seconds since the Unix epoch
+    size: u64,
+    digest: [u8; 32],
+}
+
+/// File of a listed video, with the id stored before from the same path
+struct LocalFile {
+    source: LocalSource,
+    previous_id: Option<FileHash>,
+}
+
+enum SyncOutcome {
+    Added,
+    Updated,
+    Unchanged,
+    Conflict(String), // The video was not stored, it requests an id used by another video
+}
+
+/// Result of synchronizing the database with the local library
+#[derive(Debug, Default)]
+pub(crate) struct SyncReport {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub errors: Vec<String>,    // Videos that could not be synchronized
+    pub conflicts: Vec<String>, // Videos not stored because their id is used by another video
+}
+
+impl Display for SyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} unchanged, {} removed, {} failed, {} conflicts",
+            self.added,
+            self.updated,
+            self.unchanged,
+            self.removed,
+            self.errors.len(),
+            self.conflicts.len()
+        )
+    }
+}
+
+/// Computes the SHA-256 digest of a file without loading it in memory
+fn file_digest(path: &str) -> Result<[u8; 32], String> {
+    let mut file = File::open(path).map_err(|e| format!("Error reading video file {path}: {e}"))?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let bytes_read = file
+            .read(&mut buffer)
+            .map_err(|e| format!("Error reading video file {path}: {e}"))?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Reads the size and the modification time of a library file.
+/// The digest of `stored`, the source stored from the same path, is reused if they did not change.
+fn read_local_file(
+    path: String,
+    stored: Option<&(FileHash, LocalSource)>,
+) -> Result<LocalFile, String> {
+    let file_metadata =
+        std::fs::metadata(&path).map_err(|e| format!("Error reading video file {path}: {e}"))?;
+    let modified = file_metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_secs());
+    let size = file_metadata.len();
+
+    // Compute the content digest only if the file looks different
+    let digest = match stored {
+        Some((_, source)) if source.modified == modified && source.size == size => source.digest,
+        _ => file_digest(&path)?,
+    };
+    Ok(LocalFile {
+        source: LocalSource {
+            path,
+            modified,
+            size,
+            digest,
+        },
+        previous_id: stored.map(|(id, _)| *id),
+    })
+}
+
+impl VideoDb {
+    /// Returns the sources of every local video
+    fn get_local_sources(&self) -> Result<Vec<(FileHash, LocalSource)>, String> {
+        let mut sources = Vec::new();
+        for entry in &self.sources_tree {
+            let (key, data) = entry.map_err(|e| format!("Error accessing database: {e}"))?;
+            let id = key
+                .as_ref()
+                .try_into()
+                .map(FileHash::from_be_bytes)
+                .map_err(|_| "Invalid video id in database".to_string())?;
+            let source = bincode::deserialize::<LocalSource>(&data)
+                .map_err(|e| format!("Deserialization error: {e}"))?;
+            sources.push((id, source));
+        }
+        Ok(sources)
+    }
+
+    /// Key of a local video, from the digest of the file it was imported from
+    pub(super) fn get_local_video_key(&self, id: FileHash) -> Result<Option<VideoKey>, String> {
+        Ok(self
+            .get_local_source(id)?
+            .map(|source| local_video_key(&source.digest)))
+    }
+
+    fn get_local_source(&self, id: FileHash) -> Result<Option<LocalSource>, String> {
+        self.sources_tree
+            .get(id.to_be_bytes())
+            .map_err(|e| format!("Error accessing database: {e}"))?
+            .map(|data| {
+                bincode::deserialize::<LocalSource>(&data)
+                    .map_err(|e| format!("Deserialization error: {e}"))
+            })
+            .transpose()
+    }
+
+    fn insert_local_source(&self, id: FileHash, source: &LocalSource) -> Result<(), String> {
+        let serialized_source =
+            bincode::serialize(source).map_err(|e| format!("Serialization error: {e}"))?;
+        self.sources_tree
+            .insert(id.to_be_bytes(), serialized_source)
+            .map(|_| ())
+            .map_err(|e| format!("Error inserting video source: {e}"))
+    }
+
+    fn insert_media_info(&self, id: FileHash, media_info: &MediaInfo) -> Result<(), String> {
+        let serialized_info =
+            bincode::serialize(media_info).map_err(|e| format!("Serialization error: {e}"))?;
+        self.media_tree
+            .insert(id.to_be_bytes(), serialized_info)
+            .map(|_| ())
+            .map_err(|e| format!("Error inserting video media info: {e}"))
+    }
+
+    /// Removes the metadata, the content and the source of a video, freeing its id
+    fn remove_video(&self, id: FileHash) -> Result<(), String> {
+        self.remove_video_metadata(id)?;
+        self.media_tree
+            .remove(id.to_be_bytes())
+            .map_err(|e| format!("Error removing video media info: {e}"))?;
+        self.remove_video_content(id)?;
+        self.sources_tree
+            .remove(id.to_be_bytes())
+            .map_err(|e| format!("Error removing video source: {e}"))?;
+        self.release_video_id(id)
+    }
+
+    /// Stores a local video if its metadata or its file changed since the last run.
+    /// `listed` are the keys of every video in the library.
+    fn sync_video(
+        &self,
+        video: &mut LocalVideo,
+        file: LocalFile,
+        listed: &HashSet<VideoKey>,
+    ) -> Result<SyncOutcome, String> {
+        let metadata = &mut video.metadata;
+        let LocalFile {
+            source,
+            previous_id,
+        } = file;
+        let path = source.path.clone();
+        let key = local_video_key(&source.digest);
+
+        // A file whose content changed keeps the id of its path, unless its old content is
+        // still listed somewhere else
+        if let Some(previous_id) = previous_id {
+            let owner = self.get_video_id_owner(previous_id)?;
+            let replaced = owner.is_none_or(|owner| !listed.contains(&owner));
+            if replaced && self.get_video_key_id(&key)?.is_none() {
+                self.transfer_video_id(previous_id, &key)?;
+            }
+        }
+
+        // Keep the id of the previous runs, or the one set in the JSON manifest
+        let requested = Some(metadata.id).filter(|id| *id != 0);
+        let video_id = match self.assign_video_id(&key, requested, metadata.compact_hash_u16())? {
+            IdAssignment::Assigned { id, previous } => {
+                // The manifest changed the id of the video, its old entry is dropped
+                if let Some(previous) = previous {
+                    self.remove_video(previous)?;
+                }
+                id
+            }
+            IdAssignment::Conflict { id } => {
+                return Ok(SyncOutcome::Conflict(format!(
+                    "Video '{}' ({path}) requests id {id}, already used by another video",
+                    metadata.title
+                )));
+            }
+        };
+        metadata.id = video_id;
+
+        let stored_metadata = self
+            .metadata_tree
+            .get(video_id.to_be_bytes())
+            .map_err(|e| format!("Error accessing database: {e}"))?;
+        let stored_source = self.get_local_source(video_id)?;
+        let has_content = self.get_video_content_info(video_id).is_ok();
+        let content_changed = !has_content
+            || stored_source.as_ref().map(|stored| stored.digest) != Some(source.digest);
+
+        // Parse the MP4 boxes of new files, rejecting invalid media before storing anything
+        let stored_media = self.get_video_media_info(video_id).ok();
+        let media_info = match stored_media.clone() {
+            _ if !is_mp4_mime_type(&metadata.mime_type) => None,
+            Some(media_info) if !content_changed => Some(media_info),
+            _ => {
+                let mut file = File::open(&path)
+                    .map_err(|e| format!("Error reading video file {path}: {e}"))?;
+                Some(parse_mp4(&mut file).map_err(|e| format!("Invalid media file {path}: {e}"))?)
+            }
+        };
+        if let Some(media_info) = &media_info {
+            metadata.duration = media_info.duration.round() as _;
+            metadata.mime_type = media_info.mime_type(&metadata.mime_type);
+        }
+
+        let serialized_metadata =
+            bincode::serialize(&metadata).map_err(|e| format!("Serialization error: {e}"))?;
+        let metadata_changed = stored_metadata.as_deref() != Some(serialized_metadata.as_slice());
+
+        if metadata_changed {
+            self.insert_video_metadata(metadata)?;
+        }
+        if content_changed {
+            let file =
+                File::open(&path).map_err(|e| format!("Error reading video file {path}: {e}"))?;
+            self.insert_video_content(video_id, &metadata.mime_type, file)?;
+        }
+        if let Some(media_info) = media_info.filter(|info| stored_media.as_ref() != Some(info)) {
+            self.insert_media_info(video_id, &media_info)?;
+        }
+        if stored_source.as_ref() != Some(&source) {
+            self.insert_local_source(video_id, &source)?;
+        }
+
+        Ok(match stored_metadata {
+            None => SyncOutcome::Added,
+            Some(_) if metadata_changed || content_changed => SyncOutcome::Updated,
+            Some(_) => SyncOutcome::Unchanged,
+        })
+    }
+
+    /// Synchronizes the database with the videos of the local library.
+    /// Videos downloaded from the network are never pruned.
+    /// Removed videos are pruned first, so their ids can be taken by the listed ones.
+    /// The files that could not be read are reported and never pruned.
+    pub(crate) fn sync_local_videos(
+        &self,
+        scan: LibraryScan,
+        prune: bool,
+    ) -> Result<SyncReport, String> {
+        let LibraryScan {
+            videos,
+            failed,
+            errors,
+        } = scan;
+        let mut report = SyncReport {
+            errors,
+            ..SyncReport::default()
+        };
+
+        // Read the digest of every file, videos are identified by their content
+        let stored_sources = self.get_local_sources()?;
+        let sources_by_path: HashMap<&str, &(FileHash, LocalSource)> = stored_sources
+            .iter()
+            .map(|stored| (stored.1.path.as_str(), stored))
+            .collect();
+        let mut unreadable: HashSet<String> = failed
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        let mut files = Vec::new();
+        for video in videos {
+            let path = video.path.to_string_lossy().to_string();
+            let stored = sources_by_path.get(path.as_str()).copied();
+            match read_local_file(path.clone(), stored) {
+                Ok(file) => files.push((video, file)),
+                Err(err) => {
+                    report.errors.push(err);
+                    unreadable.insert(path);
+                }
+            }
+        }
+        let listed: HashSet<VideoKey> = files
+            .iter()
+            .map(|(_, file)| local_video_key(&file.source.digest))
+            .collect();
+        let listed_paths: HashSet<&str> = files
+            .iter()
+            .map(|(_, file)| file.source.path.as_str())
+            .collect();
+
+        if prune {
+            // The files whose content changed are updated instead
+            let removed: Vec<FileHash> = stored_sources
+                .iter()
+                .filter(|(_, source)| {
+                    !unreadable.contains(&source.path)
+                        && !listed_paths.contains(source.path.as_str())
+                })
+                .map(|(id, _)| *id)
+                .filter(|id| match self.get_video_id_owner(*id) {
+                    Ok(Some(key)) => !listed.contains(&key),
+                    Ok(None) => true,
+                    Err(_) => false,
+                })
+                .collect();
+
+            for id in removed {
+                match self.remove_video(id) {
+                    Ok(()) => report.removed += 1,
+                    Err(err) => report.errors.push(err),
+                }
+            }
+        }
+
+        // Files with the same content are stored once
+        let mut synced = HashSet::new();
+        for (mut video, file) in files {
+            if !synced.insert(local_video_key(&file.source.digest)) {
+                report.conflicts.push(format!(
+                    "Video '{}' ({}) has the same content as another video, not stored",
+                    video.metadata.title, file.source.path
+                ));
+                continue;
+            }
+
+            match self.sync_video(&mut video, file, &listed) {
+                Ok(SyncOutcome::Added) => report.added += 1,
+                Ok(SyncOutcome::Updated) => report.updated += 1,
+                Ok(SyncOutcome::Unchanged) => report.unchanged += 1,
+                Ok(SyncOutcome::Conflict(conflict)) => report.conflicts.push(conflict),
+                Err(err) => report.errors.push(err),
+            }
+        }
+
+        self.db
+            .flush()
+            .map_err(|e| format!("Error flushing database: {e}"))?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use packet_forge::VideoMetaData;
+
+    use super::*;
+    use crate::db::library::tests::TestLibrary;
+
+    /// Writes the file of a video, returning it as found by the library scan
+    fn video(library: &TestLibrary, file_name: &str, title: &str, content: &[u8]) -> LocalVideo {
+        LocalVideo {
+            metadata: VideoMetaData {
+                id: 0,
+                title: title.to_string(),
+                description: String::new(),
+                duration: 0,
+                mime_type: "video/webm".to_string(),
+                created_at: String::new(),
+            },
+            path: library.write(file_name, content),
+        }
+    }
+
+    fn scan(videos: Vec<LocalVideo>, failed: Vec<PathBuf>) -> LibraryScan {
+        LibraryScan {
+            videos,
+            failed,
+            errors: Vec::new(),
+        }
+    }
+
+    fn stored_paths(db: &VideoDb) -> Vec<String> {
+        let mut paths: Vec<String> = db
+            .get_local_sources()
+            .unwrap()
+            .into_iter()
+            .map(|(_, source)| source.path)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn stored_id(db: &VideoDb, video: &LocalVideo) -> FileHash {
+        let path = video.path.to_string_lossy();
+        db.get_local_sources()
+            .unwrap()
+            .into_iter()
+            .find(|(_, source)| source.path == path)
+            .map(|(id, _)| id)
+            .unwrap()
+    }
+
+    #[test]
+    fn new_videos_are_added_once() {
+        let library = TestLibrary::new("added");
+        let db = VideoDb::temporary();
+        let a = video(&library, "a.webm", "A", b"first video");
+        let b = video(&library, "b.webm", "B", b"second video");
+
+        let report = db
+            .sync_local_videos(scan(vec![a.clone(), b.clone()], Vec::new()), true)
+            .unwrap();
+        assert_eq!((report.added, report.unchanged), (2, 0));
+        assert!(report.errors.is_empty() && report.conflicts.is_empty());
+
+        let report = db
+            .sync_local_videos(scan(vec![a, b], Vec::new()), true)
+            .unwrap();
+        assert_eq!((report.added, report.updated, report.unchanged), (0, 0, 2));
+    }
+
+    #[test]
+    fn changed_videos_are_updated_with_the_same_id() {
+        let library = TestLibrary::new("updated");
+        let db = VideoDb::temporary();
+        let a = video(&library, "a.webm", "A", b"first video");
+        db.sync_local_videos(scan(vec![a.clone()], Vec::new()), true)
+            .unwrap();
+        let id = stored_id(&db, &a);
+
+        // New content in the same file
+        let a = video(&library, "a.webm", "A", b"first video, edited");
+        let report = db
+            .sync_local_videos(scan(vec![a.clone()], Vec::new()), true)
+            .unwrap();
+        assert_eq!((report.added, report.updated), (0, 1));
+        assert_eq!(stored_id(&db, &a), id);
+        assert_eq!(
+            db.get_video_content_info(id).unwrap().size,
+            b"first video, edited".len() as u64
+        );
+
+        // New metadata for the same file
+        let mut renamed = a.clone();
+        renamed.metadata.title = "A, renamed".to_string();
+        let report = db
+            .sync_local_videos(scan(vec![renamed], Vec::new()), true)
+            .unwrap();
+        assert_eq!(report.updated, 1);
+        assert_eq!(db.get_video_metadata(id).unwrap().title, "A, renamed");
+    }
+
+    #[test]
+    fn removed_videos_are_pruned_only_if_requested() {
+        let library = TestLibrary::new("pruned");
+        let db = VideoDb::temporary();
+        let a = video(&library, "a.webm", "A", b"first video");
+        let b = video(&library, "b.webm", "B", b"second video");
+        db.sync_local_videos(scan(vec![a.clone(), b.clone()], Vec::new()), true)
+            .unwrap();
+        let b_id = stored_id(&db, &b);
+
+        let report = db
+            .sync_local_videos(scan(vec![a.clone()], Vec::new()), false)
+            .unwrap();
+        assert_eq!(report.removed, 0);
+        assert_eq!(stored_paths(&db).len(), 2);
+
+        let report = db
+            .sync_local_videos(scan(vec![a.clone()], Vec::new()), true)
+            .unwrap();
+        assert_eq!(report.removed, 1);
+        assert_eq!(
+            stored_paths(&db),
+            vec![a.path.to_string_lossy().to_string()]
+        );
+        assert!(db.get_video_content_info(b_id).is_err());
+        assert!(db.get_video_metadata(b_id).is_err());
+    }
+
+    #[test]
+    fn unreadable_videos_are_kept() {
+        let library = TestLibrary::new("unreadable");
+        let db = VideoDb::temporary();
+        let a = video(&library, "a.webm", "A", b"first video");
+        let b = video(&library, "b.webm", "B", b"second video");
+        db.sync_local_videos(scan(vec![a.clone(), b.clone()], Vec::new()), true)
+            .unwrap();
+
+        // `b` failed the scan, `a` is listed but its file cannot be read
+        std::fs::remove_file(&a.path).unwrap();
+        let report = db
+            .sync_local_videos(scan(vec![a.clone()], vec![b.path.clone()]), true)
+            .unwrap();
+        assert_eq!(report.removed, 0);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(stored_paths(&db).len(), 2);
+        assert!(db.get_video_content_info(stored_id(&db, &b)).is_ok());
+    }
+}
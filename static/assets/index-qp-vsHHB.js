@@ -67,5 +67,4 @@ Error generating stack: `+u.message+`
  *
  * This source code is licensed under the ISC license.
  * See the LICENSE file in the root directory of this source tree.
- */const Yf=[["path",{d:"M3 12a9 9 0 0 1 9-9 9.75 9.75 0 0 1 6.74 2.74L21 8",key:"v9h5vc"}],["path",{d:"M21 3v5h-5",key:"1q7to0"}],["path",{d:"M21 12a9 9 0 0 1-9 9 9.75 9.75 0 0 1-6.74-2.74L3 16",key:"3uifl3"}],["path",{d:"M8 16H3v5",key:"1cv678"}]],Xf=Ma("RefreshCw",Yf),Gf=()=>{const x=xe.useRef(null),z=xe.useRef(null),m=xe.useRef(null),[q,M]=xe.useState([]),[V,le]=xe.useState([]),[B,$]=xe.useState("Setup"),[ye,ge]=xe.useState(null),[b,D]=xe.useState(null),ze=[];let Re=!1;const ee=async O=>{if(typeof O=="string"){const te=atob(O),G=new Uint8Array(te.length);for(let H=0;H<te.length;H++)G[H]=te.charCodeAt(H);return G}throw new Error("Unsupported data format")},X=async O=>{try{if(x.current&&z.current){x.current.pause(),x.current.src="",z.current=new MediaSource;const G=URL.createObjectURL(z.current);x.current.src=G,z.current.addEventListener("sourceopen",()=>{m.current=z.current.addSourceBuffer('video/mp4; codecs="avc1.42E01E,mp4a.40.2"')})}const te=await fetch(`/req-video/${O}`,{method:"GET"});te.ok?D(null):(console.error("Failed to fetch video:",te.status),D("Failed to fetch video"))}catch(te){console.error("Error requesting video:",te),D("Failed to fetch video")}},ot=async()=>{try{const O=await fetch("/req-video-list-from-db",{method:"GET"});if(O.ok){const G=(await O.text()).trim().split(`
-`).filter(H=>H.startsWith("data:")).map(H=>JSON.parse(H.slice(5)));M(G),D(null)}else console.error("Failed to fetch videos:",O.status),D("Failed to fetch video list from db")}catch(O){console.error("Error sending message:",O),D("Failed to fetch video list from db")}},Ye=async()=>{try{const O=await fetch("/req-video-list-from-server",{method:"GET"});O.ok?D(null):(console.error("Failed to fetch videos from server:",O.status),D("Failed to fetch video list from server"))}catch(O){console.error("Error sending message:",O),D("Failed to fetch video list from server")}},Xe=async()=>{try{const O=await fetch("/flood-req",{method:"GET"});O.ok?D(null):(console.error("Failed to send message:",O.status),D("Failed to send flood_req"))}catch(O){console.error("Error sending message:",O),D("Failed to send flood_req")}};return xe.useEffect(()=>{B==="SubscribedToServer"&&Ye()},[B]),xe.useEffect(()=>{if(!x.current)return;ot();const O=new EventSource("/video-list-from-server");O.onmessage=function(H){try{const pe=JSON.parse(H.data),De=pe[0],Ie=pe[1];le(me=>{const ce=me.findIndex(ie=>ie.serverId===De);if(ce!==-1){const ie=[...me];return ie[ce]={serverId:De,videos:Ie},ie}else return[...me,{serverId:De,videos:Ie}]})}catch(pe){console.error("Error parsing video list from server:",pe),le([]),D("Failed to fetch video list from server")}};const te=new EventSource("/fsm-status");te.onmessage=function(H){try{$(H.data)}catch(pe){console.error("Error parsing FSM status:",pe),$("Setup"),D("Failed to fetch FSM status")}},z.current=new MediaSource;const G=URL.createObjectURL(z.current);return x.current.src=G,z.current.addEventListener("sourceopen",()=>{var De,Ie;if(!z.current)return;((De=z.current)==null?void 0:De.readyState)==="open"?m.current=z.current.addSourceBuffer('video/mp4; codecs="avc1.42E01E,mp4a.40.2"'):console.error("MediaSource not ready");const H=new EventSource("/video-stream");H.onmessage=async me=>{try{const ce=await ee(me.data);ze.push(ce),pe()}catch{}};const pe=async()=>{if(!(Re||ze.length===0||!m.current)){Re=!0;try{const me=ze.shift();me&&(m.current.updating&&await new Promise(ce=>{var ie;(ie=m.current)==null||ie.addEventListener("updateend",()=>ce(),{once:!0})}),m.current.appendBuffer(me),await new Promise(ce=>{var ie;(ie=m.current)==null||ie.addEventListener("updateend",()=>ce(),{once:!0})}))}catch(me){console.error("Error appending chunk:",me),ze.length=0}finally{Re=!1,pe()}}};H.onerror=me=>{console.error("EventSource error:",me),D("Failed to receive video stream"),H.close()},(Ie=m.current)==null||Ie.addEventListener("updateend",()=>{if(!(!m.current||!x.current)&&m.current.buffered.length>0){const me=m.current.buffered.end(0),ce=x.current.currentTime;me-ce>30&&m.current.remove(0,ce-10)}})}),()=>{var H;((H=z.current)==null?void 0:H.readyState)==="open"&&z.current.endOfStream(),URL.revokeObjectURL(G)}},[]),L.jsx("div",{className:"min-h-screen bg-gray-900 text-white",children:L.jsxs("div",{className:"container mx-auto px-4 py-8",children:[b&&L.jsxs("div",{className:"fixed bottom-4 left-4 bg-red-500 text-white px-4 py-2 rounded-lg shadow-lg flex items-center justify-between space-x-4 w-max",children:[L.jsx("p",{className:"text-sm",children:b}),L.jsx("button",{onClick:()=>D(null),className:"text-white font-bold text-lg leading-none",children:"✖"})]}),L.jsx("div",{className:"fixed top-4 right-4 z-50",children:L.jsxs("div",{className:"flex items-center space-x-2 bg-gray-800 rounded-full px-4 py-2 shadow-lg",children:[L.jsx("div",{className:`w-3 h-3 rounded-full ${B==="NotSubscribedToServer"?"bg-yellow-500":B==="SubscribedToServer"?"bg-green-500":B==="Terminated"?"bg-red-500":"bg-gray-500"}`}),L.jsx("span",{className:"text-sm font-medium",children:B}),L.jsx("button",{onClick:()=>Ye(),className:"text-blue-400 hover:text-blue-300 transition-colors",children:L.jsx(Xf,{size:16})}),L.jsx("button",{onClick:Xe,className:"bg-blue-600 hover:bg-blue-500 text-white px-3 py-1 rounded-full text-sm",children:"Send flood_req"})]})}),L.jsxs("div",{className:"grid md:grid-cols-3 gap-8",children:[L.jsxs("div",{className:"md:col-span-2 rounded-xl overflow-hidden shadow-2xl",children:[L.jsx("video",{ref:x,className:"w-full bg-black",controls:!0,preload:"auto",children:L.jsx("p",{className:"vjs-no-js",children:"To view this video, please enable JavaScript."})}),ye&&L.jsxs("div",{className:"p-4 bg-gray-700",children:[L.jsx("h2",{className:"text-xl font-bold",children:ye.title}),L.jsx("p",{className:"text-gray-300",children:ye.description})]})]}),L.jsxs("div",{className:"space-y-6",children:[L.jsxs("div",{children:[L.jsx("h2",{className:"text-2xl font-bold mb-4 text-gray-200",children:"Local Videos"}),L.jsxs("div",{className:"space-y-4",children:[q.map(O=>L.jsx(Oa,{video:O,onSelect:()=>{ge(O),X(O.id)}},O.id)),q.length===0&&L.jsx("p",{className:"text-gray-500 text-center",children:"No local videos"})]})]}),L.jsxs("div",{children:[L.jsx("h2",{className:"text-2xl font-bold mb-4 text-gray-200",children:"Server Videos"}),L.jsxs("div",{className:"space-y-6",children:[V.map(({serverId:O,videos:te})=>L.jsxs("div",{className:"border border-gray-600 p-4 rounded-lg",children:[L.jsxs("h3",{className:"text-xl font-semibold text-gray-300",children:["Server ",O]}),L.jsxs("div",{className:"space-y-4 mt-2",children:[te.map(G=>L.jsx(Oa,{video:G,onSelect:()=>{ge(G),X(G.id)}},G.id)),te.length===0&&L.jsx("p",{className:"text-gray-500 text-center",children:"No videos from this server"})]})]},O)),V.length===0&&L.jsx("p",{className:"text-gray-500 text-center",children:"No server videos"})]})]})]})]})]})})},Oa=({video:x,onSelect:z})=>L.jsxs("div",{className:"bg-gray-800 rounded-lg p-4 hover:bg-gray-700 transition-colors cursor-pointer group",onClick:z,children:[L.jsxs("div",{className:"flex justify-between items-center mb-2",children:[L.jsx("h3",{className:"text-lg font-semibold text-gray-200 group-hover:text-blue-400 transition-colors",children:x.title}),L.jsx(Kf,{size:20,className:"text-gray-500 hover:text-blue-500 transition-colors"})]}),L.jsx("p",{className:"text-gray-400 text-sm mb-2",children:x.description}),L.jsxs("div",{className:"flex justify-between text-xs text-gray-500",children:[L.jsx("span",{children:x.mime_type}),L.jsxs("span",{children:[x.duration,"s"]})]})]});function Zf(){return L.jsxs(L.Fragment,{children:[L.jsx(Bf,{}),L.jsx(Gf,{})]})}Af.createRoot(document.getElementById("root")).render(L.jsx(xe.StrictMode,{children:L.jsx(Zf,{})}));
+ */const Yf=[["path",{d:"M3 12a9 9 0 0 1 9-9 9.75 9.75 0 0 1 6.74 2.74L21 8",key:"v9h5vc"}],["path",{d:"M21 3v5h-5",key:"1q7to0"}],["path",{d:"M21 12a9 9 0 0 1-9 9 9.75 9.75 0 0 1-6.74-2.74L3 16",key:"3uifl3"}],["path",{d:"M8 16H3v5",key:"1cv678"}]],Xf=Ma("RefreshCw",Yf),Jf='video/mp4; codecs="avc1.42E01E,mp4a.40.2"',qf=20,ep=x=>{x.addEventListener("shutdown",()=>x.close())},Gf=()=>{const x=xe.useRef(null),z=xe.useRef(null),m=xe.useRef(null),q=xe.useRef(null),M=xe.useRef(null),V=xe.useRef(null),le=xe.useRef(0),[B,$]=xe.useState([]),[ye,ge]=xe.useState(0),[b,D]=xe.useState(""),[ze,Re]=xe.useState("title"),[ee,X]=xe.useState(0),[ot,Ye]=xe.useState([]),[Xe,O]=xe.useState("Setup"),[te,G]=xe.useState(null),[H,pe]=xe.useState(null),[De,Ie]=xe.useState(null),me=[];let ce=!1;const ie=async C=>{if(typeof C=="string"){const P=atob(C),U=new Uint8Array(P.length);for(let W=0;W<P.length;W++)U[W]=P.charCodeAt(W);return U}throw new Error("Unsupported data format")},Ce=C=>{var W,K;(W=q.current)==null||W.close();const P=new EventSource(`/video-stream/${C}`);q.current=P,ep(P),(K=M.current)==null||K.close();const U=new EventSource(`/playback-state/${C}`);M.current=U,ep(U),U.onmessage=Q=>{try{const J=JSON.parse(Q.data);Ie(J.state),(J.state==="Completed"||J.state==="Failed"||J.state==="NotSeekable")&&U.close()}catch(J){console.error("Error parsing playback state:",J)}},U.onerror=()=>U.close(),P.onmessage=async Q=>{try{const J=await ie(Q.data);me.push(J),Y()}catch{}};const Y=async()=>{if(!(ce||me.length===0||!m.current)){ce=!0;try{const Q=me.shift();Q&&(m.current.updating&&await new Promise(J=>{var se;(se=m.current)==null||se.addEventListener("updateend",()=>J(),{once:!0})}),m.current.appendBuffer(Q),await new Promise(J=>{var se;(se=m.current)==null||se.addEventListener("updateend",()=>J(),{once:!0})}))}catch(Q){console.error("Error appending chunk:",Q),me.length=0}finally{ce=!1,Y()}}};P.onerror=Q=>{console.error("EventSource error:",Q),pe("Failed to receive video stream"),P.close()}},ke=C=>{const P=[...B,...ot.map(U=>U.metadata)].find(U=>U.id===C);return P&&P.mime_type.includes("codecs=")&&MediaSource.isTypeSupported(P.mime_type)?P.mime_type:Jf},Te=async(C,P=0)=>{V.current=C,le.current=P;try{if(x.current&&z.current){x.current.pause(),x.current.src="",z.current=new MediaSource;const W=URL.createObjectURL(z.current);x.current.src=W;const K=ke(C);z.current.addEventListener("sourceopen",()=>{m.current=z.current.addSourceBuffer(K),x.current&&P>0&&(x.current.currentTime=P)})}const U=await fetch(`/req-video/${C}${P>0?`?t=${P}`:""}`,{method:"GET"});if(U.status===422)pe("This video cannot start from the requested time");else if(!U.ok)console.error("Failed to fetch video:",U.status),pe("Failed to fetch video");else{const W=await U.text();Ce(W),pe(null)}}catch(U){console.error("Error requesting video:",U),pe("Failed to fetch video")}},Pe=()=>{const C=x.current,P=V.current;if(!C||P===null||C.currentTime===le.current)return;const U=C.buffered;for(let W=0;W<U.length;W++)if(U.start(W)<=C.currentTime&&C.currentTime<=U.end(W))return;Te(P,C.currentTime)},Fe=async()=>{try{const C=new URLSearchParams({sort:ze,offset:String(ee*qf),limit:String(qf)});b.trim()!==""&&C.set("title",b.trim());const P=await fetch(`/videos?${C}`,{method:"GET"});if(P.ok){const U=await P.json();$(U.videos),ge(U.total),pe(null)}else console.error("Failed to fetch videos:",P.status),pe("Failed to fetch video list from db")}catch(C){console.error("Error sending message:",C),pe("Failed to fetch video list from db")}},Ue=async()=>{try{const C=await fetch("/req-video-list-from-server",{method:"GET"});C.ok?pe(null):(console.error("Failed to fetch videos from server:",C.status),pe("Failed to fetch video list from server"))}catch(C){console.error("Error sending message:",C),pe("Failed to fetch video list from server")}},Ve=async()=>{try{const C=await fetch("/flood-req",{method:"GET"});C.ok?pe(null):(console.error("Failed to send message:",C.status),pe("Failed to send flood_req"))}catch(C){console.error("Error sending message:",C),pe("Failed to send flood_req")}};return xe.useEffect(()=>{Xe==="SubscribedToServer"&&Ue()},[Xe]),xe.useEffect(()=>{Fe()},[b,ze,ee]),xe.useEffect(()=>{if(!x.current)return;const C=new EventSource("/video-list-from-server");ep(C),C.onmessage=async function(){try{const W=await fetch("/network-catalog",{method:"GET"});W.ok?Ye(await W.json()):(console.error("Failed to fetch network catalog:",W.status),pe("Failed to fetch network catalog"))}catch(W){console.error("Error fetching network catalog:",W),Ye([]),pe("Failed to fetch network catalog")}};const P=new EventSource("/fsm-status");ep(P),P.onmessage=function(W){try{O(W.data)}catch(K){console.error("Error parsing FSM status:",K),O("Setup"),pe("Failed to fetch FSM status")}};const U=new EventSource("/request-errors");ep(U),U.addEventListener("request-error",W=>{try{const K=JSON.parse(W.data);pe(`Request failed: ${K.message}`)}catch(K){console.error("Error parsing request error:",K)}}),z.current=new MediaSource;const Q=URL.createObjectURL(z.current);return x.current.src=Q,z.current.addEventListener("sourceopen",()=>{var W,K;z.current&&(((W=z.current)==null?void 0:W.readyState)==="open"?m.current=z.current.addSourceBuffer(Jf):console.error("MediaSource not ready"),(K=m.current)==null||K.addEventListener("updateend",()=>{if(!(!m.current||!x.current)&&m.current.buffered.length>0){const Y=m.current.buffered.end(0),J=x.current.currentTime;Y-J>30&&m.current.remove(0,J-10)}}))}),()=>{var W,K,Y;(W=q.current)==null||W.close(),(K=M.current)==null||K.close(),((Y=z.current)==null?void 0:Y.readyState)==="open"&&z.current.endOfStream(),URL.revokeObjectURL(Q)}},[]),L.jsx("div",{className:"min-h-screen bg-gray-900 text-white",children:L.jsxs("div",{className:"container mx-auto px-4 py-8",children:[H&&L.jsxs("div",{className:"fixed bottom-4 left-4 bg-red-500 text-white px-4 py-2 rounded-lg shadow-lg flex items-center justify-between space-x-4 w-max",children:[L.jsx("p",{className:"text-sm",children:H}),L.jsx("button",{onClick:()=>pe(null),className:"text-white font-bold text-lg leading-none",children:"✖"})]}),L.jsx("div",{className:"fixed top-4 right-4 z-50",children:L.jsxs("div",{className:"flex items-center space-x-2 bg-gray-800 rounded-full px-4 py-2 shadow-lg",children:[L.jsx("div",{className:`w-3 h-3 rounded-full ${Xe==="NotSubscribedToServer"?"bg-yellow-500":Xe==="SubscribedToServer"?"bg-green-500":Xe==="Terminated"?"bg-red-500":"bg-gray-500"}`}),L.jsx("span",{className:"text-sm font-medium",children:Xe}),L.jsx("button",{onClick:()=>Ue(),className:"text-blue-400 hover:text-blue-300 transition-colors",children:L.jsx(Xf,{size:16})}),L.jsx("button",{onClick:Ve,className:"bg-blue-600 hover:bg-blue-500 text-white px-3 py-1 rounded-full text-sm",children:"Send flood_req"})]})}),L.jsxs("div",{className:"grid md:grid-cols-3 gap-8",children:[L.jsxs("div",{className:"md:col-span-2 rounded-xl overflow-hidden shadow-2xl",children:[L.jsxs("div",{className:"relative",children:[L.jsx("video",{ref:x,className:"w-full bg-black",controls:!0,preload:"auto",onSeeking:Pe,children:L.jsx("p",{className:"vjs-no-js",children:"To view this video, please enable JavaScript."})}),De==="Buffering"&&L.jsx("div",{className:"absolute top-2 left-2 bg-gray-800 bg-opacity-75 rounded px-2 py-1 text-sm",children:"Buffering..."}),De==="Failed"&&L.jsx("div",{className:"absolute top-2 left-2 bg-red-800 bg-opacity-75 rounded px-2 py-1 text-sm",children:"Download failed, no peer can send this video"}),De==="NotSeekable"&&L.jsx("div",{className:"absolute top-2 left-2 bg-red-800 bg-opacity-75 rounded px-2 py-1 text-sm",children:"This video cannot start from the requested time"})]}),te&&L.jsxs("div",{className:"p-4 bg-gray-700",children:[L.jsx("h2",{className:"text-xl font-bold",children:te.title}),L.jsx("p",{className:"text-gray-300",children:te.description})]})]}),L.jsxs("div",{className:"space-y-6",children:[L.jsxs("div",{children:[L.jsx("h2",{className:"text-2xl font-bold mb-4 text-gray-200",children:"Local Videos"}),L.jsxs("div",{className:"flex space-x-2 mb-4",children:[L.jsx("input",{type:"search",value:b,onChange:C=>{D(C.target.value),X(0)},placeholder:"Search by title",className:"flex-1 bg-gray-800 rounded-lg px-3 py-2 text-sm text-gray-200"}),L.jsxs("select",{value:ze,onChange:C=>{Re(C.target.value),X(0)},className:"bg-gray-800 rounded-lg px-3 py-2 text-sm text-gray-200",children:[L.jsx("option",{value:"title",children:"Title"}),L.jsx("option",{value:"duration",children:"Duration"}),L.jsx("option",{value:"created_at",children:"Date"})]})]}),L.jsxs("div",{className:"space-y-4",children:[B.map(C=>L.jsx(Oa,{video:C,onSelect:()=>{G(C),Te(C.id)}},C.id)),B.length===0&&L.jsx("p",{className:"text-gray-500 text-center",children:"No local videos"})]}),ye>qf&&L.jsxs("div",{className:"flex justify-between items-center mt-4 text-sm text-gray-400",children:[L.jsx("button",{onClick:()=>X(ee-1),disabled:ee===0,className:"text-blue-400 hover:text-blue-300 disabled:text-gray-600",children:"Previous"}),L.jsxs("span",{children:["Page ",ee+1," of ",Math.ceil(ye/qf)]}),L.jsx("button",{onClick:()=>X(ee+1),disabled:(ee+1)*qf>=ye,className:"text-blue-400 hover:text-blue-300 disabled:text-gray-600",children:"Next"})]})]}),L.jsxs("div",{children:[L.jsx("h2",{className:"text-2xl font-bold mb-4 text-gray-200",children:"Server Videos"}),L.jsxs("div",{className:"space-y-4",children:[ot.map(({metadata:C,servers:P})=>L.jsxs("div",{children:[L.jsx(Oa,{video:C,onSelect:()=>{G(C),Te(C.id)}}),L.jsxs("p",{className:"text-xs text-gray-500 mt-1",children:["Servers: ",P.join(", ")]})]},C.id)),ot.length===0&&L.jsx("p",{className:"text-gray-500 text-center",children:"No server videos"})]})]})]})]})]})})},Oa=({video:x,onSelect:z})=>L.jsxs("div",{className:"bg-gray-800 rounded-lg p-4 hover:bg-gray-700 transition-colors cursor-pointer group",onClick:z,children:[L.jsxs("div",{className:"flex justify-between items-center mb-2",children:[L.jsx("h3",{className:"text-lg font-semibold text-gray-200 group-hover:text-blue-400 transition-colors",children:x.title}),L.jsx(Kf,{size:20,className:"text-gray-500 hover:text-blue-500 transition-colors"})]}),L.jsx("p",{className:"text-gray-400 text-sm mb-2",children:x.description}),L.jsxs("div",{className:"flex justify-between text-xs text-gray-500",children:[L.jsx("span",{children:x.mime_type}),L.jsxs("span",{children:[x.duration,"s"]})]})]});function Zf(){return L.jsxs(L.Fragment,{children:[L.jsx(Bf,{}),L.jsx(Gf,{})]})}Af.createRoot(document.getElementById("root")).render(L.jsx(xe.StrictMode,{children:L.jsx(Zf,{})}));